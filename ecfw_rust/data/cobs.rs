@@ -0,0 +1,112 @@
+// c4puter embedded controller firmware
+// Copyright (C) 2017 Chris Pavlina
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+//
+
+//! Consistent Overhead Byte Stuffing
+//!
+//! COBS removes all zero bytes from a block of data, at a cost of at most
+//! one byte in 254, so that zero can be used to delimit frames on a byte
+//! stream. The delimiter is not added or expected here.
+
+use messages::*;
+
+/// Largest possible encoded size of `len` bytes.
+pub fn max_encoded_len(len: usize) -> usize
+{
+    len + len / 254 + 1
+}
+
+/// Encode a block of data.
+///
+/// # Arguments
+/// - `dest` - buffer to hold the result, at least `max_encoded_len()` long
+/// - `src` - data to encode
+///
+/// # Return
+/// - `Ok(n)` - number of bytes written to `dest`
+/// - `Err(ERR_STRLEN)` - `dest` is too short
+pub fn encode(dest: &mut [u8], src: &[u8]) -> Result<usize, Error>
+{
+    if dest.len() < max_encoded_len(src.len()) {
+        return Err(ERR_STRLEN);
+    }
+
+    // Each block starts with a code byte giving the offset to the next zero,
+    // which is filled in when the block ends.
+    let mut code_idx = 0;
+    let mut code = 1u8;
+    let mut written = 1;
+
+    for &c in src {
+        if c != 0 {
+            dest[written] = c;
+            written += 1;
+            code += 1;
+        }
+
+        if c == 0 || code == 0xff {
+            dest[code_idx] = code;
+            code_idx = written;
+            code = 1;
+            written += 1;
+        }
+    }
+
+    dest[code_idx] = code;
+    Ok(written)
+}
+
+/// Decode a block of data.
+///
+/// # Arguments
+/// - `dest` - buffer to hold the result; `src.len()` is always enough
+/// - `src` - encoded data, without the delimiter
+///
+/// # Return
+/// - `Ok(n)` - number of bytes written to `dest`
+/// - `Err(ERR_COBS)` - `src` is not valid COBS
+/// - `Err(ERR_STRLEN)` - `dest` is too short
+pub fn decode(dest: &mut [u8], src: &[u8]) -> Result<usize, Error>
+{
+    let mut i = 0;
+    let mut written = 0;
+
+    while i < src.len() {
+        let code = src[i] as usize;
+
+        if code == 0 || i + code > src.len() {
+            return Err(ERR_COBS);
+        }
+        i += 1;
+
+        for _ in 1 .. code {
+            if src[i] == 0 {
+                return Err(ERR_COBS);
+            }
+            *dest.get_mut(written).ok_or(ERR_STRLEN)? = src[i];
+            written += 1;
+            i += 1;
+        }
+
+        // Every block but the last and the full-length ones ends in a zero
+        if code != 0xff && i < src.len() {
+            *dest.get_mut(written).ok_or(ERR_STRLEN)? = 0;
+            written += 1;
+        }
+    }
+
+    Ok(written)
+}
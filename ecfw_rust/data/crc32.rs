@@ -0,0 +1,60 @@
+// c4puter embedded controller firmware
+// Copyright (C) 2017 Chris Pavlina
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+//
+
+//! CRC-32 (IEEE 802.3, as used by GPT, zlib and ftrans)
+
+use lwext4_crc32;
+use ctypes;
+
+/// Incremental CRC-32, for data that arrives in pieces.
+#[derive(Copy, Clone)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    pub const fn new() -> Crc32
+    {
+        Crc32 { state: 0xffffffff }
+    }
+
+    /// Feed more data into the checksum.
+    pub fn update(&mut self, data: &[u8])
+    {
+        self.state = unsafe {
+            lwext4_crc32::ext4_crc32(
+                self.state,
+                data.as_ptr() as *const ctypes::c_void,
+                data.len() as u32,
+            )
+        };
+    }
+
+    /// Get the checksum of all data fed so far.
+    pub fn finish(&self) -> u32
+    {
+        !self.state
+    }
+}
+
+/// Compute the CRC-32 of a buffer.
+pub fn crc32(data: &[u8]) -> u32
+{
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
@@ -0,0 +1,149 @@
+// c4puter embedded controller firmware
+// Copyright (C) 2017 Chris Pavlina
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+//
+
+
+//! Shell-style wildcard matching
+
+/// Match a string against a shell-style glob pattern.
+///
+/// Supported syntax:
+///
+/// - `*` - any run of characters, including none
+/// - `?` - any single character
+/// - `[abc]`, `[a-z]` - any one of a set or range of characters
+/// - `[!abc]`, `[^abc]` - any one character not in the set
+///
+/// A `[` with no closing `]` is matched literally. There is no escape
+/// character, and `/` is not treated specially.
+pub fn matches(pattern: &str, s: &str) -> bool
+{
+    let mut ipat = 0usize;
+    let mut is = 0usize;
+
+    // Position to resume from on mismatch: (pattern after the last `*`,
+    // string position that `*` is currently absorbing up to)
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while is < s.len() {
+        let c = char_at(s, is);
+
+        if ipat < pattern.len() {
+            let pc = char_at(pattern, ipat);
+
+            if pc == '*' {
+                ipat += 1;
+                backtrack = Some((ipat, is));
+                continue;
+            }
+
+            let consumed = if pc == '?' {
+                Some(1)
+            } else if pc == '[' {
+                match match_class(&pattern[ipat ..], c) {
+                    Some((true, n)) => Some(n),
+                    Some((false, _)) => None,
+                    None if c == '[' => Some(1),
+                    None => None,
+                }
+            } else if pc == c {
+                Some(pc.len_utf8())
+            } else {
+                None
+            };
+
+            if let Some(n) = consumed {
+                ipat += n;
+                is += c.len_utf8();
+                continue;
+            }
+        }
+
+        // Mismatch. Let the last `*` absorb one more character and retry.
+        match backtrack {
+            Some((bpat, bs)) => {
+                let bs = bs + char_at(s, bs).len_utf8();
+                backtrack = Some((bpat, bs));
+                ipat = bpat;
+                is = bs;
+            },
+            None => return false,
+        }
+    }
+
+    // Trailing stars match the empty string
+    while ipat < pattern.len() && char_at(pattern, ipat) == '*' {
+        ipat += 1;
+    }
+
+    ipat == pattern.len()
+}
+
+/// Match a character against a `[...]` class at the start of `pattern`.
+///
+/// # Return
+/// - `Some((matched, n))` - whether `c` is in the class, and the length in
+///   bytes of the class expression
+/// - `None` - the class has no closing `]`
+fn match_class(pattern: &str, c: char) -> Option<(bool, usize)>
+{
+    let mut i = 1usize; // skip '['
+    let mut negate = false;
+    let mut matched = false;
+
+    if i < pattern.len() {
+        let first = char_at(pattern, i);
+        if first == '!' || first == '^' {
+            negate = true;
+            i += 1;
+        }
+    }
+
+    let mut first = true;
+
+    while i < pattern.len() {
+        let lo = char_at(pattern, i);
+
+        // A ']' immediately after the opening bracket is literal
+        if lo == ']' && !first {
+            return Some((matched != negate, i + 1));
+        }
+        first = false;
+        i += lo.len_utf8();
+
+        let mut hi = lo;
+        if i + 1 < pattern.len() && char_at(pattern, i) == '-' {
+            let h = char_at(pattern, i + 1);
+            if h != ']' {
+                hi = h;
+                i += 1 + h.len_utf8();
+            }
+        }
+
+        if lo <= c && c <= hi {
+            matched = true;
+        }
+    }
+
+    None
+}
+
+/// Get the character starting at byte index `i`. `i` must be on a char
+/// boundary and less than `s.len()`.
+fn char_at(s: &str, i: usize) -> char
+{
+    s[i ..].chars().next().unwrap()
+}
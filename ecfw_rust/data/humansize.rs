@@ -0,0 +1,83 @@
+// c4puter embedded controller firmware
+// Copyright (C) 2017 Chris Pavlina
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+//
+
+
+//! Human-readable byte counts
+
+use core::fmt;
+use core::fmt::Write;
+use core::str;
+
+/// Byte count that displays in `ls -h` style: `512`, `1.5K`, `23M`, `4.0G`.
+/// Units are powers of 1024. Honors width and alignment flags.
+#[derive(Copy, Clone)]
+pub struct HumanSize(pub u64);
+
+const UNITS: &[u8] = b"KMGTPE";
+
+impl fmt::Display for HumanSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let n = self.0;
+        let mut buf = StackBuf {
+            buf: [0u8; 16],
+            len: 0,
+        };
+
+        if n < 1024 {
+            write!(buf, "{}", n)?;
+        } else {
+            let mut iunit = 0usize;
+            let mut div = 1024u64;
+            while iunit + 1 < UNITS.len() && n / div >= 1024 {
+                iunit += 1;
+                div *= 1024;
+            }
+
+            let tenths = (n / div) * 10 + (n % div) * 10 / div;
+            let unit = UNITS[iunit] as char;
+
+            if tenths < 100 {
+                write!(buf, "{}.{}{}", tenths / 10, tenths % 10, unit)?;
+            } else {
+                write!(buf, "{}{}", tenths / 10, unit)?;
+            }
+        }
+
+        // Safe: only ASCII was written
+        f.pad(unsafe { str::from_utf8_unchecked(&buf.buf[0 .. buf.len]) })
+    }
+}
+
+/// Small fixed buffer to format into before padding.
+struct StackBuf {
+    buf: [u8; 16],
+    len: usize,
+}
+
+impl fmt::Write for StackBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result
+    {
+        let sb = s.as_bytes();
+        if self.len + sb.len() > self.buf.len() {
+            return Err(fmt::Error);
+        }
+        self.buf[self.len .. self.len + sb.len()].copy_from_slice(sb);
+        self.len += sb.len();
+        Ok(())
+    }
+}
@@ -0,0 +1,420 @@
+// c4puter embedded controller firmware
+// Copyright (C) 2017 Chris Pavlina
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+//
+
+
+//! Byte stream traits, modeled on `std::io`
+//!
+//! Errors carry the number of bytes transferred before the failure, so a
+//! caller that retries or reports progress does not lose data.
+
+use core::{convert, ops, str};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use messages::*;
+
+/// Default buffer size for BufReader and BufWriter
+const DEFAULT_BUF_SIZE: usize = 512;
+
+/// Error struct containing a number of bytes accessed before error, as well as
+/// an error code.
+#[derive(Copy, Clone, Debug)]
+pub struct IoError {
+    error: Error,
+    bytes: usize,
+}
+
+impl ops::Deref for IoError {
+    type Target = Error;
+
+    fn deref(&self) -> &Error
+    {
+        &self.error
+    }
+}
+
+impl convert::From<IoError> for Error {
+    fn from(e: IoError) -> Error
+    {
+        e.error()
+    }
+}
+
+impl convert::From<Error> for IoError {
+    fn from(e: Error) -> IoError
+    {
+        IoError::new(e, 0)
+    }
+}
+
+impl IoError {
+    /// Create a new IoError given error code and number of bytes accessed
+    pub const fn new(error: Error, bytes: usize) -> IoError
+    {
+        IoError {
+            error: error,
+            bytes: bytes,
+        }
+    }
+
+    /// Get the number of bytes accessed before error
+    pub fn bytes(&self) -> usize
+    {
+        self.bytes
+    }
+
+    /// Get the actual error code
+    pub fn error(&self) -> Error
+    {
+        self.error
+    }
+}
+
+/// Source of bytes.
+pub trait Read {
+    /// Read up to `buf.len()` bytes, returning how many were read. Zero means
+    /// end of stream (or an empty `buf`).
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError>;
+
+    /// Fill all of `buf`. Fails with ERR_EOF if the stream ends first.
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), IoError>
+    {
+        let mut done = 0;
+
+        while done < buf.len() {
+            match self.read(&mut buf[done ..]) {
+                Ok(0) => return Err(IoError::new(ERR_EOF, done)),
+                Ok(n) => done += n,
+                Err(e) => return Err(IoError::new(e.error(), done + e.bytes())),
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill as much of `buf` as the stream has left, returning how much
+    /// that was. Unlike read(), this is only short at the end of the stream.
+    fn read_full(&mut self, buf: &mut [u8]) -> Result<usize, IoError>
+    {
+        match self.read_exact(buf) {
+            Ok(()) => Ok(buf.len()),
+            Err(ref e) if e.error() == ERR_EOF => Ok(e.bytes()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Sink for bytes.
+pub trait Write {
+    /// Write up to `buf.len()` bytes, returning how many were written.
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError>;
+
+    /// Push out any buffered data.
+    fn flush(&mut self) -> StdResult
+    {
+        Ok(())
+    }
+
+    /// Write all of `buf`. Fails with ERR_WRITE_ZERO if the sink stops
+    /// accepting data.
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), IoError>
+    {
+        let mut done = 0;
+
+        while done < buf.len() {
+            match self.write(&buf[done ..]) {
+                Ok(0) => return Err(IoError::new(ERR_WRITE_ZERO, done)),
+                Ok(n) => done += n,
+                Err(e) => return Err(IoError::new(e.error(), done + e.bytes())),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Position to seek to.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64),
+}
+
+/// Stream with a movable position.
+pub trait Seek {
+    /// Move the position, returning the new position from the start.
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error>;
+}
+
+impl<'a, R: Read + ?Sized> Read for &'a mut R {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError>
+    {
+        (**self).read(buf)
+    }
+}
+
+impl<'a, W: Write + ?Sized> Write for &'a mut W {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError>
+    {
+        (**self).write(buf)
+    }
+
+    fn flush(&mut self) -> StdResult
+    {
+        (**self).flush()
+    }
+}
+
+/// Reader that fetches from the inner reader in large chunks.
+pub struct BufReader<R: Read> {
+    inner: R,
+    buf: Box<[u8]>,
+    pos: usize,
+    cap: usize,
+}
+
+impl<R: Read> BufReader<R> {
+    pub fn new(inner: R) -> BufReader<R>
+    {
+        BufReader::with_capacity(DEFAULT_BUF_SIZE, inner)
+    }
+
+    pub fn with_capacity(capacity: usize, inner: R) -> BufReader<R>
+    {
+        let mut buf = Vec::with_capacity(capacity);
+        buf.resize(capacity, 0u8);
+
+        BufReader {
+            inner: inner,
+            buf: buf.into_boxed_slice(),
+            pos: 0,
+            cap: 0,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut R
+    {
+        &mut self.inner
+    }
+
+    /// Return the buffered data, reading more if the buffer is empty. An
+    /// empty slice means end of stream.
+    pub fn fill_buf(&mut self) -> Result<&[u8], IoError>
+    {
+        if self.pos >= self.cap {
+            self.cap = self.inner.read(&mut self.buf)?;
+            self.pos = 0;
+        }
+        Ok(&self.buf[self.pos .. self.cap])
+    }
+
+    /// Mark `amt` bytes returned by fill_buf as used.
+    pub fn consume(&mut self, amt: usize)
+    {
+        self.pos = if self.pos + amt > self.cap {
+            self.cap
+        } else {
+            self.pos + amt
+        };
+    }
+
+    /// Append bytes up to and including `delim` to `out`. Returns the number
+    /// of bytes appended; zero at end of stream.
+    pub fn read_until(
+        &mut self,
+        delim: u8,
+        out: &mut Vec<u8>,
+    ) -> Result<usize, IoError>
+    {
+        let mut total = 0;
+
+        loop {
+            let (found, used) = {
+                let available = match self.fill_buf() {
+                    Ok(b) => b,
+                    Err(e) => {
+                        return Err(IoError::new(e.error(), total + e.bytes()))
+                    },
+                };
+                match available.iter().position(|&c| c == delim) {
+                    Some(i) => {
+                        out.extend_from_slice(&available[.. i + 1]);
+                        (true, i + 1)
+                    },
+                    None => {
+                        out.extend_from_slice(available);
+                        (false, available.len())
+                    },
+                }
+            };
+
+            self.consume(used);
+            total += used;
+
+            if found || used == 0 {
+                return Ok(total);
+            }
+        }
+    }
+
+    /// Append a line including its `\n` to `out`. Fails with ERR_UTF8 if the
+    /// line is not valid UTF-8, in which case `out` is left unchanged.
+    pub fn read_line(&mut self, out: &mut String) -> Result<usize, IoError>
+    {
+        let mut bytes = Vec::new();
+        let n = self.read_until(b'\n', &mut bytes)?;
+
+        match str::from_utf8(&bytes) {
+            Ok(s) => {
+                out.push_str(s);
+                Ok(n)
+            },
+            Err(_) => Err(IoError::new(ERR_UTF8, n)),
+        }
+    }
+
+    /// Iterate over lines, without their `\n` or `\r\n` terminators.
+    pub fn lines(self) -> Lines<R>
+    {
+        Lines { reader: self }
+    }
+}
+
+impl<R: Read> Read for BufReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError>
+    {
+        // Skip the buffer entirely for large reads when it is empty
+        if self.pos >= self.cap && buf.len() >= self.buf.len() {
+            return self.inner.read(buf);
+        }
+
+        let n = {
+            let available = self.fill_buf()?;
+            let n = if available.len() < buf.len() {
+                available.len()
+            } else {
+                buf.len()
+            };
+            buf[.. n].copy_from_slice(&available[.. n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+/// Iterator over the lines of a BufReader.
+pub struct Lines<R: Read> {
+    reader: BufReader<R>,
+}
+
+impl<R: Read> Iterator for Lines<R> {
+    type Item = Result<String, Error>;
+
+    fn next(&mut self) -> Option<Result<String, Error>>
+    {
+        let mut line = String::new();
+
+        match self.reader.read_line(&mut line) {
+            Ok(0) => None,
+            Ok(_) => {
+                if line.ends_with('\n') {
+                    line.pop();
+                    if line.ends_with('\r') {
+                        line.pop();
+                    }
+                }
+                Some(Ok(line))
+            },
+            Err(e) => Some(Err(e.error())),
+        }
+    }
+}
+
+/// Writer that collects small writes and passes them on in large chunks.
+/// Buffered data is flushed when dropped, but errors at that point are lost;
+/// call flush() first to see them.
+pub struct BufWriter<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> BufWriter<W> {
+    pub fn new(inner: W) -> BufWriter<W>
+    {
+        BufWriter::with_capacity(DEFAULT_BUF_SIZE, inner)
+    }
+
+    pub fn with_capacity(capacity: usize, inner: W) -> BufWriter<W>
+    {
+        BufWriter {
+            inner: inner,
+            buf: Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W
+    {
+        &mut self.inner
+    }
+
+    /// Write out the buffer without flushing the inner writer.
+    fn flush_buf(&mut self) -> Result<(), IoError>
+    {
+        let result = self.inner.write_all(&self.buf);
+        match result {
+            Ok(()) => self.buf.clear(),
+            Err(e) => {
+                // Keep what was not written so a later flush can retry
+                self.buf.drain(.. e.bytes());
+            },
+        }
+        result
+    }
+}
+
+impl<W: Write> Write for BufWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError>
+    {
+        if self.buf.len() + buf.len() > self.buf.capacity() {
+            // The count in a flush error is of buffered bytes; none of `buf`
+            // has been written yet.
+            if let Err(e) = self.flush_buf() {
+                return Err(IoError::new(e.error(), 0));
+            }
+        }
+
+        if buf.len() >= self.buf.capacity() {
+            self.inner.write(buf)
+        } else {
+            self.buf.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    fn flush(&mut self) -> StdResult
+    {
+        self.flush_buf()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> ops::Drop for BufWriter<W> {
+    fn drop(&mut self)
+    {
+        let _ = self.flush_buf();
+    }
+}
@@ -18,9 +18,21 @@
 //! Utilities for processing and displaying data
 
 mod parseint;
+mod crc32;
+mod sha256;
 mod hexprint;
+mod datetime;
+mod humansize;
 pub mod base64;
+pub mod cobs;
+pub mod glob;
+pub mod io;
+pub mod path;
 pub mod utf;
 
 pub use self::parseint::ParseInt;
+pub use self::crc32::{Crc32, crc32};
+pub use self::sha256::{Sha256, sha256};
 pub use self::hexprint::hexprint;
+pub use self::datetime::DateTime;
+pub use self::humansize::HumanSize;
@@ -0,0 +1,72 @@
+// c4puter embedded controller firmware
+// Copyright (C) 2017 Chris Pavlina
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+//
+
+
+//! Lexical path manipulation
+//!
+//! These functions only look at the strings; they do not touch the
+//! filesystem, so `..` is resolved without regard to symlinks. Use
+//! `ext4::expand` to resolve a path physically.
+
+use alloc::string::String;
+
+/// Normalize an absolute path: collapse repeated slashes, drop `.`, resolve
+/// `..` and remove any trailing slash. `..` at the root stays at the root.
+/// A path not starting with `/` is treated as relative to the root.
+pub fn normalize(path: &str) -> String
+{
+    let mut s = String::with_capacity(path.len() + 1);
+
+    for comp in path.split('/') {
+        match comp {
+            "" | "." => (),
+            ".." => {
+                let cut = s.rfind('/').unwrap_or(0);
+                s.truncate(cut);
+            },
+            _ => {
+                s.push('/');
+                s.push_str(comp);
+            },
+        }
+    }
+
+    if s.len() == 0 {
+        s.push('/');
+    }
+    s
+}
+
+/// Resolve `path` against the absolute directory `base` and normalize the
+/// result. Absolute paths ignore `base`.
+pub fn join(base: &str, path: &str) -> String
+{
+    if is_absolute(path) {
+        normalize(path)
+    } else {
+        let mut s = String::with_capacity(base.len() + path.len() + 1);
+        s.push_str(base);
+        s.push('/');
+        s.push_str(path);
+        normalize(&s)
+    }
+}
+
+pub fn is_absolute(path: &str) -> bool
+{
+    path.starts_with('/')
+}
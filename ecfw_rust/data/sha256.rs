@@ -0,0 +1,162 @@
+// c4puter embedded controller firmware
+// Copyright (C) 2017 Chris Pavlina
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+//
+
+//! SHA-256 (FIPS 180-4)
+
+use core::cmp;
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1,
+    0x923f82a4, 0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3,
+    0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786,
+    0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147,
+    0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13,
+    0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+    0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a,
+    0x5b9cca4f, 0x682e6ff3, 0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208,
+    0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const H0: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c,
+    0x1f83d9ab, 0x5be0cd19,
+];
+
+/// Incremental SHA-256, for data that arrives in pieces.
+#[derive(Copy, Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    /// Bytes in `block`
+    fill: usize,
+    /// Total bytes fed
+    len: u64,
+}
+
+impl Sha256 {
+    pub const fn new() -> Sha256
+    {
+        Sha256 {
+            state: H0,
+            block: [0; 64],
+            fill: 0,
+            len: 0,
+        }
+    }
+
+    /// Feed more data into the hash.
+    pub fn update(&mut self, mut data: &[u8])
+    {
+        self.len += data.len() as u64;
+
+        while data.len() > 0 {
+            let n = cmp::min(64 - self.fill, data.len());
+            self.block[self.fill .. self.fill + n].copy_from_slice(&data[.. n]);
+            self.fill += n;
+            data = &data[n ..];
+
+            if self.fill == 64 {
+                self.compress();
+                self.fill = 0;
+            }
+        }
+    }
+
+    /// Get the hash of all data fed so far.
+    pub fn finish(&self) -> [u8; 32]
+    {
+        let mut last = *self;
+        let bits = self.len * 8;
+
+        // Padding: 0x80, zeros, then the length in bits, ending a block
+        last.update(&[0x80]);
+        while last.fill != 56 {
+            last.update(&[0]);
+        }
+        for i in 0 .. 8 {
+            last.update(&[(bits >> (56 - 8 * i)) as u8]);
+        }
+
+        let mut digest = [0u8; 32];
+        for (i, word) in last.state.iter().enumerate() {
+            for j in 0 .. 4 {
+                digest[4 * i + j] = (word >> (24 - 8 * j)) as u8;
+            }
+        }
+        digest
+    }
+
+    fn compress(&mut self)
+    {
+        let mut w = [0u32; 64];
+
+        for i in 0 .. 16 {
+            let b = &self.block[4 * i .. 4 * i + 4];
+            w[i] = (b[0] as u32) << 24 | (b[1] as u32) << 16 |
+                (b[2] as u32) << 8 | b[3] as u32;
+        }
+        for i in 16 .. 64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^
+                (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^
+                (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let mut v = self.state;
+        for i in 0 .. 64 {
+            let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^
+                v[4].rotate_right(25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7]
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^
+                v[0].rotate_right(22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0.wrapping_add(maj);
+
+            v[7] = v[6];
+            v[6] = v[5];
+            v[5] = v[4];
+            v[4] = v[3].wrapping_add(t1);
+            v[3] = v[2];
+            v[2] = v[1];
+            v[1] = v[0];
+            v[0] = t1.wrapping_add(t2);
+        }
+
+        for i in 0 .. 8 {
+            self.state[i] = self.state[i].wrapping_add(v[i]);
+        }
+    }
+}
+
+/// Compute the SHA-256 of a buffer.
+pub fn sha256(data: &[u8]) -> [u8; 32]
+{
+    let mut hash = Sha256::new();
+    hash.update(data);
+    hash.finish()
+}
@@ -116,3 +116,36 @@ pub unsafe fn write_one_utf8(
     let s = c.encode_utf8(&mut dest[idest .. destlen]);
     s.len()
 }
+
+/// Write a string as UTF-16LE into a fixed-size field, padding the rest of
+/// the field with zeros.
+///
+/// # Arguments
+/// - `dest` - field to hold the encoded data
+/// - `src` - string to encode
+///
+/// # Return
+/// - `Ok(n)` - number of bytes written before the padding
+/// - `Err(ERR_STRLEN)` - string does not fit; `dest` is left unchanged
+pub fn write_utf8_into_utf16le(
+    dest: &mut [u8],
+    src: &str,
+) -> Result<usize, Error>
+{
+    if src.encode_utf16().count() * 2 > dest.len() {
+        return Err(ERR_STRLEN);
+    }
+
+    let mut idest = 0usize;
+    for unit in src.encode_utf16() {
+        dest[idest] = unit as u8;
+        dest[idest + 1] = (unit >> 8) as u8;
+        idest += 2;
+    }
+
+    for i in idest .. dest.len() {
+        dest[i] = 0;
+    }
+
+    Ok(idest)
+}
@@ -51,6 +51,7 @@ static FPGA_MUTEX: Mutex<()> = Mutex::new(());
 /// Collection of FPGA programming interfaces, in order: bridge, CPU0, CPU1
 pub static FPGAS: [Spartan6; 3] = [
     Spartan6::new(
+        "bridge",
         &FPGA_MUTEX,
         &SPI,
         &pins::FPGA_DONE0,
@@ -58,6 +59,7 @@ pub static FPGAS: [Spartan6; 3] = [
         &pins::FPGA_PROG0,
     ),
     Spartan6::new(
+        "cpu0",
         &FPGA_MUTEX,
         &SPI,
         &pins::FPGA_DONE1,
@@ -65,6 +67,7 @@ pub static FPGAS: [Spartan6; 3] = [
         &pins::FPGA_PROG1,
     ),
     Spartan6::new(
+        "cpu1",
         &FPGA_MUTEX,
         &SPI,
         &pins::FPGA_DONE2,
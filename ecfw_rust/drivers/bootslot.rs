@@ -0,0 +1,235 @@
+// c4puter embedded controller firmware
+// Copyright (C) 2017 Chris Pavlina
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+//
+
+//! A/B boot slots.
+//!
+//! Every GPT partition of type BOOT_GUID is a boot slot. Slot state lives in
+//! the partition's attribute bits, laid out as ChromeOS does:
+//!
+//! - bits 48-51: priority. The bootable slot with the highest priority is
+//!   tried first; 0 means never boot.
+//! - bits 52-55: tries remaining. Counted down before each attempt, until
+//!   the slot is marked successful.
+//! - bit 56: successful. The slot has booted to a healthy host before.
+//!
+//! A slot is bootable if its priority is nonzero and it is either successful
+//! or has tries left. Tables where no slot has a priority predate this, and
+//! boot the first BOOT_GUID partition as before.
+
+use drivers::gpt::{BOOT_GUID, Gpt, GptEntry, GptWriter};
+use drivers::part::Partition;
+use drivers::sd::*;
+use messages::*;
+use os::Mutex;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub const MAX_PRIORITY: u8 = 15;
+pub const MAX_TRIES: u8 = 15;
+
+/// Tries given to a slot by prioritize().
+pub const DEFAULT_TRIES: u8 = 3;
+
+const PRIORITY_SHIFT: u32 = 48;
+const TRIES_SHIFT: u32 = 52;
+const SUCCESSFUL_SHIFT: u32 = 56;
+
+/// One boot slot.
+#[derive(Clone)]
+pub struct Slot {
+    /// Index of the slot's entry in the GPT.
+    pub index: usize,
+    pub priority: u8,
+    pub tries: u8,
+    pub successful: bool,
+    pub partition: Partition,
+    pub name: String,
+}
+
+impl Slot {
+    fn from_entry(index: usize, entry: &GptEntry) -> Slot
+    {
+        let attr = entry.attributes;
+
+        Slot {
+            index: index,
+            priority: ((attr >> PRIORITY_SHIFT) & 0xf) as u8,
+            tries: ((attr >> TRIES_SHIFT) & 0xf) as u8,
+            successful: (attr >> SUCCESSFUL_SHIFT) & 1 != 0,
+            partition: Partition::from(entry),
+            name: String::from(entry.name()),
+        }
+    }
+
+    /// Store the slot state into an entry's attributes, leaving the other
+    /// bits alone.
+    fn store(&self, entry: &mut GptEntry)
+    {
+        let mask = (0xf << PRIORITY_SHIFT) | (0xf << TRIES_SHIFT) |
+            (1 << SUCCESSFUL_SHIFT);
+
+        entry.attributes = (entry.attributes & !mask) |
+            ((self.priority as u64 & 0xf) << PRIORITY_SHIFT) |
+            ((self.tries as u64 & 0xf) << TRIES_SHIFT) |
+            ((self.successful as u64) << SUCCESSFUL_SHIFT);
+    }
+
+    pub fn bootable(&self) -> bool
+    {
+        self.priority > 0 && (self.successful || self.tries > 0)
+    }
+}
+
+/// Read all boot slots from the card's GPT, in table order.
+pub fn read_slots(sd: &Mutex<Sd>) -> Result<Vec<Slot>, Error>
+{
+    let mut table = Gpt::new(sd);
+    table.read_header()?;
+    read_slots_from(&mut table)
+}
+
+/// Read all boot slots from a GPT whose header has been read.
+pub fn read_slots_from(table: &mut Gpt) -> Result<Vec<Slot>, Error>
+{
+    let mut entry = GptEntry::new();
+    let mut slots = Vec::new();
+
+    for i in 0 .. table.number_entries() {
+        table.read_entry(i, &mut entry)?;
+        if entry.valid() && entry.type_guid == BOOT_GUID {
+            slots.push(Slot::from_entry(i, &entry));
+        }
+    }
+
+    Ok(slots)
+}
+
+/// Choose the slot to boot, skipping the GPT indices in `exclude`: the
+/// bootable slot with the highest priority, or the first on ties. If no slot
+/// has a priority at all, the first slot.
+pub fn choose(slots: &[Slot], exclude: &[usize]) -> Option<Slot>
+{
+    let candidates = slots.iter().filter(|s| !exclude.contains(&s.index));
+
+    if slots.iter().all(|s| s.priority == 0) {
+        return candidates.cloned().next();
+    }
+
+    let mut best: Option<&Slot> = None;
+    for slot in candidates.filter(|s| s.bootable()) {
+        match best {
+            Some(b) if b.priority >= slot.priority => (),
+            _ => best = Some(slot),
+        }
+    }
+
+    best.cloned()
+}
+
+/// Whether the slots use priorities, i.e. attempts should be counted.
+pub fn counted(slots: &[Slot]) -> bool
+{
+    slots.iter().any(|s| s.priority != 0)
+}
+
+/// Change one slot's state and write it back to the card, if it changed.
+pub fn update<F>(sd: &Mutex<Sd>, index: usize, f: F) -> StdResult
+where
+    F: FnOnce(&mut Slot),
+{
+    let mut table = GptWriter::open(sd)?;
+    let mut entry = GptEntry::new();
+
+    table.read_entry(index, &mut entry)?;
+    if !entry.valid() || entry.type_guid != BOOT_GUID {
+        return Err(ERR_CANNOT_FIND);
+    }
+
+    let attributes = entry.attributes;
+    let mut slot = Slot::from_entry(index, &entry);
+    f(&mut slot);
+    slot.store(&mut entry);
+    if entry.attributes == attributes {
+        return Ok(());
+    }
+
+    table.write_entry(index, &entry)?;
+    table.write()
+}
+
+/// Use up one try of a slot that has not yet been marked successful. Call
+/// before attempting to boot it.
+pub fn begin_attempt(sd: &Mutex<Sd>, index: usize) -> StdResult
+{
+    update(sd, index, |s| if !s.successful && s.tries > 0 {
+        s.tries -= 1;
+    })
+}
+
+/// Record that an attempt to boot a slot failed. begin_attempt() has already
+/// used up the try, so this leaves the tries and the successful bit alone:
+/// the failure need not be the image's fault, e.g. an SD glitch, and a slot
+/// that has booted before must stay a fallback. As in ChromeOS, only a slot
+/// that never booted successfully and has no tries left drops out, by
+/// losing its priority.
+pub fn mark_failed(sd: &Mutex<Sd>, index: usize) -> StdResult
+{
+    update(sd, index, |s| if !s.successful && s.tries == 0 {
+        s.priority = 0;
+    })
+}
+
+/// Mark a slot as having booted to a healthy host.
+pub fn mark_successful(sd: &Mutex<Sd>, index: usize) -> StdResult
+{
+    update(sd, index, |s| s.successful = true)
+}
+
+/// Make a slot the next one booted, e.g. after writing a new image to it:
+/// it gets priority 2 and DEFAULT_TRIES, and is not yet successful. Every
+/// other slot gets priority 1, so it remains a fallback, even on tables
+/// that had no priorities. All slots change in one table write.
+pub fn prioritize(sd: &Mutex<Sd>, index: usize) -> StdResult
+{
+    let mut table = GptWriter::open(sd)?;
+    let mut entry = GptEntry::new();
+    let mut found = false;
+
+    for i in 0 .. table.number_entries() {
+        table.read_entry(i, &mut entry)?;
+        if !entry.valid() || entry.type_guid != BOOT_GUID {
+            continue;
+        }
+
+        let mut slot = Slot::from_entry(i, &entry);
+        if i == index {
+            slot.priority = 2;
+            slot.tries = DEFAULT_TRIES;
+            slot.successful = false;
+            found = true;
+        } else {
+            slot.priority = 1;
+        }
+        slot.store(&mut entry);
+        table.write_entry(i, &entry)?;
+    }
+
+    if !found {
+        return Err(ERR_CANNOT_FIND);
+    }
+    table.write()
+}
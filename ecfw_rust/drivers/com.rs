@@ -20,11 +20,14 @@
 
 use os;
 use core::fmt;
+use data::io::{IoError, Read, Write};
+use messages::*;
 
 static OUT_MUTEX: os::Mutex<()> = os::Mutex::new(());
 
-/// COM (RS232/CDC-like) interface.
-pub trait Com {
+/// COM (RS232/CDC-like) interface. Interfaces are shared between tasks, so
+/// they must be Sync; this also lets lists of them be statics.
+pub trait Com: Sync {
     /// Get one byte, returning `None` immediately if none is available.
     fn getc(&self) -> Option<u8>;
 
@@ -70,11 +73,21 @@ pub trait Com {
 /// * `yield_task` - whether the task should yield to other tasks until
 ///     input is ready (most polite) or loop tightly (best throughput).
 pub fn getc_any_blocking(interfaces: &[&Com], yield_task: bool) -> u8
+{
+    getc_which_blocking(interfaces, yield_task).1
+}
+
+/// Get the next byte available from any of the given interfaces, along with
+/// the index in `interfaces` of the one it came from.
+pub fn getc_which_blocking(
+    interfaces: &[&Com],
+    yield_task: bool,
+) -> (usize, u8)
 {
     loop {
-        for i in interfaces {
+        for (n, i) in interfaces.iter().enumerate() {
             if let Some(x) = i.getc() {
-                return x;
+                return (n, x);
             }
         }
 
@@ -84,6 +97,75 @@ pub fn getc_any_blocking(interfaces: &[&Com], yield_task: bool) -> u8
     }
 }
 
+/// Check whether ^C is waiting on any of the given interfaces, for long
+/// operations to poll. Other input received in the meantime is discarded.
+pub fn interrupted(interfaces: &[&Com]) -> bool
+{
+    let mut seen = false;
+
+    for i in interfaces {
+        while let Some(c) = i.getc() {
+            if c == 3 {
+                seen = true;
+            }
+        }
+    }
+
+    seen
+}
+
+/// Raw byte stream over a COM interface, for use with the `data::io` traits.
+/// Unlike the print functions, no newline translation is done.
+pub struct ComStream<'a> {
+    com: &'a Com,
+}
+
+impl<'a> ComStream<'a> {
+    pub fn new(com: &'a Com) -> ComStream<'a>
+    {
+        ComStream { com: com }
+    }
+}
+
+impl<'a> Read for ComStream<'a> {
+    /// Block until at least one byte is available, then return as many as
+    /// are available without waiting.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError>
+    {
+        if buf.len() == 0 {
+            return Ok(0);
+        }
+
+        buf[0] = self.com.getc_blocking(true);
+        let mut n = 1;
+
+        while n < buf.len() {
+            match self.com.getc() {
+                Some(c) => buf[n] = c,
+                None => break,
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+impl<'a> Write for ComStream<'a> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError>
+    {
+        for &c in buf {
+            self.com.putc(c);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> StdResult
+    {
+        self.com.flush_output();
+        Ok(())
+    }
+}
+
 struct ComWriter<'a> {
     com: &'a Com,
 }
@@ -21,19 +21,26 @@
 //! [lwext4]: https://github.com/gkostka/lwext4
 
 use lwext4;
+use lwext4_mkfs;
 use ctypes;
-use core::{convert, fmt, mem, ops, ptr, slice, str};
+use core::{fmt, mem, ops, ptr, slice, str};
 use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use alloc::raw_vec::RawVec;
 use alloc::vec;
+use alloc::vec::Vec;
 use alloc::boxed::Box;
 use alloc::string::String;
 use self::lwext4::ext4_blockdev_iface;
 pub use self::lwext4::ext4_blockdev;
+pub use data::io::IoError;
 
+use data::io::{Read, Seek, SeekFrom, Write};
 use drivers::sd::*;
-use drivers::gpt;
-use os::{Mutex, StrAlloc};
+use drivers::part;
+use os;
+use os::freertos;
+use os::{Mutex, RecursiveMutex, StrAlloc};
 use messages::*;
 
 /// Block device representing a partition on an SD card
@@ -44,14 +51,35 @@ pub struct SdBlockDev<'a> {
 }
 
 impl<'a> SdBlockDev<'a> {
-    /// Create a new block device from an SD card device and a GPT entry
-    pub fn new<'b>(sd: &'b Mutex<Sd>, part: &gpt::GptEntry) -> SdBlockDev<'b>
+    /// Create a new block device from an SD card device and a partition
+    pub fn new<'b>(sd: &'b Mutex<Sd>, part: &part::Partition) -> SdBlockDev<'b>
     {
         SdBlockDev {
             lwext4_bd: ext4_blockdev {
                 bdif: unsafe { &mut BLOCKDEV_IFACE },
-                part_offset: (512 * part.start_lba) as u64,
-                part_size: (512 * (part.end_lba - part.start_lba + 1)) as u64,
+                part_offset: 512 * part.start_lba as u64,
+                part_size: 512 * part.blocks() as u64,
+                bc: ptr::null_mut(),
+                lg_bsize: 0,
+                lg_bcnt: 0,
+                cache_write_back: 0,
+                fs: ptr::null_mut(),
+                journal: ptr::null_mut(),
+            },
+            sd: sd,
+        }
+    }
+
+    /// Create a new block device covering an entire SD card
+    pub fn whole<'b>(sd: &'b Mutex<Sd>) -> SdBlockDev<'b>
+    {
+        let capacity_kib = sd.lock().capacity() as u64;
+
+        SdBlockDev {
+            lwext4_bd: ext4_blockdev {
+                bdif: unsafe { &mut BLOCKDEV_IFACE },
+                part_offset: 0,
+                part_size: capacity_kib * 1024,
                 bc: ptr::null_mut(),
                 lg_bsize: 0,
                 lg_bcnt: 0,
@@ -68,50 +96,115 @@ unsafe impl<'a> Sync for SdBlockDev<'a> {}
 unsafe impl<'a> Send for SdBlockDev<'a> {}
 
 const EIO: i32 = 5;
+const ENODEV: i32 = 19;
+const EINVAL: i32 = 22;
+const EROFS: i32 = 30;
+const ERANGE: i32 = 34;
+const ETIMEDOUT: i32 = 116;
 
-/// Error struct containing a number of bytes accessed before error, as well as
-/// an error code.
-pub struct IoError {
-    error: Error,
-    bytes: usize,
+///////////////////////////////////////////////////////////////////////////////
+// Filesystem lock
+// lwext4 is not re-entrant, so every call into it is made with FS_LOCK held.
+// The lock is recursive within a task, because higher-level operations
+// (expand, walk, mount) are built from lower-level ones that lock too.
+//
+// Open File and Dir handles do not hold the lock between calls, or a long
+// ftrans session would shut out the shell. They are counted instead, so that
+// unmounting can refuse while any are open.
+//
+// force_umount() can't refuse, so it bumps the mount generation instead.
+// Every handle records the generation it was opened under, and checks it
+// with the lock held before each call into lwext4; a stale handle fails with
+// ERR_ENODEV and is never passed to lwext4 again, not even to close it.
+
+struct FsLock {
+    mutex: RecursiveMutex,
+    // Kept alongside the mutex for lock_stats(); only the holder writes them
+    owner: AtomicUsize,
+    depth: AtomicUsize,
+    acquisitions: AtomicUsize,
+    contentions: AtomicUsize,
 }
 
-impl ops::Deref for IoError {
-    type Target = Error;
+static FS_LOCK: FsLock = FsLock {
+    mutex: RecursiveMutex::new(),
+    owner: AtomicUsize::new(0),
+    depth: AtomicUsize::new(0),
+    acquisitions: AtomicUsize::new(0),
+    contentions: AtomicUsize::new(0),
+};
 
-    fn deref(&self) -> &Error
-    {
-        &self.error
+static OPEN_HANDLES: AtomicUsize = AtomicUsize::new(0);
+static MOUNT_GEN: AtomicUsize = AtomicUsize::new(0);
+
+/// Fail if a handle opened under mount generation `gen` has outlived its
+/// filesystem. Call with FS_LOCK held.
+fn check_gen(gen: usize) -> StdResult
+{
+    if gen == MOUNT_GEN.load(Ordering::SeqCst) {
+        Ok(())
+    } else {
+        Err(ERR_ENODEV)
     }
 }
 
-impl convert::From<IoError> for Error {
-    fn from(e: IoError) -> Error
-    {
-        e.error()
+/// Held level of FS_LOCK; releases it on drop.
+struct FsGuard;
+
+/// Take the filesystem lock, waiting for other tasks to release it.
+fn lock_fs() -> FsGuard
+{
+    if !FS_LOCK.mutex.take_timeout(0) {
+        FS_LOCK.contentions.fetch_add(1, Ordering::SeqCst);
+        FS_LOCK.mutex.take();
+    }
+
+    if FS_LOCK.depth.fetch_add(1, Ordering::SeqCst) == 0 {
+        let me = freertos::this_task() as usize;
+        FS_LOCK.owner.store(me, Ordering::SeqCst);
+        FS_LOCK.acquisitions.fetch_add(1, Ordering::SeqCst);
     }
+    FsGuard
 }
 
-impl IoError {
-    /// Create a new IoError given error code and number of bytes accessed
-    pub const fn new(error: Error, bytes: usize) -> IoError
+impl ops::Drop for FsGuard {
+    fn drop(&mut self)
     {
-        IoError {
-            error: error,
-            bytes: bytes,
+        if FS_LOCK.depth.fetch_sub(1, Ordering::SeqCst) == 1 {
+            FS_LOCK.owner.store(0, Ordering::SeqCst);
         }
+        FS_LOCK.mutex.give();
     }
+}
 
-    /// Get the number of bytes accessed before error
-    pub fn bytes(&self) -> usize
-    {
-        self.bytes
-    }
+/// Filesystem lock state, for display.
+pub struct LockStats {
+    /// Task currently holding the lock
+    pub owner: Option<freertos::TaskHandle>,
+    /// Recursion depth of the current holder
+    pub depth: usize,
+    /// Number of times the lock was taken (not counting recursion)
+    pub acquisitions: usize,
+    /// Number of times a task had to wait for another to release it
+    pub contentions: usize,
+    /// Number of open File and Dir handles
+    pub open_handles: usize,
+}
 
-    /// Get the actual error code
-    pub fn error(&self) -> Error
-    {
-        self.error
+pub fn lock_stats() -> LockStats
+{
+    let owner = FS_LOCK.owner.load(Ordering::SeqCst);
+
+    LockStats {
+        owner: if owner == 0 {
+            None
+        } else {
+            Some(owner as freertos::TaskHandle)
+        },
+        depth: FS_LOCK.depth.load(Ordering::SeqCst),
+        acquisitions: FS_LOCK.acquisitions.load(Ordering::SeqCst),
+        contentions: FS_LOCK.contentions.load(Ordering::SeqCst),
+        open_handles: OPEN_HANDLES.load(Ordering::SeqCst),
     }
 }
 
@@ -139,12 +232,18 @@ impl IoError {
 
 static BLOCKDEV: Mutex<Option<SdBlockDev<'static>>> = Mutex::new(None);
 
+/// Set when the card behind the block device has gone away. Reads fail and
+/// writes are silently discarded, so that lwext4 can be torn down without
+/// touching the hardware.
+static DETACHED: AtomicBool = AtomicBool::new(false);
+
 /// Register a block device with a device name
 ///
 /// Only one can be registered at a time; if you want to see the gory details
 /// check "lifetime notes" in ext4.rs.
 pub fn register_device(bd: SdBlockDev<'static>, dev_name: &str) -> StdResult
 {
+    let _fs = lock_fs();
     let mut lock = BLOCKDEV.lock();
 
     if lock.is_some() {
@@ -152,6 +251,7 @@ pub fn register_device(bd: SdBlockDev<'static>, dev_name: &str) -> StdResult
     }
 
     *lock = Some(bd);
+    DETACHED.store(false, Ordering::SeqCst);
 
     let mut alloc = StrAlloc::new();
     let c_name = alloc.nulterm(dev_name)?.as_ptr() as *const _;
@@ -167,6 +267,7 @@ pub fn register_device(bd: SdBlockDev<'static>, dev_name: &str) -> StdResult
 /// Unregister a block device.
 pub fn unregister_device(dev_name: &str) -> StdResult
 {
+    let _fs = lock_fs();
     let mut lock = BLOCKDEV.lock();
 
     if lock.is_none() {
@@ -185,77 +286,791 @@ pub fn unregister_device(dev_name: &str) -> StdResult
     Ok(())
 }
 
-/// Mount a filesystem. If journaled, recovers journal.
+/// Whether a block device is registered, i.e. some partition of the card is
+/// in use by the filesystem.
+pub fn device_registered() -> bool
+{
+    BLOCKDEV.lock().is_some()
+}
+
+/// State of the mounted filesystem. Like the block device, only one can be
+/// mounted at a time.
+struct MountInfo {
+    dev_name: String,
+    mount_point: String,
+    read_only: bool,
+    journaled: bool,
+}
+
+static MOUNTED: Mutex<Option<MountInfo>> = Mutex::new(None);
+
+/// Mount a filesystem. If journaled and mounted read-write, recovers and
+/// starts the journal. Read-only mounts leave the journal alone, as replaying
+/// it would write to the device.
 pub fn mount(dev_name: &str, mount_point: &str, read_only: bool) -> StdResult
 {
-    let journaled;
+    let _fs = lock_fs();
+    let mut mounted = MOUNTED.lock();
+    if mounted.is_some() {
+        return Err(ERR_BUSY);
+    }
+
     let mut alloc = StrAlloc::new();
     let c_name = alloc.nulterm(dev_name)?.as_ptr() as *const _;
     let c_mp = alloc.nulterm(mount_point)?.as_ptr() as *const _;
 
-    debug!(DEBUG_FS, "mount \"{}\" as \"{}\"", dev_name, mount_point);
+    // lwext4 marks the superblock as in use when mounting, so the state left
+    // by the last unmount has to be captured first.
+    let state = raw_sb_state();
+    MOUNT_SB_STATE.store(state.unwrap_or(0) as usize, Ordering::SeqCst);
+
+    debug!(
+        DEBUG_FS,
+        "mount \"{}\" as \"{}\"{}",
+        dev_name,
+        mount_point,
+        if read_only { " (read-only)" } else { "" }
+    );
     to_stdresult(unsafe { lwext4::ext4_mount(c_name, c_mp, read_only) })?;
 
-    debug!(DEBUG_FS, "recover journal on \"{}\"", mount_point);
-    match to_stdresult(unsafe { lwext4::ext4_recover(c_mp) }) {
-        Ok(_) => {
-            journaled = true;
-        },
-        Err(e) if e == ERR_ENOTSUP => {
-            debug!(DEBUG_FS, "filesystem \"{}\" has no journal", mount_point);
-            journaled = false;
-        },
-        Err(e) => {
-            return Err(e);
-        },
+    let mut sb: *mut lwext4::ext4_sblock = ptr::null_mut();
+    let rc = unsafe { lwext4::ext4_get_sblock(c_mp, &mut sb) };
+    let inode_size = match to_stdresult(rc) {
+        Ok(()) => u16::from_le(unsafe { (*sb).inode_size }),
+        Err(_) => 0,
+    };
+    INODE_SIZE.store(inode_size as usize, Ordering::SeqCst);
+
+    if let Some(state) = state {
+        if state & SB_STATE_VALID == 0 || state & SB_STATE_ERROR != 0 {
+            debug!(
+                DEBUG_FS,
+                "warning: \"{}\" was not cleanly unmounted",
+                mount_point
+            );
+        }
     }
 
-    if journaled {
-        debug!(DEBUG_FS, "start journal on \"{}\"", mount_point);
-        to_stdresult(unsafe { lwext4::ext4_journal_start(c_mp) })?;
-    }
+    // Until MOUNTED is set nothing can unmount this, so a failure to get
+    // ready for writing has to undo the mount itself
+    let start_writes = || -> Result<bool, Error> {
+        debug!(DEBUG_FS, "recover journal on \"{}\"", mount_point);
+        let journaled =
+            match to_stdresult(unsafe { lwext4::ext4_recover(c_mp) }) {
+                Ok(_) => true,
+                Err(e) if e == ERR_ENOTSUP => {
+                    debug!(
+                        DEBUG_FS,
+                        "filesystem \"{}\" has no journal",
+                        mount_point
+                    );
+                    false
+                },
+                Err(e) => return Err(e),
+            };
+
+        if journaled {
+            debug!(DEBUG_FS, "start journal on \"{}\"", mount_point);
+            to_stdresult(unsafe { lwext4::ext4_journal_start(c_mp) })?;
+        }
+
+        let res = to_stdresult(unsafe {
+            lwext4::ext4_cache_write_back(c_mp, true)
+        });
+        if let Err(e) = res {
+            if journaled {
+                unsafe { lwext4::ext4_journal_stop(c_mp) };
+            }
+            return Err(e);
+        }
+        Ok(journaled)
+    };
+
+    let journaled = if read_only {
+        false
+    } else {
+        match start_writes() {
+            Ok(journaled) => journaled,
+            Err(e) => {
+                debug!(DEBUG_FS, "umount \"{}\" after error", mount_point);
+                unsafe { lwext4::ext4_umount(c_mp) };
+                return Err(e);
+            },
+        }
+    };
+
+    *mounted = Some(MountInfo {
+        dev_name: String::from(dev_name),
+        mount_point: String::from(mount_point),
+        read_only: read_only,
+        journaled: journaled,
+    });
+    drop(mounted);
+
+    if let Ok(stats) = statfs(mount_point) {
+        if read_only
+            && stats.features_incompat & FEATURE_INCOMPAT_RECOVER != 0
+        {
+            debug!(
+                DEBUG_FS,
+                "warning: journal on \"{}\" needs recovery; contents may \
+                 be stale until mounted read-write",
+                mount_point
+            );
+        }
 
-    to_stdresult(unsafe { lwext4::ext4_cache_write_back(c_mp, true) })?;
+        if stats.used_percent() >= FULL_WARN_PERCENT {
+            debug!(
+                DEBUG_FS,
+                "warning: \"{}\" is {}% full",
+                mount_point,
+                stats.used_percent()
+            );
+        }
+    }
 
     Ok(())
 }
 
-/// Unmount a filesystem.
+/// Unmount a filesystem. Fails with ERR_BUSY if any files or directories
+/// are open.
 pub fn umount(mount_point: &str) -> StdResult
 {
+    let _fs = lock_fs();
+    let mut mounted = MOUNTED.lock();
     let mut alloc = StrAlloc::new();
     let c_mp = alloc.nulterm(mount_point)?.as_ptr() as *const _;
 
-    debug!(DEBUG_FS, "flush cache on \"{}\"", mount_point);
-    to_stdresult(unsafe { lwext4::ext4_cache_write_back(c_mp, false) })?;
+    let read_only = match *mounted {
+        Some(ref info) if info.mount_point == mount_point => info.read_only,
+        _ => return Err(ERR_ENOENT),
+    };
 
-    debug!(DEBUG_FS, "stop journal on \"{}\", if any", mount_point);
-    to_stdresult(unsafe { lwext4::ext4_journal_stop(c_mp) })?;
+    if OPEN_HANDLES.load(Ordering::SeqCst) != 0 {
+        return Err(ERR_BUSY);
+    }
+
+    if !read_only {
+        debug!(DEBUG_FS, "flush cache on \"{}\"", mount_point);
+        to_stdresult(unsafe { lwext4::ext4_cache_write_back(c_mp, false) })?;
+
+        debug!(DEBUG_FS, "stop journal on \"{}\", if any", mount_point);
+        to_stdresult(unsafe { lwext4::ext4_journal_stop(c_mp) })?;
+    }
 
     debug!(DEBUG_FS, "umount \"{}\"", mount_point);
     to_stdresult(unsafe { lwext4::ext4_umount(c_mp) })?;
 
+    *mounted = None;
+    DIRTY_BYTES.store(0, Ordering::SeqCst);
     Ok(())
 }
 
+/// Unmount a filesystem, waiting up to `timeout_ms` for open handles to be
+/// closed.
+pub fn umount_wait(mount_point: &str, timeout_ms: u32) -> StdResult
+{
+    let end = os::ticks().wrapping_add(timeout_ms);
+
+    loop {
+        match umount(mount_point) {
+            Err(e) if e == ERR_BUSY
+                && (os::ticks().wrapping_sub(end) as i32) < 0 => {
+                os::delay(10);
+            },
+            result => return result,
+        }
+    }
+}
+
+/// Unmount a filesystem whose device has been removed. All unwritten data
+/// is discarded; the device must still be unregistered afterward. Open
+/// handles are left stale: every call on them fails with ERR_ENODEV, and
+/// closing them does nothing.
+pub fn force_umount(mount_point: &str) -> StdResult
+{
+    let _fs = lock_fs();
+    let mut mounted = MOUNTED.lock();
+    let mut alloc = StrAlloc::new();
+    let c_mp = alloc.nulterm(mount_point)?.as_ptr() as *const _;
+
+    match *mounted {
+        Some(ref info) if info.mount_point == mount_point => (),
+        _ => return Err(ERR_ENOENT),
+    }
+
+    DETACHED.store(true, Ordering::SeqCst);
+    debug!(DEBUG_FS, "force umount \"{}\", discarding cache", mount_point);
+
+    // With the device detached these can only fail on internal errors, and
+    // there is nothing to do about those but carry on tearing down.
+    unsafe {
+        lwext4::ext4_cache_write_back(c_mp, false);
+        lwext4::ext4_journal_stop(c_mp);
+    }
+    let rc = unsafe { lwext4::ext4_umount(c_mp) };
+
+    MOUNT_GEN.fetch_add(1, Ordering::SeqCst);
+    OPEN_HANDLES.store(0, Ordering::SeqCst);
+    *mounted = None;
+    DIRTY_BYTES.store(0, Ordering::SeqCst);
+    to_stdresult(rc)
+}
+
+/// Switch a mounted filesystem between read-only and read-write by
+/// unmounting and mounting it again. If mounting read-write fails, the
+/// filesystem is mounted read-only again before returning the error.
+pub fn remount(mount_point: &str, read_only: bool) -> StdResult
+{
+    let dev_name = match *MOUNTED.lock() {
+        Some(ref info) if info.mount_point == mount_point => {
+            if info.read_only == read_only {
+                return Ok(());
+            }
+            info.dev_name.clone()
+        },
+        _ => return Err(ERR_ENOENT),
+    };
+
+    umount(mount_point)?;
+
+    match mount(&dev_name, mount_point, read_only) {
+        Ok(()) => Ok(()),
+        Err(e) if !read_only => {
+            debug!(DEBUG_FS, "remount read-write failed, restoring read-only");
+            mount(&dev_name, mount_point, true)?;
+            Err(e)
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether the filesystem at a mount point is mounted read-only.
+pub fn read_only(mount_point: &str) -> Result<bool, Error>
+{
+    match *MOUNTED.lock() {
+        Some(ref info) if info.mount_point == mount_point => {
+            Ok(info.read_only)
+        },
+        _ => Err(ERR_ENOENT),
+    }
+}
+
+/// Whether the filesystem at a mount point is running a journal.
+pub fn journaled(mount_point: &str) -> Result<bool, Error>
+{
+    match *MOUNTED.lock() {
+        Some(ref info) if info.mount_point == mount_point => {
+            Ok(info.journaled)
+        },
+        _ => Err(ERR_ENOENT),
+    }
+}
+
+/// Fail with ERR_EROFS if the filesystem is mounted read-only. lwext4 does
+/// its own checks, but not in every path and not always with EROFS.
+fn check_writable() -> StdResult
+{
+    match *MOUNTED.lock() {
+        Some(ref info) if info.read_only => Err(ERR_EROFS),
+        _ => Ok(()),
+    }
+}
+
 /// Flush a filesystem's cache.
 pub fn sync(mount_point: &str) -> StdResult
 {
+    let _fs = lock_fs();
+    let mut alloc = StrAlloc::new();
+    let c_mp = alloc.nulterm(mount_point)?.as_ptr() as *const _;
+
+    // Not DEBUG_FS: this runs in the background and during file transfers,
+    // where the message would land in the middle of protocol replies
+    debug!(DEBUG_FLUSH, "flush cache on \"{}\"", mount_point);
+
+    let start = os::ticks();
+    let dirty = DIRTY_BYTES.swap(0, Ordering::SeqCst);
+    let rc = to_stdresult(unsafe { lwext4::ext4_cache_flush(c_mp) });
+    let end = os::ticks();
+
+    FLUSH_COUNT.fetch_add(1, Ordering::SeqCst);
+    FLUSH_LAST_TICK.store(end as usize, Ordering::SeqCst);
+    FLUSH_LAST_MS.store(end.wrapping_sub(start) as usize, Ordering::SeqCst);
+
+    if rc.is_err() {
+        FLUSH_ERRORS.fetch_add(1, Ordering::SeqCst);
+        DIRTY_BYTES.fetch_add(dirty, Ordering::SeqCst);
+    } else {
+        FLUSH_BYTES.fetch_add(dirty, Ordering::SeqCst);
+    }
+    rc
+}
+
+/// Flush the cache of the mounted filesystem, if it is mounted read-write.
+pub fn sync_all() -> StdResult
+{
+    let mount_point = match *MOUNTED.lock() {
+        Some(ref info) if !info.read_only => info.mount_point.clone(),
+        _ => return Ok(()),
+    };
+    sync(&mount_point)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Background flush
+// With write-back caching enabled, written data stays in RAM until the cache
+// is flushed. Writes through this module are counted here so that a
+// background task can call flush_if_due() periodically, and flush once writes
+// have been idle for a while or enough data has piled up.
+
+static DIRTY_BYTES: AtomicUsize = AtomicUsize::new(0);
+static LAST_WRITE_TICK: AtomicUsize = AtomicUsize::new(0);
+static FLUSH_IDLE_MS: AtomicUsize = AtomicUsize::new(2000);
+static FLUSH_THRESHOLD: AtomicUsize = AtomicUsize::new(256 * 1024);
+
+static FLUSH_COUNT: AtomicUsize = AtomicUsize::new(0);
+static FLUSH_ERRORS: AtomicUsize = AtomicUsize::new(0);
+static FLUSH_BYTES: AtomicUsize = AtomicUsize::new(0);
+static FLUSH_LAST_TICK: AtomicUsize = AtomicUsize::new(0);
+static FLUSH_LAST_MS: AtomicUsize = AtomicUsize::new(0);
+
+/// Record a modification of `bytes` bytes that has not been flushed yet.
+fn mark_dirty(bytes: usize)
+{
+    DIRTY_BYTES.fetch_add(if bytes == 0 { 1 } else { bytes }, Ordering::SeqCst);
+    LAST_WRITE_TICK.store(os::ticks() as usize, Ordering::SeqCst);
+}
+
+/// Flush the mounted filesystem if there are unflushed writes and either no
+/// write has happened for the idle period, or the dirty threshold is reached.
+pub fn flush_if_due() -> StdResult
+{
+    let dirty = DIRTY_BYTES.load(Ordering::SeqCst);
+    if dirty == 0 {
+        return Ok(());
+    }
+
+    let last_write = LAST_WRITE_TICK.load(Ordering::SeqCst) as u32;
+    let idle = os::ticks().wrapping_sub(last_write) as usize;
+
+    if idle >= FLUSH_IDLE_MS.load(Ordering::SeqCst)
+        || dirty >= FLUSH_THRESHOLD.load(Ordering::SeqCst)
+    {
+        sync_all()
+    } else {
+        Ok(())
+    }
+}
+
+/// Set the background flush policy: flush after `idle_ms` milliseconds
+/// without writes, or once `threshold` bytes have been written.
+pub fn set_flush_policy(idle_ms: u32, threshold: usize)
+{
+    FLUSH_IDLE_MS.store(idle_ms as usize, Ordering::SeqCst);
+    FLUSH_THRESHOLD.store(threshold, Ordering::SeqCst);
+}
+
+/// Flush counters and policy, for display.
+pub struct FlushStats {
+    pub idle_ms: u32,
+    pub threshold: usize,
+    pub dirty_bytes: usize,
+    pub flushes: usize,
+    pub errors: usize,
+    pub bytes_flushed: usize,
+    /// Tick count at the end of the last flush
+    pub last_tick: u32,
+    /// Duration of the last flush in milliseconds
+    pub last_ms: u32,
+}
+
+pub fn flush_stats() -> FlushStats
+{
+    FlushStats {
+        idle_ms: FLUSH_IDLE_MS.load(Ordering::SeqCst) as u32,
+        threshold: FLUSH_THRESHOLD.load(Ordering::SeqCst),
+        dirty_bytes: DIRTY_BYTES.load(Ordering::SeqCst),
+        flushes: FLUSH_COUNT.load(Ordering::SeqCst),
+        errors: FLUSH_ERRORS.load(Ordering::SeqCst),
+        bytes_flushed: FLUSH_BYTES.load(Ordering::SeqCst),
+        last_tick: FLUSH_LAST_TICK.load(Ordering::SeqCst) as u32,
+        last_ms: FLUSH_LAST_MS.load(Ordering::SeqCst) as u32,
+    }
+}
+
+/// Usage above which mount() warns that the filesystem is nearly full.
+const FULL_WARN_PERCENT: u32 = 90;
+
+const SB_STATE_VALID: u16 = 0x0001;
+const SB_STATE_ERROR: u16 = 0x0002;
+const SB_MAGIC: u16 = 0xEF53;
+const SB_OFFSET_MAGIC: usize = 0x38;
+const SB_OFFSET_STATE: usize = 0x3A;
+
+const FEATURE_COMPAT_HAS_JOURNAL: u32 = 0x0004;
+const FEATURE_INCOMPAT_RECOVER: u32 = 0x0004;
+const FEATURE_INCOMPAT_EXTENTS: u32 = 0x0040;
+const FEATURE_INCOMPAT_64BIT: u32 = 0x0080;
+const FEATURE_INCOMPAT_FLEX_BG: u32 = 0x0200;
+
+/// Superblock state as found on disk by the last mount(), or zero if it could
+/// not be read.
+static MOUNT_SB_STATE: AtomicUsize = AtomicUsize::new(0);
+
+/// On-disk inode size of the mounted filesystem, or zero if unknown.
+static INODE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Read the superblock state straight from the registered block device,
+/// bypassing lwext4. Returns None if no device is registered, the read
+/// fails, or there is no ext2/3/4 superblock.
+fn raw_sb_state() -> Option<u16>
+{
+    let lock = BLOCKDEV.lock();
+    let bd = match lock.as_ref() {
+        Some(bd) => bd,
+        None => return None,
+    };
+
+    // The superblock lives 1024 bytes into the partition
+    let iblock = (bd.lwext4_bd.part_offset / 512) as usize + 2;
+    let mut buf = [0u8; 512];
+    if bd.sd.lock().read_block(iblock, &mut buf).is_err() {
+        return None;
+    }
+
+    let magic = buf[SB_OFFSET_MAGIC] as u16
+        | (buf[SB_OFFSET_MAGIC + 1] as u16) << 8;
+    if magic != SB_MAGIC {
+        return None;
+    }
+
+    Some(buf[SB_OFFSET_STATE] as u16 | (buf[SB_OFFSET_STATE + 1] as u16) << 8)
+}
+
+/// Usage and superblock summary of a mounted filesystem.
+pub struct FsStats {
+    pub block_size: u32,
+    pub blocks_count: u64,
+    pub free_blocks: u64,
+    pub inodes_count: u32,
+    pub free_inodes: u32,
+    pub block_group_count: u32,
+    pub uuid: Uuid,
+    pub features_compat: u32,
+    pub features_incompat: u32,
+    pub features_ro_compat: u32,
+    pub mount_count: u16,
+    pub read_only: bool,
+    volume_name: [u8; 16],
+    mount_state: u16,
+}
+
+/// Get usage and superblock details for a mount point.
+pub fn statfs(mount_point: &str) -> Result<FsStats, Error>
+{
+    let _fs = lock_fs();
     let mut alloc = StrAlloc::new();
     let c_mp = alloc.nulterm(mount_point)?.as_ptr() as *const _;
 
-    debug!(DEBUG_FS, "flush cache on \"{}\"", mount_point);
-    to_stdresult(unsafe { lwext4::ext4_cache_flush(c_mp) })
+    let mut ms: lwext4::ext4_mount_stats = unsafe { mem::zeroed() };
+    to_stdresult(unsafe { lwext4::ext4_mount_point_stats(c_mp, &mut ms) })?;
+
+    let mut sb: *mut lwext4::ext4_sblock = ptr::null_mut();
+    to_stdresult(unsafe { lwext4::ext4_get_sblock(c_mp, &mut sb) })?;
+    let sb = unsafe { &*sb };
+
+    let mut volume_name = [0u8; 16];
+    for (dest, &src) in volume_name.iter_mut().zip(ms.volume_name.iter()) {
+        *dest = src as u8;
+    }
+
+    Ok(FsStats {
+        block_size: ms.block_size,
+        blocks_count: ms.blocks_count,
+        free_blocks: ms.free_blocks_count,
+        inodes_count: ms.inodes_count,
+        free_inodes: ms.free_inodes_count,
+        block_group_count: ms.block_group_count,
+        uuid: Uuid(sb.uuid),
+        features_compat: u32::from_le(sb.features_compatible),
+        features_incompat: u32::from_le(sb.features_incompatible),
+        features_ro_compat: u32::from_le(sb.features_read_only),
+        mount_count: u16::from_le(sb.mount_count),
+        read_only: read_only(mount_point).unwrap_or(false),
+        volume_name: volume_name,
+        mount_state: MOUNT_SB_STATE.load(Ordering::SeqCst) as u16,
+    })
+}
+
+impl FsStats {
+    /// Volume label, up to the first nul.
+    pub fn volume_name(&self) -> &str
+    {
+        let len = self.volume_name.iter().position(|&c| c == 0).unwrap_or(16);
+        str::from_utf8(&self.volume_name[0 .. len]).unwrap_or("?")
+    }
+
+    pub fn used_blocks(&self) -> u64
+    {
+        self.blocks_count - self.free_blocks
+    }
+
+    pub fn size_bytes(&self) -> u64
+    {
+        self.blocks_count * self.block_size as u64
+    }
+
+    pub fn free_bytes(&self) -> u64
+    {
+        self.free_blocks * self.block_size as u64
+    }
+
+    pub fn used_bytes(&self) -> u64
+    {
+        self.used_blocks() * self.block_size as u64
+    }
+
+    /// Percentage of blocks in use, rounded up like df.
+    pub fn used_percent(&self) -> u32
+    {
+        if self.blocks_count == 0 {
+            return 0;
+        }
+        let used = self.used_blocks() * 100;
+        ((used + self.blocks_count - 1) / self.blocks_count) as u32
+    }
+
+    pub fn journaled(&self) -> bool
+    {
+        self.features_compat & FEATURE_COMPAT_HAS_JOURNAL != 0
+    }
+
+    /// Whether the filesystem had been cleanly unmounted before it was
+    /// mounted. None if the state could not be read at mount time.
+    pub fn clean_at_mount(&self) -> Option<bool>
+    {
+        if self.mount_state == 0 {
+            None
+        } else {
+            Some(
+                self.mount_state & SB_STATE_VALID != 0
+                    && self.mount_state & SB_STATE_ERROR == 0,
+            )
+        }
+    }
+
+    /// Best guess at the filesystem flavor from its feature flags.
+    pub fn fs_type(&self) -> &'static str
+    {
+        let ext4_features = FEATURE_INCOMPAT_EXTENTS | FEATURE_INCOMPAT_64BIT
+            | FEATURE_INCOMPAT_FLEX_BG;
+
+        if self.features_incompat & ext4_features != 0 {
+            "ext4"
+        } else if self.journaled() {
+            "ext3"
+        } else {
+            "ext2"
+        }
+    }
+
+    /// Iterate over the names of all known features that are set.
+    pub fn features(&self) -> Features
+    {
+        Features {
+            stats: self,
+            i: 0,
+        }
+    }
+}
+
+/// Feature flag names, as used by mke2fs. (set, bit, name); set is 0 for
+/// compatible, 1 for incompatible and 2 for read-only compatible.
+static FEATURE_NAMES: &[(u8, u32, &str)] = &[
+    (0, 0x0001, "dir_prealloc"),
+    (0, 0x0002, "imagic_inodes"),
+    (0, 0x0004, "has_journal"),
+    (0, 0x0008, "ext_attr"),
+    (0, 0x0010, "resize_inode"),
+    (0, 0x0020, "dir_index"),
+    (1, 0x0001, "compression"),
+    (1, 0x0002, "filetype"),
+    (1, 0x0004, "needs_recovery"),
+    (1, 0x0008, "journal_dev"),
+    (1, 0x0010, "meta_bg"),
+    (1, 0x0040, "extent"),
+    (1, 0x0080, "64bit"),
+    (1, 0x0100, "mmp"),
+    (1, 0x0200, "flex_bg"),
+    (1, 0x0400, "ea_inode"),
+    (1, 0x1000, "dirdata"),
+    (1, 0x2000, "metadata_csum_seed"),
+    (1, 0x4000, "large_dir"),
+    (1, 0x8000, "inline_data"),
+    (2, 0x0001, "sparse_super"),
+    (2, 0x0002, "large_file"),
+    (2, 0x0008, "huge_file"),
+    (2, 0x0010, "uninit_bg"),
+    (2, 0x0020, "dir_nlink"),
+    (2, 0x0040, "extra_isize"),
+    (2, 0x0100, "quota"),
+    (2, 0x0200, "bigalloc"),
+    (2, 0x0400, "metadata_csum"),
+];
+
+/// Iterator over the feature names of a filesystem.
+pub struct Features<'a> {
+    stats: &'a FsStats,
+    i: usize,
+}
+
+impl<'a> Iterator for Features<'a> {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<&'static str>
+    {
+        while self.i < FEATURE_NAMES.len() {
+            let (set, bit, name) = FEATURE_NAMES[self.i];
+            self.i += 1;
+
+            let flags = match set {
+                0 => self.stats.features_compat,
+                1 => self.stats.features_incompat,
+                _ => self.stats.features_ro_compat,
+            };
+            if flags & bit != 0 {
+                return Some(name);
+            }
+        }
+        None
+    }
+}
+
+/// Filesystem UUID. Unlike a GPT GUID, this is stored in display order.
+#[derive(Copy, Clone, PartialEq)]
+pub struct Uuid(pub [u8; 16]);
+
+impl fmt::Display for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        for (i, b) in self.0.iter().enumerate() {
+            if i == 4 || i == 6 || i == 8 || i == 10 {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Filesystem flavor for mkfs.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FsType {
+    Ext2,
+    Ext3,
+    Ext4,
+}
+
+impl FsType {
+    pub fn from_name(name: &str) -> Option<FsType>
+    {
+        match name {
+            "ext2" => Some(FsType::Ext2),
+            "ext3" => Some(FsType::Ext3),
+            "ext4" => Some(FsType::Ext4),
+            _ => None,
+        }
+    }
+
+    fn to_lwext4(&self) -> i32
+    {
+        match *self {
+            FsType::Ext2 => lwext4_mkfs::F_SET_EXT2 as i32,
+            FsType::Ext3 => lwext4_mkfs::F_SET_EXT3 as i32,
+            FsType::Ext4 => lwext4_mkfs::F_SET_EXT4 as i32,
+        }
+    }
+}
+
+/// Parameters for mkfs. A block size of zero lets lwext4 choose (4096).
+pub struct MkfsOptions<'a> {
+    pub fs_type: FsType,
+    pub block_size: u32,
+    pub label: &'a str,
+    pub journal: bool,
+}
+
+/// Format a block device. Refuses with ERR_BUSY if any device is currently
+/// registered, as it may be mounted; the registration slot stays locked for
+/// the duration so nothing can be mounted while formatting.
+pub fn mkfs(mut bd: SdBlockDev, opts: &MkfsOptions) -> StdResult
+{
+    let _fs = lock_fs();
+    let lock = BLOCKDEV.lock();
+    if lock.is_some() {
+        return Err(ERR_BUSY);
+    }
+
+    match opts.block_size {
+        0 | 1024 | 2048 | 4096 => (),
+        _ => return Err(ERR_EINVAL),
+    }
+
+    let mut alloc = StrAlloc::new();
+    let c_label = alloc.nulterm(opts.label)?.as_ptr() as *const _;
+
+    let mut info: lwext4_mkfs::ext4_mkfs_info = unsafe { mem::zeroed() };
+    info.block_size = opts.block_size;
+    info.journal = opts.journal && opts.fs_type != FsType::Ext2;
+    info.label = c_label;
+
+    // ext4_fs holds a full superblock and more; keep it off the task stack.
+    let mut fs: Box<lwext4_mkfs::ext4_fs> = Box::new(unsafe { mem::zeroed() });
+
+    debug!(
+        DEBUG_FS,
+        "mkfs {:?}, {} bytes, block size {}",
+        opts.fs_type,
+        bd.lwext4_bd.part_size,
+        opts.block_size
+    );
+
+    // The mkfs bindings are a separate bindgen crate, so their blockdev type
+    // is distinct from ours despite having the same layout.
+    let rc = unsafe {
+        lwext4_mkfs::ext4_mkfs(
+            &mut *fs,
+            bd.to_ptr() as *mut lwext4_mkfs::ext4_blockdev,
+            &mut info,
+            opts.fs_type.to_lwext4(),
+        )
+    };
+    drop(lock);
+
+    to_stdresult(rc)
 }
 
 /// Open a directory.
 pub fn dir_open(path: &str) -> Result<Dir, Error>
 {
     let mut alloc = StrAlloc::new();
-    let c_path = alloc.nulterm(path)?.as_ptr() as *const _;
+    let c_path = alloc.nulterm(path)?.as_ptr();
+    unsafe {
+        dir_open_cstr(c_path)
+    }
+}
 
-    let mut dir: Dir = unsafe { mem::zeroed() };
-    to_stdresult(unsafe { lwext4::ext4_dir_open(&mut dir.0, c_path) })?;
+/// Open a directory from a C string path. Used internally to reduce the
+/// allocations caused by additional null-termination when the string is
+/// already terminated or can be terminated cheaply.
+unsafe fn dir_open_cstr(path: *const u8) -> Result<Dir, Error>
+{
+    let _fs = lock_fs();
+    let c_path = path as *const _;
+    let mut dir: Dir = mem::zeroed();
+    dir.1 = MOUNT_GEN.load(Ordering::SeqCst);
+    OPEN_HANDLES.fetch_add(1, Ordering::SeqCst);
+    to_stdresult(lwext4::ext4_dir_open(&mut dir.0, c_path))?;
 
     Ok(dir)
 }
@@ -286,12 +1101,24 @@ pub fn fopen(path: &str, flags: OpenFlags) -> Result<File, Error>
 /// or can be terminated cheaply.
 unsafe fn fopen_cstr(path: *const u8, flags: OpenFlags) -> Result<File, Error>
 {
+    let _fs = lock_fs();
+    if flags != OpenFlags::Read {
+        check_writable()?;
+    }
+
     let c_path = path as *const _;
     let mut file: File = mem::zeroed();
+    file.1 = MOUNT_GEN.load(Ordering::SeqCst);
+    OPEN_HANDLES.fetch_add(1, Ordering::SeqCst);
     to_stdresult(
         lwext4::ext4_fopen2(&mut file.0, c_path, flags as _)
     )?;
 
+    if flags != OpenFlags::Read && flags != OpenFlags::ReadWrite {
+        // Opening may have created or truncated the file
+        mark_dirty(0);
+    }
+
     Ok(file)
 }
 
@@ -323,23 +1150,65 @@ pub fn stat(path: &str) -> Result<Stat, Error>
 /// or can be terminated cheaply.
 unsafe fn stat_cstr(path: *const u8) -> Result<Stat, Error>
 {
+    let _fs = lock_fs();
     let c_path = path as *const _;
-    let mut inode: Stat = mem::zeroed();
-    let mut ret_ino = 0u32;
+    let mut st: Stat = mem::zeroed();
 
     to_stdresult(
-        lwext4::ext4_raw_inode_fill(c_path, &mut ret_ino, &mut inode.0)
+        lwext4::ext4_raw_inode_fill(c_path, &mut st.ino, &mut st.inode)
     )?;
 
-    Ok(inode as Stat)
+    // lwext4 copies a whole struct ext4_inode whatever the on-disk size, so
+    // past 128 bytes there may be the next inode rather than extra fields
+    st.extra_isize = if INODE_SIZE.load(Ordering::SeqCst) > BASE_INODE_SIZE {
+        u16::from_le(st.inode.extra_isize)
+    } else {
+        0
+    };
+
+    Ok(st)
 }
 
 #[repr(C)]
-pub struct Stat(lwext4::ext4_inode);
+#[derive(Copy, Clone)]
+pub struct Stat {
+    inode: lwext4::ext4_inode,
+    ino: u32,
+    /// Size of the extended inode area actually on disk
+    extra_isize: u16,
+}
+
+/// Inode size of ext2/ext3, which has no extended area
+const BASE_INODE_SIZE: usize = 128;
+
+/// Timestamp with nanosecond resolution. `sec` is seconds since the Unix
+/// epoch; inodes without extra timestamp space always have `nsec == 0`.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Timespec {
+    pub sec: i64,
+    pub nsec: u32,
+}
+
+impl Timespec {
+    /// Decode an on-disk timestamp. The low two bits of `extra` extend the
+    /// signed 32-bit seconds past 2038; the rest are nanoseconds.
+    fn from_raw(base: u32, extra: Option<u32>) -> Timespec
+    {
+        let sec = base as i32 as i64;
+        match extra {
+            Some(extra) => Timespec {
+                sec: sec + (((extra & 3) as i64) << 32),
+                nsec: extra >> 2,
+            },
+            None => Timespec { sec: sec, nsec: 0 },
+        }
+    }
+}
 
 /// Read a link.
 pub fn readlink(path: &str) -> Result<String, Error>
 {
+    let _fs = lock_fs();
     let mut alloc = StrAlloc::new();
     let c_path = alloc.nulterm(path)?.as_ptr() as *const _;
 
@@ -372,72 +1241,335 @@ pub fn readlink(path: &str) -> Result<String, Error>
 /// Unlink a path.
 pub fn unlink(path: &str) -> StdResult
 {
+    let _fs = lock_fs();
+    check_writable()?;
+
     let mut alloc = StrAlloc::new();
     let c_path = alloc.nulterm(path)?.as_ptr() as *const _;
 
     let rc = unsafe { lwext4::ext4_fremove(c_path) };
+    mark_dirty(0);
     to_stdresult(rc)
 }
 
-/// Expand a path, following all symlinks.
-pub fn expand(path: &str) -> Result<String, Error>
+/// Create a directory. Fails with ERR_EEXIST if the path exists.
+pub fn mkdir(path: &str) -> StdResult
 {
-    let mut s = String::with_capacity(1024);
+    let _fs = lock_fs();
+    check_writable()?;
 
-    for i in path.split('/') {
-        if i.len() == 0 {
-            continue;
+    // lwext4 quietly succeeds on existing directories
+    if stat(path).is_ok() {
+        return Err(ERR_EEXIST);
+    }
+
+    let mut alloc = StrAlloc::new();
+    let c_path = alloc.nulterm(path)?.as_ptr() as *const _;
+
+    let rc = unsafe { lwext4::ext4_dir_mk(c_path) };
+    mark_dirty(0);
+    to_stdresult(rc)
+}
+
+/// Remove an empty directory. Fails with ERR_ENOTEMPTY otherwise; lwext4
+/// itself would delete the whole tree.
+pub fn rmdir(path: &str) -> StdResult
+{
+    let _fs = lock_fs();
+    check_writable()?;
+
+    if stat(path)?.inode_type() != InodeType::Dir {
+        return Err(ERR_ENOTDIR);
+    }
+
+    {
+        let mut dir = dir_open(path)?;
+        for entry in dir.iter() {
+            match entry.name()? {
+                "." | ".." => (),
+                _ => return Err(ERR_ENOTEMPTY),
+            }
+        }
+    }
+
+    let mut alloc = StrAlloc::new();
+    let c_path = alloc.nulterm(path)?.as_ptr() as *const _;
+
+    let rc = unsafe { lwext4::ext4_dir_rm(c_path) };
+    mark_dirty(0);
+    to_stdresult(rc)
+}
+
+/// Rename a file or directory. Fails with ERR_EEXIST if the new path exists.
+pub fn rename(old_path: &str, new_path: &str) -> StdResult
+{
+    let _fs = lock_fs();
+    check_writable()?;
+
+    let mut alloc = StrAlloc::new();
+    let c_old = alloc.nulterm(old_path)?.as_ptr() as *const _;
+    let c_new = alloc.nulterm(new_path)?.as_ptr() as *const _;
+
+    let rc = unsafe { lwext4::ext4_frename(c_old, c_new) };
+    mark_dirty(0);
+    to_stdresult(rc)
+}
+
+// Extended attributes
+// Names include the namespace prefix, e.g. "user.fpga.target". lwext4 knows
+// the user, trusted, security and system namespaces; any other prefix fails
+// with ERR_EINVAL.
+
+/// Largest value getxattr() will return. An attribute must fit in the inode
+/// or a single block, so this covers every block size lwext4 supports.
+const XATTR_VALUE_MAX: usize = 4096;
+
+/// Get the value of an extended attribute. Fails with ERR_ENODATA if the
+/// path has no attribute by that name.
+pub fn getxattr(path: &str, name: &str) -> Result<Vec<u8>, Error>
+{
+    let _fs = lock_fs();
+    let mut alloc = StrAlloc::new();
+    let c_path = alloc.nulterm(path)?.as_ptr() as *const _;
+
+    // Most attributes are short; only go to the maximum if needed.
+    let mut buf = vec::from_elem(0u8, 256);
+
+    loop {
+        let mut size = 0usize;
+        let rc = unsafe {
+            lwext4::ext4_getxattr(
+                c_path,
+                name.as_ptr() as *const _,
+                name.len(),
+                buf.as_mut_slice().as_mut_ptr() as *mut _,
+                buf.len(),
+                &mut size,
+            )
         };
 
-        // In order to stat this path element, we append it to the string
-        // builder, stat that path, and then truncate it back off.
+        let short = rc == ERANGE || size > buf.len();
+        if short && buf.len() < XATTR_VALUE_MAX {
+            buf = vec::from_elem(0u8, XATTR_VALUE_MAX);
+            continue;
+        }
+
+        to_stdresult(rc)?;
+        if size > buf.len() {
+            return Err(ERR_ERANGE);
+        }
+        buf.truncate(size);
+        return Ok(buf);
+    }
+}
+
+/// Get the value of an extended attribute as a string.
+pub fn getxattr_str(path: &str, name: &str) -> Result<String, Error>
+{
+    match String::from_utf8(getxattr(path, name)?) {
+        Ok(s) => Ok(s),
+        Err(_) => Err(ERR_UTF8),
+    }
+}
+
+/// Set an extended attribute, creating or replacing it.
+pub fn setxattr(path: &str, name: &str, value: &[u8]) -> StdResult
+{
+    let _fs = lock_fs();
+    check_writable()?;
+
+    let mut alloc = StrAlloc::new();
+    let c_path = alloc.nulterm(path)?.as_ptr() as *const _;
+
+    let rc = unsafe {
+        lwext4::ext4_setxattr(
+            c_path,
+            name.as_ptr() as *const _,
+            name.len(),
+            value.as_ptr() as *const _,
+            value.len(),
+        )
+    };
+    mark_dirty(value.len());
+    to_stdresult(rc)
+}
+
+/// List the names of all extended attributes of a path.
+pub fn listxattr(path: &str) -> Result<Vec<String>, Error>
+{
+    let _fs = lock_fs();
+    let mut alloc = StrAlloc::new();
+    let c_path = alloc.nulterm(path)?.as_ptr() as *const _;
+
+    // A zero-length list only asks for the size needed.
+    let mut size = 0usize;
+    let rc = unsafe {
+        lwext4::ext4_listxattr(c_path, ptr::null_mut(), 0, &mut size)
+    };
+    to_stdresult(rc)?;
+
+    if size == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut buf = vec::from_elem(0u8, size);
+    let rc = unsafe {
+        lwext4::ext4_listxattr(
+            c_path,
+            buf.as_mut_slice().as_mut_ptr() as *mut _,
+            buf.len(),
+            &mut size,
+        )
+    };
+    to_stdresult(rc)?;
+    buf.truncate(size);
+
+    // Names are NUL-terminated and packed one after another.
+    let mut names = Vec::new();
+    for name in buf.split(|&c| c == 0).filter(|n| n.len() > 0) {
+        match str::from_utf8(name) {
+            Ok(s) => names.push(String::from(s)),
+            Err(_) => return Err(ERR_UTF8),
+        }
+    }
+    Ok(names)
+}
+
+/// Remove an extended attribute. Fails with ERR_ENODATA if there is no
+/// attribute by that name.
+pub fn removexattr(path: &str, name: &str) -> StdResult
+{
+    let _fs = lock_fs();
+    check_writable()?;
+
+    let mut alloc = StrAlloc::new();
+    let c_path = alloc.nulterm(path)?.as_ptr() as *const _;
+
+    let rc = unsafe {
+        lwext4::ext4_removexattr(
+            c_path,
+            name.as_ptr() as *const _,
+            name.len(),
+        )
+    };
+    mark_dirty(0);
+    to_stdresult(rc)
+}
+
+/// Maximum number of symlinks followed while expanding one path before giving
+/// up with ERR_ELOOP.
+pub const SYMLINK_MAX: usize = 16;
+
+/// Expand a path, following all symlinks and resolving `.` and `..`. The
+/// result is absolute and contains no symlinks. `..` is applied after the
+/// preceding components are resolved, so it names the physical parent.
+pub fn expand(path: &str) -> Result<String, Error>
+{
+    let mut s = String::with_capacity(256);
+    let mut rest = String::from(path);
+    let mut pos = 0;
+    let mut nlinks = 0;
+
+    while pos < rest.len() {
+        let end = rest[pos ..].find('/').map_or(rest.len(), |i| pos + i);
         let len = s.len();
-        s.push('/');
-        s.push_str(i);
-        s.push('\0');
 
-        let stat = unsafe { stat_cstr(s.as_ptr()) }?;
+        match &rest[pos .. end] {
+            "" | "." => (),
+            ".." => {
+                let cut = s.rfind('/').unwrap_or(0);
+                s.truncate(cut);
+            },
+            comp => {
+                s.push('/');
+                s.push_str(comp);
+            },
+        }
+        pos = end + 1;
+
+        if s.len() <= len {
+            continue;
+        }
 
-        // Truncate the \0 that was added just to get a C string
+        // In order to stat this path element, we append a terminator to the
+        // string builder, stat that path, and then truncate it back off.
+        s.push('\0');
+        let stat = unsafe { stat_cstr(s.as_ptr()) };
         let without_nulterm = s.len() - 1;
         s.truncate(without_nulterm);
 
-        if stat.inode_type() == InodeType::Symlink {
-            let link = readlink(&s)?;
+        if stat?.inode_type() == InodeType::Symlink {
+            nlinks += 1;
+            if nlinks > SYMLINK_MAX {
+                return Err(ERR_ELOOP);
+            }
 
-            if link.as_bytes()[0] == '/' as u8 {
+            // Splice the link target in front of what is left to resolve
+            let mut link = readlink(&s)?;
+            if link.starts_with('/') {
                 s.truncate(0);
             } else {
                 s.truncate(len);
-                s.push('/');
             }
-            s.push_str(&link);
+            if pos < rest.len() {
+                link.push('/');
+                link.push_str(&rest[pos ..]);
+            }
+            rest = link;
+            pos = 0;
         }
     }
 
-    if s.len() == 0 && path.len() > 0 {
-        // Special case, we were given "/" or similar ("///", etc). These will
-        // produce an empty stringbuilder but should expand to "/"
+    if s.len() == 0 {
+        // We were given "/" or similar ("///", "/..", etc). These produce an
+        // empty string builder but should expand to "/"
         s.push('/');
     }
 
     Ok(s)
 }
 
+/// Open directory, with the mount generation it was opened under.
 #[repr(C)]
-pub struct Dir(lwext4::ext4_dir);
+pub struct Dir(lwext4::ext4_dir, usize);
 
 impl Dir {
+    /// Iterate over the entries from the start. A stale handle yields none.
     pub fn iter(&mut self) -> DirIter
     {
-        unsafe { lwext4::ext4_dir_entry_rewind(&mut self.0) };
+        let _fs = lock_fs();
+        if check_gen(self.1).is_ok() {
+            unsafe { lwext4::ext4_dir_entry_rewind(&mut self.0) };
+        }
         DirIter { dir: self }
     }
+
+    /// Get the next entry, or None at the end of the directory.
+    fn entry_next(&mut self) -> Result<Option<DirEntry>, Error>
+    {
+        let _fs = lock_fs();
+        check_gen(self.1)?;
+        let de = unsafe { lwext4::ext4_dir_entry_next(&mut self.0) };
+
+        if de == ptr::null() {
+            Ok(None)
+        } else {
+            Ok(Some(DirEntry {
+                de: de,
+                _p: PhantomData,
+            }))
+        }
+    }
 }
 
 impl ops::Drop for Dir {
     fn drop(&mut self)
     {
+        let _fs = lock_fs();
+        if check_gen(self.1).is_err() {
+            return;
+        }
+        OPEN_HANDLES.fetch_sub(1, Ordering::SeqCst);
         to_stdresult(unsafe { lwext4::ext4_dir_close(&mut self.0) })
             .unwrap();
     }
@@ -472,6 +1604,10 @@ impl<'a> Iterator for DirIter<'a> {
 
     fn next(&mut self) -> Option<DirEntry<'a>>
     {
+        let _fs = lock_fs();
+        if check_gen(self.dir.1).is_err() {
+            return None;
+        }
         let de = unsafe { lwext4::ext4_dir_entry_next(&mut self.dir.0) };
 
         if de == ptr::null() {
@@ -485,28 +1621,278 @@ impl<'a> Iterator for DirIter<'a> {
     }
 }
 
-#[repr(C)]
-pub struct File(lwext4::ext4_file);
+/// Default maximum depth for `walk()`. Each level holds a directory open,
+/// so this bounds the memory used as well as the time spent in symlink
+/// loops.
+pub const WALK_MAX_DEPTH: usize = 16;
+
+/// How `Walk` treats symbolic links.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Symlinks {
+    /// Yield links as entries, but do not descend into them.
+    Report,
+    /// Do not yield links at all.
+    Skip,
+    /// Yield links, and descend into those that point to directories. Link
+    /// cycles are only bounded by the depth limit.
+    Follow,
+}
+
+/// Recursive directory walker. Yields every entry below the starting
+/// directory in depth-first pre-order, skipping `.` and `..`.
+///
+/// Entries are yielded as `Result`s; an error on one entry (e.g. a failed
+/// stat) does not end the walk.
+pub struct Walk {
+    /// Open directories, with the length of each one's path in `path`
+    stack: Vec<(Dir, usize)>,
+    /// Path builder, holding the path of the most recent entry
+    path: String,
+    max_depth: usize,
+    symlinks: Symlinks,
+    /// Error to yield on the next call, after the entry that caused it
+    error: Option<Error>,
+}
+
+/// One entry yielded by `Walk`.
+pub struct WalkEntry {
+    path: String,
+    name_start: usize,
+    depth: usize,
+    stat: Stat,
+}
+
+/// Walk the directory tree at `path`. Does not expand symlinks in `path`
+/// itself.
+pub fn walk(path: &str) -> Result<Walk, Error>
+{
+    let mut s = String::with_capacity(256);
+    s.push_str(path.trim_right_matches('/'));
+
+    // The root directory is represented by an empty path, so that children
+    // are built as "" + "/" + name.
+    let root_len = s.len();
+    if root_len == 0 {
+        s.push('/');
+    }
+    s.push('\0');
+    let dir = unsafe { dir_open_cstr(s.as_ptr()) }?;
+    s.truncate(root_len);
+
+    let mut stack = Vec::with_capacity(WALK_MAX_DEPTH);
+    stack.push((dir, root_len));
+
+    Ok(Walk {
+        stack: stack,
+        path: s,
+        max_depth: WALK_MAX_DEPTH,
+        symlinks: Symlinks::Report,
+        error: None,
+    })
+}
+
+impl Walk {
+    /// Set the maximum depth. Entries directly inside the starting directory
+    /// have depth 1, so `max_depth(1)` lists a single directory.
+    pub fn max_depth(mut self, depth: usize) -> Walk
+    {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Set the symlink policy. Default is `Symlinks::Report`.
+    pub fn symlinks(mut self, policy: Symlinks) -> Walk
+    {
+        self.symlinks = policy;
+        self
+    }
+
+    /// Descend into the directory at `self.path`, which is an entry at the
+    /// current depth.
+    fn push_dir(&mut self, follow: bool) -> StdResult
+    {
+        let len = self.path.len();
+
+        let dir = if follow {
+            let mut expanded = expand(&self.path)?;
+            expanded.push('\0');
+            unsafe { dir_open_cstr(expanded.as_ptr()) }?
+        } else {
+            self.path.push('\0');
+            let dir = unsafe { dir_open_cstr(self.path.as_ptr()) };
+            self.path.truncate(len);
+            dir?
+        };
+
+        self.stack.push((dir, len));
+        Ok(())
+    }
+}
+
+impl Iterator for Walk {
+    type Item = Result<WalkEntry, Error>;
+
+    fn next(&mut self) -> Option<Result<WalkEntry, Error>>
+    {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+
+        loop {
+            let depth = self.stack.len();
+            let parent_len = match self.stack.last() {
+                Some(&(_, len)) => len,
+                None => return None,
+            };
+
+            // Append the next name to the parent path
+            let got_entry = {
+                let dir = &mut self.stack.last_mut().unwrap().0;
+                match dir.entry_next() {
+                    Ok(Some(de)) => {
+                        let name = match de.name() {
+                            Ok(name) => name,
+                            Err(e) => return Some(Err(e)),
+                        };
+                        if name == "." || name == ".." {
+                            continue;
+                        }
+                        self.path.truncate(parent_len);
+                        self.path.push('/');
+                        self.path.push_str(name);
+                        true
+                    },
+                    Ok(None) => false,
+                    Err(e) => {
+                        // Stale: the filesystem is gone
+                        self.stack.clear();
+                        return Some(Err(e));
+                    },
+                }
+            };
+
+            if !got_entry {
+                self.stack.pop();
+                continue;
+            }
+
+            let name_start = parent_len + 1;
+
+            self.path.push('\0');
+            let st = unsafe { stat_cstr(self.path.as_ptr()) };
+            let without_nulterm = self.path.len() - 1;
+            self.path.truncate(without_nulterm);
+
+            let st = match st {
+                Ok(st) => st,
+                Err(e) => return Some(Err(e)),
+            };
+
+            let itype = st.inode_type();
+            let is_link = itype == InodeType::Symlink;
+
+            if is_link && self.symlinks == Symlinks::Skip {
+                continue;
+            }
+
+            if depth < self.max_depth {
+                let descend = if itype == InodeType::Dir {
+                    Some(false)
+                } else if is_link && self.symlinks == Symlinks::Follow {
+                    // Only descend if the link resolves to a directory
+                    match expand(&self.path).and_then(|p| stat(&p)) {
+                        Ok(ref st) if st.inode_type() == InodeType::Dir => {
+                            Some(true)
+                        },
+                        _ => None,
+                    }
+                } else {
+                    None
+                };
+
+                if let Some(follow) = descend {
+                    if let Err(e) = self.push_dir(follow) {
+                        self.error = Some(e);
+                    }
+                }
+            }
+
+            return Some(Ok(WalkEntry {
+                path: self.path.clone(),
+                name_start: name_start,
+                depth: depth,
+                stat: st,
+            }));
+        }
+    }
+}
+
+impl WalkEntry {
+    /// Full path of the entry
+    pub fn path(&self) -> &str
+    {
+        &self.path
+    }
+
+    /// Name of the entry, without its parent path
+    pub fn name(&self) -> &str
+    {
+        &self.path[self.name_start ..]
+    }
+
+    /// Depth of the entry below the starting directory, starting at 1
+    pub fn depth(&self) -> usize
+    {
+        self.depth
+    }
 
-pub enum Origin {
-    Set,
-    Current,
-    End,
+    pub fn stat(&self) -> &Stat
+    {
+        &self.stat
+    }
 }
 
+/// Open file, with the mount generation it was opened under.
+#[repr(C)]
+pub struct File(lwext4::ext4_file, usize);
+
 impl File {
     /// Truncate file to the specified length.
     pub fn truncate(&mut self, size: usize) -> StdResult
     {
+        let _fs = lock_fs();
+        check_gen(self.1)?;
+        check_writable()?;
+        mark_dirty(0);
         to_stdresult(
             unsafe { lwext4::ext4_ftruncate(&mut self.0, size as u64) },
         )
     }
 
+    /// Get file position
+    pub fn tell(&mut self) -> Result<usize, Error>
+    {
+        let _fs = lock_fs();
+        check_gen(self.1)?;
+        Ok((unsafe { lwext4::ext4_ftell(&mut self.0) }) as usize)
+    }
+
+    /// Get file size
+    pub fn size(&mut self) -> Result<usize, Error>
+    {
+        let _fs = lock_fs();
+        check_gen(self.1)?;
+        Ok((unsafe { lwext4::ext4_fsize(&mut self.0) }) as usize)
+    }
+}
+
+impl Read for File {
     /// Read data from file. Will attempt to fill `buf`; returns the number
     /// of bytes read.
-    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError>
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError>
     {
+        let _fs = lock_fs();
+        check_gen(self.1).map_err(|e| IoError::new(e, 0))?;
         let mut rcnt = 0usize;
         let buflen = buf.len();
 
@@ -523,58 +1909,69 @@ impl File {
             Err(e) => Err(IoError::new(e, rcnt)),
         }
     }
+}
 
+impl Write for File {
     /// Write data to file. Will attempt to write all of `buf`; returns the
     /// number of bytes written.
-    pub fn write(&mut self, buf: &[u8]) -> Result<usize, IoError>
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError>
     {
+        let _fs = lock_fs();
+        check_gen(self.1).map_err(|e| IoError::new(e, 0))?;
+        check_writable().map_err(|e| IoError::new(e, 0))?;
+
         let mut rcnt = 0usize;
         let buflen = buf.len();
 
-        match to_stdresult(unsafe {
+        let rc = to_stdresult(unsafe {
             lwext4::ext4_fwrite(
                 &mut self.0,
                 buf.as_ptr() as *mut ctypes::c_void,
                 buflen,
                 &mut rcnt,
             )
-        }) {
+        });
+        mark_dirty(rcnt);
 
+        match rc {
             Ok(..) => Ok(rcnt),
             Err(e) => Err(IoError::new(e, rcnt)),
         }
     }
+}
 
-    /// Seek to a position.
-    pub fn seek(&mut self, offset: usize, origin: Origin) -> StdResult
+impl Seek for File {
+    /// Seek to a position. Seeking past the end of the file is not supported.
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error>
     {
-        let c_origin = match origin {
-            Origin::Set => lwext4::SEEK_SET,
-            Origin::Current => lwext4::SEEK_CUR,
-            Origin::End => lwext4::SEEK_END,
+        let _fs = lock_fs();
+        check_gen(self.1)?;
+
+        // lwext4 takes an unsigned offset; for SEEK_END it is subtracted from
+        // the size, and for SEEK_CUR a wrapped negative value works out.
+        let (offset, c_origin) = match pos {
+            SeekFrom::Start(n) => (n, lwext4::SEEK_SET),
+            SeekFrom::Current(n) => (n as u64, lwext4::SEEK_CUR),
+            SeekFrom::End(n) if n <= 0 => ((-n) as u64, lwext4::SEEK_END),
+            SeekFrom::End(_) => return Err(ERR_EINVAL),
         };
 
         to_stdresult(unsafe {
-            lwext4::ext4_fseek(&mut self.0, offset as u64, c_origin)
-        })
-    }
-
-    /// Get file position
-    pub fn tell(&mut self) -> usize
-    {
-        (unsafe { lwext4::ext4_ftell(&mut self.0) }) as usize
-    }
+            lwext4::ext4_fseek(&mut self.0, offset, c_origin)
+        })?;
 
-    /// Get file size
-    pub fn size(&mut self) -> usize
-    {
-        (unsafe { lwext4::ext4_fsize(&mut self.0) }) as usize
+        Ok(unsafe { lwext4::ext4_ftell(&mut self.0) })
     }
 }
 
 impl ops::Drop for File {
     fn drop(&mut self)
     {
+        let _fs = lock_fs();
+        if check_gen(self.1).is_err() {
+            return;
+        }
+        OPEN_HANDLES.fetch_sub(1, Ordering::SeqCst);
         to_stdresult(unsafe { lwext4::ext4_fclose(&mut self.0) })
             .unwrap();
     }
@@ -597,47 +1994,98 @@ impl Stat {
     /// Get mode. Warning, HURD has 32-bit mode and we ignore upper bits.
     pub fn mode(&self) -> u16
     {
-        u16::from_le(self.0.mode)
+        u16::from_le(self.inode.mode)
+    }
+
+    /// Inode number.
+    pub fn ino(&self) -> u32
+    {
+        self.ino
     }
 
-    pub fn uid(&self) -> u16
+    pub fn uid(&self) -> u32
     {
-        u16::from_le(self.0.uid)
+        let high = unsafe { self.inode.osd2.linux2.uid_high };
+        u16::from_le(self.inode.uid) as u32
+            | (u16::from_le(high) as u32) << 16
     }
 
-    pub fn gid(&self) -> u16
+    pub fn gid(&self) -> u32
     {
-        u16::from_le(self.0.gid)
+        let high = unsafe { self.inode.osd2.linux2.gid_high };
+        u16::from_le(self.inode.gid) as u32
+            | (u16::from_le(high) as u32) << 16
+    }
+
+    pub fn atime(&self) -> Timespec
+    {
+        Timespec::from_raw(
+            u32::from_le(self.inode.access_time),
+            self.extra(16, self.inode.atime_extra),
+        )
+    }
+
+    pub fn ctime(&self) -> Timespec
+    {
+        Timespec::from_raw(
+            u32::from_le(self.inode.change_inode_time),
+            self.extra(8, self.inode.ctime_extra),
+        )
     }
 
-    pub fn atime(&self) -> u32
+    pub fn mtime(&self) -> Timespec
     {
-        u32::from_le(self.0.access_time)
+        Timespec::from_raw(
+            u32::from_le(self.inode.modification_time),
+            self.extra(12, self.inode.mtime_extra),
+        )
     }
 
-    pub fn ctime(&self) -> u32
+    /// Creation time. Only recorded in inodes with extra space, so this is
+    /// None on ext2/ext3 filesystems with 128-byte inodes.
+    pub fn crtime(&self) -> Option<Timespec>
     {
-        u32::from_le(self.0.change_inode_time)
+        self.extra(20, self.inode.crtime).map(|crtime| {
+            Timespec::from_raw(crtime, self.extra(24, self.inode.crtime_extra))
+        })
     }
 
-    pub fn mtime(&self) -> u32
+    /// Return a field from the extended inode area if the inode is large
+    /// enough to hold it. `end` is the offset just past the field, counted
+    /// from the end of the 128-byte base inode.
+    fn extra(&self, end: u16, field: u32) -> Option<u32>
     {
-        u32::from_le(self.0.modification_time)
+        if self.extra_isize >= end {
+            Some(u32::from_le(field))
+        } else {
+            None
+        }
     }
 
     pub fn linkcount(&self) -> u16
     {
-        u16::from_le(self.0.links_count)
+        u16::from_le(self.inode.links_count)
     }
 
     pub fn flags(&self) -> u32
     {
-        u32::from_le(self.0.flags)
+        u32::from_le(self.inode.flags)
+    }
+
+    pub fn size(&self) -> u64
+    {
+        u32::from_le(self.inode.size_lo) as u64
+            | (u32::from_le(self.inode.size_hi) as u64) << 32
     }
 
-    pub fn size(&self) -> u32
+    /// Number of 512-byte sectors allocated to the inode. Inodes flagged
+    /// HUGE_FILE count in filesystem blocks instead; those only occur for
+    /// files over 2 TiB, which we do not expect to see.
+    pub fn blocks(&self) -> u64
     {
-        u32::from_le(self.0.size_lo)
+        let high = unsafe { self.inode.osd2.linux2.blocks_high };
+        u32::from_le(self.inode.blocks_count_lo) as u64
+            | (u16::from_le(high) as u64) << 32
     }
 
     pub fn inode_type(&self) -> InodeType
@@ -739,6 +2187,10 @@ unsafe extern "C" fn blockdev_bread(
     blk_cnt: u32,
 ) -> i32
 {
+    if DETACHED.load(Ordering::SeqCst) {
+        return EIO;
+    }
+
     let bd = SdBlockDev::from_ptr(bdev);
     let mut sd = bd.sd.lock();
     match sd.read_blocks(
@@ -747,7 +2199,7 @@ unsafe extern "C" fn blockdev_bread(
         buf as *mut u8,
     ) {
         Ok(()) => 0,
-        Err(_) => EIO,
+        Err(e) => sd_errno(e),
     }
 }
 
@@ -758,6 +2210,10 @@ unsafe extern "C" fn blockdev_bwrite(
     blk_cnt: u32,
 ) -> i32
 {
+    if DETACHED.load(Ordering::SeqCst) {
+        return 0;
+    }
+
     let bd = SdBlockDev::from_ptr(bdev);
     let mut sd = bd.sd.lock();
     match sd.write_blocks(
@@ -766,7 +2222,24 @@ unsafe extern "C" fn blockdev_bwrite(
         buf as *const u8,
     ) {
         Ok(()) => 0,
-        Err(_) => EIO,
+        Err(e) => sd_errno(e),
+    }
+}
+
+/// Map an SD card error to the errno lwext4 passes back up. Anything not
+/// more specific is EIO.
+fn sd_errno(e: Error) -> i32
+{
+    if e == ERR_EROFS || e == ERR_SD_WRITE_PROT {
+        EROFS
+    } else if e == ERR_NO_CARD || e == ERR_SD_SLOT {
+        ENODEV
+    } else if e == ERR_SD_PARAM {
+        EINVAL
+    } else if e == ERR_TIMEOUT {
+        ETIMEDOUT
+    } else {
+        EIO
     }
 }
 
@@ -835,8 +2308,10 @@ fn to_stdresult(ec: i32) -> StdResult
         31 => Err(ERR_EMLINK),
         34 => Err(ERR_ERANGE),
         39 => Err(ERR_ENOTEMPTY),
+        40 => Err(ERR_ELOOP),
         61 => Err(ERR_ENODATA),
         95 => Err(ERR_ENOTSUP),
+        116 => Err(ERR_ETIMEDOUT),
         _ => Err(ERR_UNKNOWN),
     }
 }
@@ -23,10 +23,16 @@ use messages::*;
 use drivers::spi::Spi;
 use drivers::gpio::Gpio;
 use drivers::ext4;
+use data::io::Read;
 use alloc::vec;
 
+/// Extended attribute naming the FPGA a bitstream was built for. If a
+/// bitstream file has it, it must match the name of the FPGA being loaded.
+pub const XATTR_TARGET: &'static str = "user.fpga.target";
+
 /// Driver for programming a Spartan 6.
 pub struct Spartan6<'a> {
+    name: &'a str,
     mutex: &'a Mutex<()>,
     spi: &'a Spi,
     done_pin: &'a (Gpio + Sync),
@@ -39,6 +45,8 @@ impl<'a> Spartan6<'a> {
     ///
     /// # Arguments
     ///
+    /// * `name`     - Name of this FPGA, checked against the target
+    ///                attribute of bitstream files.
     /// * `mutex`    - Mutex to lock the programming interface. If any
     ///                hardware (SPI) is shared between FPGAs, they must
     ///                share a mutex.
@@ -55,6 +63,7 @@ impl<'a> Spartan6<'a> {
     ///                inverted to reflect the active-low hardware
     ///                interface.
     pub const fn new<'b>(
+        name: &'b str,
         mutex: &'b Mutex<()>,
         spi: &'b Spi,
         done_pin: &'b (Gpio + Sync),
@@ -63,6 +72,7 @@ impl<'a> Spartan6<'a> {
     ) -> Spartan6<'b>
     {
         Spartan6 {
+            name: name,
             mutex: mutex,
             spi: spi,
             done_pin: done_pin,
@@ -71,9 +81,17 @@ impl<'a> Spartan6<'a> {
         }
     }
 
+    /// Name of this FPGA.
+    pub fn name(&self) -> &str
+    {
+        self.name
+    }
+
     /// Initialize the FPGA with the given filename
     pub fn load(&self, filename: &str) -> StdResult
     {
+        self.check_target(filename)?;
+
         let _lock = self.mutex.lock();
 
         self.prog_pin.set(true);
@@ -88,18 +106,34 @@ impl<'a> Spartan6<'a> {
         Ok(())
     }
 
+    /// Refuse a bitstream tagged for another FPGA. Untagged files, and
+    /// filesystems without extended attributes, are accepted.
+    fn check_target(&self, filename: &str) -> StdResult
+    {
+        let path = ext4::expand(filename)?;
+
+        match ext4::getxattr_str(&path, XATTR_TARGET) {
+            Ok(ref target) if target.as_str() == self.name => Ok(()),
+            Ok(_) => Err(ERR_FPGA_TARGET),
+            Err(e) if e == ERR_ENODATA || e == ERR_ENOTSUP => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
     /// The actual file transfer without the pin twiddling
     fn actual_load(&self, filename: &str) -> StdResult
     {
         let mut file = ext4::fopen_expand(filename, ext4::OpenFlags::Read)?;
 
+        // Two buffers rather than a BufReader, so that one can be read into
+        // while the SPI DMA sends the other.
         let mut buf1 = vec::from_elem(0u8, 4096);
         let mut buf2 = vec::from_elem(0u8, 4096);
 
         let mut wr1 = None;
 
         loop {
-            let n_read1 = file.read(&mut buf1)?;
+            let n_read1 = file.read_full(&mut buf1)?;
 
             if let Some(wr) = wr1.take() {
                 self.spi.end_write(wr);
@@ -111,7 +145,7 @@ impl<'a> Spartan6<'a> {
 
             let wr2 = self.spi.start_write(&buf1[0 .. n_read1])?;
 
-            let n_read2 = file.read(&mut buf2)?;
+            let n_read2 = file.read_full(&mut buf2)?;
             self.spi.end_write(wr2);
 
             if n_read2 == 0 {
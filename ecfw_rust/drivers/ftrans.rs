@@ -17,39 +17,177 @@
 
 //! File transfer over debug interface.
 
-use lwext4_crc32;
-use ctypes;
-use data::{ParseInt, base64};
+use data::{Crc32, ParseInt, Sha256, base64, cobs, crc32};
+use data::io::{BufReader, Read, Seek, SeekFrom, Write};
 use drivers::ext4;
-use drivers::com::Com;
-use devices;
-use devices::COMCDC;
+use drivers::ftrans_proto::*;
+use drivers::com::{Com, ComStream};
+use core::cmp;
+use core::fmt::Write as FmtWrite;
 use core::iter::Iterator;
 use core::str;
 use alloc::string::String;
-use alloc::raw_vec::RawVec;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
 use messages::*;
+use os;
 
-pub struct FTrans {
+/// Commands understood, as reported by `hello`.
+const COMMANDS: &[&str] = &[
+    "hello", "open", "close", "sync", "read", "write", "truncate", "seekset",
+    "seekcur", "size", "list", "stat", "mkdir", "remove", "rename",
+    "chunksize", "begin", "wchunk", "rchunk", "end", "binary", "hash",
+    "upload", "commit",
+];
+
+/// Chunk size for windowed transfers until one is negotiated
+const DEFAULT_CHUNK: usize = 512;
+
+/// Largest window, however small the chunks
+const MAX_WINDOW: usize = 16;
+
+/// Time without input after which binary mode gives up on the client and
+/// returns to line mode, where ^C and ^D work, in milliseconds
+const BINARY_IDLE_MS: u32 = 60_000;
+
+/// Uploads are written to ".{name}{TEMP_SUFFIX}" next to the target, which
+/// commit renames to ".{name}{NEW_SUFFIX}" once verified and synced, and then
+/// over the target. See recover_uploads().
+const TEMP_SUFFIX: &str = ".ftrans-tmp";
+const NEW_SUFFIX: &str = ".ftrans-new";
+
+/// Directories that have had uploads since the last clean recovery, one per
+/// line, in a file at the root of the mount. Saves recover_uploads() walking
+/// the whole filesystem.
+const UPLOAD_LOG: &str = ".ftrans-uploads";
+
+pub struct FTrans<'c> {
+    /// Interface the session runs on
+    com: &'c Com,
     file: Option<ext4::File>,
+    chunk_size: usize,
+    window: Window,
+    /// Binary mode writes that start past the end of the file, by offset
+    held: Vec<(u64, Box<[u8]>)>,
+    /// Set while the open file is an upload's temporary
+    upload: Option<Upload>,
+}
+
+/// An upload in progress. The open file is `temp`, which replaces `target`
+/// on commit.
+struct Upload {
+    target: String,
+    temp: String,
+}
+
+/// State of a windowed transfer. Chunk `n` lives at `base + n * chunk_size`
+/// in the file. Write chunks are written strictly in order, because lwext4
+/// cannot seek past the end of a file; any that arrive early wait in
+/// `pending`.
+struct Window {
+    base: u64,
+    next: u32,
+    pending: Vec<(u32, Box<[u8]>)>,
+}
+
+/// Running hash for `hash` and OP_HASH
+enum Hasher {
+    Crc32(Crc32),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    fn from_name(name: &str) -> Result<Hasher, Error>
+    {
+        match name {
+            "crc32" => Ok(Hasher::Crc32(Crc32::new())),
+            "sha256" => Ok(Hasher::Sha256(Sha256::new())),
+            _ => Err(ERR_ENOTSUP),
+        }
+    }
+
+    fn from_code(code: u8) -> Result<Hasher, Error>
+    {
+        match code {
+            HASH_CRC32 => Ok(Hasher::Crc32(Crc32::new())),
+            HASH_SHA256 => Ok(Hasher::Sha256(Sha256::new())),
+            _ => Err(ERR_ENOTSUP),
+        }
+    }
+
+    fn update(&mut self, data: &[u8])
+    {
+        match *self {
+            Hasher::Crc32(ref mut h) => h.update(data),
+            Hasher::Sha256(ref mut h) => h.update(data),
+        }
+    }
+
+    /// Store the digest in `dest`, returning its length. A CRC32 is big
+    /// endian, as it is usually printed.
+    fn finish(&self, dest: &mut [u8; 32]) -> usize
+    {
+        match *self {
+            Hasher::Crc32(ref h) => {
+                let crc = h.finish();
+                for i in 0 .. 4 {
+                    dest[i] = (crc >> (24 - 8 * i)) as u8;
+                }
+                4
+            },
+            Hasher::Sha256(ref h) => {
+                *dest = h.finish();
+                32
+            },
+        }
+    }
 }
 
-impl FTrans {
-    pub fn new() -> FTrans
+impl<'c> FTrans<'c> {
+    /// Create a session on `com`. Debug output on the same interface (the
+    /// USART) will be mixed in with replies. Each line protocol reply is
+    /// written in one call under the output lock that debug messages also
+    /// take, so they only come between lines, and binary frames are
+    /// checksummed, so clients can skip it.
+    pub fn new(com: &'c Com) -> FTrans<'c>
     {
-        FTrans { file: None }
+        FTrans {
+            com: com,
+            file: None,
+            chunk_size: DEFAULT_CHUNK,
+            window: Window {
+                base: 0,
+                next: 0,
+                pending: Vec::new(),
+            },
+            held: Vec::new(),
+            upload: None,
+        }
+    }
+
+    /// Number of chunks a client may have in flight.
+    fn window_size(&self) -> u32
+    {
+        let chunks = WINDOW_BYTES / self.chunk_size;
+
+        if chunks > MAX_WINDOW {
+            MAX_WINDOW as u32
+        } else {
+            chunks as u32
+        }
     }
 
     /// Open a file transfer session. Quits when either ^C or ^D is
     /// received.
     pub fn run(&mut self)
     {
-        devices::COMCDC.flush_output();
+        self.com.flush_output();
         let mut s = String::with_capacity(8192);
         let mut invalid = false;
 
         loop {
-            let c = devices::COMCDC.getc_blocking(true);
+            let c = self.com.getc_blocking(true);
 
             match c {
                 3 | 4 => {
@@ -123,7 +261,7 @@ impl FTrans {
             // Set position relative to zero
             Some("seekset") => {
                 self.data_cmd(&mut iter, |s, i| {
-                    FTrans::seek_wrapped(s, i, ext4::Origin::Set)
+                    FTrans::seek_wrapped(s, i, false)
                 })
             },
 
@@ -131,10 +269,142 @@ impl FTrans {
             // Set position relative to current point
             Some("seekcur") => {
                 self.data_cmd(&mut iter, |s, i| {
-                    FTrans::seek_wrapped(s, i, ext4::Origin::Current)
+                    FTrans::seek_wrapped(s, i, true)
                 })
             },
 
+            // size
+            // Get the size of the currently open file.
+            // Returns as:
+            //  ack {size as u64 LE, base64} {crc32}
+            Some("size") => self.do_size(&mut iter),
+
+            // hello
+            // version
+            // Identify the protocol, so clients can tell what is supported.
+            // Returns as:
+            //  ack {protocol version} {command} {command}...
+            Some("hello") | Some("version") => self.do_hello(&mut iter),
+
+            // list {base64 path} {crc32}
+            // List a directory, except for . and ..
+            // Returns one line per entry, then ack:
+            //  entry {base64 stat record, see below} {crc32}
+            //  ack
+            Some("list") => self.data_cmd(&mut iter, FTrans::list_wrapped),
+
+            // stat {base64 path} {crc32}
+            // Returns as:
+            //  ack {base64 stat record} {crc32}
+            // A stat record is text: "{type} {size} {mtime} {mode} {name}",
+            // where type is one of fdlpcbs? (file, directory, link, ...),
+            // mtime is in seconds since the epoch, mode is the permission
+            // bits in octal, and name is empty for stat.
+            Some("stat") => self.data_cmd(&mut iter, FTrans::stat_wrapped),
+
+            // mkdir {base64 path} {crc32}
+            // Create a directory. Fails if the path exists.
+            Some("mkdir") => self.data_cmd(&mut iter, FTrans::mkdir_wrapped),
+
+            // remove {base64 path} {crc32}
+            // Delete a file, or a directory if it is empty.
+            Some("remove") => {
+                self.data_cmd(&mut iter, FTrans::remove_wrapped)
+            },
+
+            // rename {base64 old path, NUL, new path} {crc32}
+            // Rename a file or directory. Fails if the new path exists.
+            Some("rename") => {
+                self.data_cmd(&mut iter, FTrans::rename_wrapped)
+            },
+
+            // upload {base64 path} {crc32}
+            // Like open, but for replacing path safely: the data goes to a
+            // temporary file next to it, and only commit puts it in place.
+            // A temporary left by an interrupted upload of the same path is
+            // reopened as it is, so the upload can be resumed. close
+            // abandons the upload; temporaries still around at the next
+            // mount are removed.
+            Some("upload") => {
+                self.data_cmd(&mut iter, FTrans::upload_wrapped)
+            },
+
+            // commit {base64 SHA-256 of the whole file} {crc32}
+            // Check the upload against the hash, then sync it and rename it
+            // over its path. The file is closed afterwards.
+            // Returns as:
+            //  ack
+            //  error upload does not match   (the upload is still open)
+            Some("commit") => {
+                self.data_cmd(&mut iter, FTrans::commit_wrapped)
+            },
+
+            // hash {algorithm} [{offset} [{length}]]
+            // Hash the open file, or up to length bytes of it from offset,
+            // so that clients can check a transfer end to end or find where
+            // an interrupted one got to. algorithm is crc32 or sha256.
+            // Returns as:
+            //  ack {hex digest} {bytes hashed}
+            Some("hash") => self.do_hash(&mut iter),
+
+            // Windowed transfers
+            //
+            // The commands above wait for each response before the next
+            // command; these let the client keep up to a window of
+            // sequence-numbered chunks in flight, resending only those that
+            // were refused.
+
+            // chunksize {bytes}
+            // Set the chunk size for windowed transfers. The firmware may
+            // reduce it; the size actually used is returned. Chunk offsets
+            // depend on the size, so it can only be changed before the first
+            // chunk of a window (after open or begin); later it is refused
+            // with ERR_BUSY.
+            // Returns as:
+            //  ack {bytes}
+            Some("chunksize") => self.do_chunksize(&mut iter),
+
+            // begin
+            // Start a windowed transfer at the current position; this is
+            // chunk 0. A client may send up to {window} chunks beyond the
+            // first one not yet acked.
+            // Returns as:
+            //  ack {window}
+            Some("begin") => self.do_begin(&mut iter),
+
+            // wchunk {seq} {base64 data} {crc32}
+            // Write chunk seq. Only the last chunk may be short.
+            // Returns as:
+            //  ack {seq}
+            //  nack {seq}          (bad data or outside window: resend it)
+            //  error {message}     (file error: the transfer is over)
+            Some("wchunk") => self.do_wchunk(&mut iter),
+
+            // rchunk {seq}
+            // Read chunk seq. Short at the end of the file, empty after it.
+            // Returns as:
+            //  data {seq} {base64 data} {crc32}
+            Some("rchunk") => self.do_rchunk(&mut iter),
+
+            // end
+            // Finish a windowed write.
+            // Returns as:
+            //  ack {chunks written}
+            //  nack {seq}          (first chunk still missing)
+            Some("end") => self.do_end(&mut iter),
+
+            // binary
+            // Switch to binary mode (see run_binary()) until an OP_EXIT
+            // frame is received, or nothing is for BINARY_IDLE_MS.
+            // Returns as:
+            //  ack
+            Some("binary") => {
+                print_to!(self.com, "ack\n");
+                self.com.flush_output();
+                self.run_binary();
+                Ok(())
+            },
+
             _ => {
                 self.handle_invalid();
                 Ok(())
@@ -142,43 +412,314 @@ impl FTrans {
         }
     }
 
-    fn data_cmd<'a, I, F>(&mut self, iter: &'a mut I, f: F) -> StdResult
+    /// Decode a `{base64 data} {crc32}` pair and check the CRC.
+    fn decode_data<'a, I>(iter: &'a mut I) -> Result<Box<[u8]>, Error>
     where
         I: Iterator<Item = &'a str>,
-        F: Fn(&mut Self, &[u8]) -> StdResult,
     {
-        let mut decode_buf = unsafe { RawVec::with_capacity(4096).into_box() };
-
         let data_b64 = iter.next().ok_or(ERR_EXPECTED_ARGS)?;
+        let mut decode_buf = vec::from_elem(0u8, data_b64.len() / 4 * 3 + 3);
         let n_bytes = base64::decode(&mut decode_buf, data_b64.as_bytes())?;
+        decode_buf.truncate(n_bytes);
 
         let rx_crc32_str = iter.next().ok_or(ERR_EXPECTED_ARGS)?;
         let rx_crc32 = u32::parseint(rx_crc32_str)?;
 
-        let actual_crc32 = crc32(&decode_buf[0 .. n_bytes]);
-
-        if actual_crc32 != rx_crc32 {
+        if crc32(&decode_buf) != rx_crc32 {
             Err(ERR_CKSUM)
         } else {
-            f(self, &decode_buf[0 .. n_bytes])
+            Ok(decode_buf.into_boxed_slice())
+        }
+    }
+
+    fn data_cmd<'a, I, F>(&mut self, iter: &'a mut I, f: F) -> StdResult
+    where
+        I: Iterator<Item = &'a str>,
+        F: Fn(&mut Self, &[u8]) -> StdResult,
+    {
+        let data = FTrans::decode_data(iter)?;
+        f(self, &data)
+    }
+
+    fn do_chunksize<'a, I>(&mut self, iter: &'a mut I) -> StdResult
+    where
+        I: Iterator<Item = &'a str>,
+    {
+        let requested = iter.next().ok_or(ERR_EXPECTED_ARGS)?;
+        let requested = u32::parseint(requested)? as usize;
+
+        if requested == 0 {
+            return Err(ERR_ARG_RANGE);
+        }
+        if self.window.next > 0 || !self.window.pending.is_empty() {
+            return Err(ERR_BUSY);
+        }
+
+        self.chunk_size = if requested > MAX_CHUNK {
+            MAX_CHUNK
+        } else {
+            requested
+        };
+
+        print_to!(self.com, "ack {}\n", self.chunk_size);
+        Ok(())
+    }
+
+    fn do_begin<'a, I>(&mut self, _iter: &'a mut I) -> StdResult
+    where
+        I: Iterator<Item = &'a str>,
+    {
+        let base = match self.file {
+            Some(ref mut file) => file.tell()? as u64,
+            None => return Err(ERR_FILE_NOT_OPEN),
+        };
+
+        self.window = Window {
+            base: base,
+            next: 0,
+            pending: Vec::new(),
+        };
+
+        print_to!(self.com, "ack {}\n", self.window_size());
+        Ok(())
+    }
+
+    fn do_wchunk<'a, I>(&mut self, iter: &'a mut I) -> StdResult
+    where
+        I: Iterator<Item = &'a str>,
+    {
+        let seq = iter.next().ok_or(ERR_EXPECTED_ARGS)?;
+        let seq = u32::parseint(seq)?;
+
+        if self.file.is_none() {
+            return Err(ERR_FILE_NOT_OPEN);
+        }
+
+        let data = match FTrans::decode_data(iter) {
+            Ok(ref data) if data.len() > self.chunk_size => None,
+            Ok(data) => Some(data),
+            Err(_) => None,
+        };
+        let in_window = seq < self.window.next + self.window_size();
+
+        let data = match data {
+            Some(data) if in_window => data,
+            _ => {
+                print_to!(self.com, "nack {}\n", seq);
+                return Ok(());
+            },
+        };
+
+        if seq == self.window.next {
+            self.write_chunk(seq, &data)?;
+
+            // Catch up on anything that was waiting for this one
+            loop {
+                let next = self.window.next;
+                let waiting =
+                    self.window.pending.iter().position(|&(n, _)| n == next);
+                match waiting {
+                    Some(i) => {
+                        let (n, data) = self.window.pending.swap_remove(i);
+                        self.write_chunk(n, &data)?;
+                    },
+                    None => break,
+                }
+            }
+        } else if seq > self.window.next
+            && !self.window.pending.iter().any(|&(n, _)| n == seq)
+        {
+            self.window.pending.push((seq, data));
+        }
+        // else: already written, the ack was lost
+
+        print_to!(self.com, "ack {}\n", seq);
+        Ok(())
+    }
+
+    /// Write chunk `seq`, which must be the next one.
+    fn write_chunk(&mut self, seq: u32, data: &[u8]) -> StdResult
+    {
+        let pos = self.window.base + seq as u64 * self.chunk_size as u64;
+
+        match self.file {
+            Some(ref mut file) => {
+                file.seek(SeekFrom::Start(pos))?;
+                file.write_all(data)?;
+            },
+            None => return Err(ERR_FILE_NOT_OPEN),
+        }
+
+        self.window.next = seq + 1;
+        Ok(())
+    }
+
+    fn do_rchunk<'a, I>(&mut self, iter: &'a mut I) -> StdResult
+    where
+        I: Iterator<Item = &'a str>,
+    {
+        let seq = iter.next().ok_or(ERR_EXPECTED_ARGS)?;
+        let seq = u32::parseint(seq)?;
+        let pos = self.window.base + seq as u64 * self.chunk_size as u64;
+        let mut buf = vec::from_elem(0u8, self.chunk_size);
+        let len = self.read_at(pos, &mut buf)?;
+
+        let mut tag = String::new();
+        let _ = write!(tag, "data {}", seq);
+        self.send_data(&tag, &buf[.. len]);
+        Ok(())
+    }
+
+    /// Read from an offset until `buf` is full or the file ends. Offsets
+    /// past the end read nothing.
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> Result<usize, Error>
+    {
+        let file = match self.file {
+            Some(ref mut file) => file,
+            None => return Err(ERR_FILE_NOT_OPEN),
+        };
+
+        if pos < file.size()? as u64 {
+            file.seek(SeekFrom::Start(pos))?;
+            Ok(file.read_full(buf)?)
+        } else {
+            Ok(0)
         }
     }
 
+    fn do_end<'a, I>(&mut self, _iter: &'a mut I) -> StdResult
+    where
+        I: Iterator<Item = &'a str>,
+    {
+        if self.window.pending.is_empty() {
+            print_to!(self.com, "ack {}\n", self.window.next);
+        } else {
+            print_to!(self.com, "nack {}\n", self.window.next);
+        }
+        Ok(())
+    }
+
+    /// Send a response line carrying data: `{tag} {base64 data} {crc32}`
+    fn send_data(&self, tag: &str, data: &[u8])
+    {
+        let mut b64_buf = vec::from_elem(0u8, (data.len() + 2) / 3 * 4);
+        let b64_converted = base64::encode(&mut b64_buf, data).unwrap();
+        let crc = crc32(data);
+
+        // base64 is ASCII
+        let b64 = str::from_utf8(&b64_buf[.. b64_converted]).unwrap();
+        print_to!(self.com, "{} {} {}\n", tag, b64, crc);
+    }
+
     fn open_wrapped(&mut self, filename: &[u8]) -> StdResult
     {
         let strslice = str::from_utf8(filename).unwrap();
-        self.file = Some(ext4::fopen(strslice, ext4::OpenFlags::ReadAppend)?);
 
-        print_to_async!(&COMCDC, "ack\n");
+        self.open_file(strslice)?;
+        print_to!(self.com, "ack\n");
+        Ok(())
+    }
+
+    fn upload_wrapped(&mut self, path: &[u8]) -> StdResult
+    {
+        self.start_upload(bytes_to_str(path)?)?;
+        print_to!(self.com, "ack\n");
+        Ok(())
+    }
+
+    fn commit_wrapped(&mut self, digest: &[u8]) -> StdResult
+    {
+        self.commit(digest)?;
+        print_to!(self.com, "ack\n");
         Ok(())
     }
 
+    fn open_file(&mut self, path: &str) -> StdResult
+    {
+        // Files on a read-only mount can still be downloaded; writes to them
+        // fail with ERR_EROFS.
+        let flags = if ext4::read_only("/")? {
+            ext4::OpenFlags::Read
+        } else {
+            ext4::OpenFlags::ReadAppend
+        };
+        // Abandons an upload in progress, removing its temporary
+        self.close_file()?;
+        self.file = Some(ext4::fopen(path, flags)?);
+        self.window = Window {
+            base: 0,
+            next: 0,
+            pending: Vec::new(),
+        };
+        Ok(())
+    }
+
+    /// Open the temporary for an upload to `target`.
+    fn start_upload(&mut self, target: &str) -> StdResult
+    {
+        if ext4::read_only("/")? {
+            return Err(ERR_EROFS);
+        }
+        // Otherwise this would only fail at commit
+        if let Ok(st) = ext4::stat(target) {
+            if st.inode_type() == ext4::InodeType::Dir {
+                return Err(ERR_EISDIR);
+            }
+        }
+
+        log_upload_dir(parent_dir(target))?;
+        let temp = sibling_path(target, TEMP_SUFFIX);
+        self.open_file(&temp)?;
+        self.upload = Some(Upload {
+            target: String::from(target),
+            temp: temp,
+        });
+        Ok(())
+    }
+
+    /// Check the upload against its SHA-256 and put it in place.
+    fn commit(&mut self, digest: &[u8]) -> StdResult
+    {
+        if self.upload.is_none() {
+            return Err(ERR_NO_UPLOAD);
+        }
+        if digest.len() != 32 {
+            return Err(ERR_ARG_RANGE);
+        }
+
+        let mut hasher = Hasher::Sha256(Sha256::new());
+        self.hash_at(&mut hasher, 0, !0)?;
+        let mut actual = [0u8; 32];
+        hasher.finish(&mut actual);
+        if actual[..] != digest[..] {
+            return Err(ERR_UPLOAD_MISMATCH);
+        }
+
+        let upload = self.upload.take().unwrap();
+        self.file = None;
+        self.held.clear();
+        finish_upload(&upload.temp, &upload.target)
+    }
+
+    /// Close the open file, if any, returning whether there was one. An
+    /// upload that wasn't committed is abandoned.
+    fn close_file(&mut self) -> Result<bool, Error>
+    {
+        let was_open = self.file.take().is_some();
+        self.held.clear();
+
+        if let Some(upload) = self.upload.take() {
+            ext4::unlink(&upload.temp)?;
+        }
+        Ok(was_open)
+    }
+
     fn write_wrapped(&mut self, data: &[u8]) -> StdResult
     {
         match self.file {
             Some(ref mut file) => {
-                file.write(data)?;
-                print_to_async!(&COMCDC, "ack\n");
+                file.write_all(data)?;
+                print_to!(self.com, "ack\n");
                 Ok(())
             },
             None => Err(ERR_FILE_NOT_OPEN),
@@ -189,14 +730,10 @@ impl FTrans {
     where
         I: Iterator<Item = &'a str>,
     {
-        let was_open = self.file.is_some();
-
-        self.file = None;
-
-        if was_open {
-            print_to_async!(&COMCDC, "ack\n");
+        if self.close_file()? {
+            print_to!(self.com, "ack\n");
         } else {
-            print_to_async!(&COMCDC, "warn was_not_open\n");
+            print_to!(self.com, "warn was_not_open\n");
         }
 
         Ok(())
@@ -209,7 +746,7 @@ impl FTrans {
         if let Err(e) = ext4::sync("/") {
             Err(e)
         } else {
-            print_to_async!(&COMCDC, "ack\n");
+            print_to!(self.com, "ack\n");
             Ok(())
         }
     }
@@ -219,26 +756,149 @@ impl FTrans {
         I: Iterator<Item = &'a str>,
     {
         let mut file_buf = [0u8; 512];
-        let mut b64_buf = [0u8; 700];
 
-        match self.file {
-            Some(ref mut file) => {
-                let bytes_read = file.read(&mut file_buf)?;
-                let b64_converted =
-                    base64::encode(&mut b64_buf, &file_buf[0 .. bytes_read])
-                        .unwrap();
-                let crc = crc32(&file_buf[0 .. bytes_read]);
-
-                print_to_async!(&COMCDC, "ack ");
-                for i in &b64_buf[0 .. b64_converted] {
-                    print_to_async!(&COMCDC, "{}", *i as char);
-                }
-                print_to_async!(&COMCDC, " {}\n", crc);
+        let bytes_read = match self.file {
+            Some(ref mut file) => file.read_full(&mut file_buf)?,
+            None => return Err(ERR_FILE_NOT_OPEN),
+        };
 
-                Ok(())
-            },
-            None => Err(ERR_FILE_NOT_OPEN),
+        self.send_data("ack", &file_buf[0 .. bytes_read]);
+        Ok(())
+    }
+
+    fn do_size<'a, I>(&mut self, _iter: &'a mut I) -> StdResult
+    where
+        I: Iterator<Item = &'a str>,
+    {
+        let size = match self.file {
+            Some(ref mut file) => file.size()? as u64,
+            None => return Err(ERR_FILE_NOT_OPEN),
+        };
+
+        let mut encoded = [0u8; 8];
+        put_le(&mut encoded, size);
+
+        self.send_data("ack", &encoded);
+        Ok(())
+    }
+
+    fn do_hash<'a, I>(&mut self, iter: &'a mut I) -> StdResult
+    where
+        I: Iterator<Item = &'a str>,
+    {
+        let alg = iter.next().ok_or(ERR_EXPECTED_ARGS)?;
+        let mut hasher = Hasher::from_name(alg)?;
+        let pos = match iter.next() {
+            Some(s) => u64::parseint(s)?,
+            None => 0,
+        };
+        let len = match iter.next() {
+            Some(s) => u64::parseint(s)?,
+            // To the end
+            None => !0,
+        };
+
+        let hashed = self.hash_at(&mut hasher, pos, len)?;
+        let mut digest = [0u8; 32];
+        let digest_len = hasher.finish(&mut digest);
+
+        let mut line = String::from("ack ");
+        for b in &digest[.. digest_len] {
+            let _ = write!(line, "{:02x}", b);
         }
+        let _ = write!(line, " {}\n", hashed);
+        print_to!(self.com, "{}", line);
+        Ok(())
+    }
+
+    /// Feed up to `len` bytes of the open file from `pos` into `hasher`,
+    /// returning how many there were.
+    fn hash_at(
+        &mut self,
+        hasher: &mut Hasher,
+        pos: u64,
+        len: u64,
+    ) -> Result<u64, Error>
+    {
+        let mut buf = vec::from_elem(0u8, 4096);
+        let mut hashed = 0u64;
+
+        while hashed < len {
+            let want = cmp::min(len - hashed, buf.len() as u64) as usize;
+            let n = self.read_at(pos + hashed, &mut buf[.. want])?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[.. n]);
+            hashed += n as u64;
+        }
+
+        Ok(hashed)
+    }
+
+    fn do_hello<'a, I>(&mut self, _iter: &'a mut I) -> StdResult
+    where
+        I: Iterator<Item = &'a str>,
+    {
+        let mut line = String::new();
+        let _ = write!(line, "ack {}", VERSION);
+        for cmd in COMMANDS {
+            line.push(' ');
+            line.push_str(cmd);
+        }
+        line.push('\n');
+        print_to!(self.com, "{}", line);
+        Ok(())
+    }
+
+    fn list_wrapped(&mut self, path: &[u8]) -> StdResult
+    {
+        let walk = ext4::walk(bytes_to_str(path)?)?.max_depth(1);
+        let mut record = String::new();
+
+        for entry in walk {
+            let entry = entry?;
+
+            record.truncate(0);
+            write_stat_record(&mut record, entry.stat(), entry.name());
+            self.send_data("entry", record.as_bytes());
+        }
+
+        print_to!(self.com, "ack\n");
+        Ok(())
+    }
+
+    fn stat_wrapped(&mut self, path: &[u8]) -> StdResult
+    {
+        let st = ext4::stat(bytes_to_str(path)?)?;
+        let mut record = String::new();
+
+        write_stat_record(&mut record, &st, "");
+        self.send_data("ack", record.as_bytes());
+        Ok(())
+    }
+
+    fn mkdir_wrapped(&mut self, path: &[u8]) -> StdResult
+    {
+        ext4::mkdir(bytes_to_str(path)?)?;
+        print_to!(self.com, "ack\n");
+        Ok(())
+    }
+
+    fn remove_wrapped(&mut self, path: &[u8]) -> StdResult
+    {
+        remove_path(bytes_to_str(path)?)?;
+        print_to!(self.com, "ack\n");
+        Ok(())
+    }
+
+    fn rename_wrapped(&mut self, paths: &[u8]) -> StdResult
+    {
+        let (old_path, new_path) = split_paths(paths)?;
+
+        ext4::rename(old_path, new_path)?;
+        print_to!(self.com, "ack\n");
+        Ok(())
     }
 
     fn truncate_wrapped(&mut self, sz_encoded: &[u8]) -> StdResult
@@ -248,7 +908,7 @@ impl FTrans {
         match self.file {
             Some(ref mut file) => {
                 file.truncate(sz as usize)?;
-                print_to_async!(&COMCDC, "ack\n");
+                print_to!(self.com, "ack\n");
                 Ok(())
             },
             None => Err(ERR_FILE_NOT_OPEN),
@@ -258,15 +918,20 @@ impl FTrans {
     fn seek_wrapped(
         &mut self,
         pos_encoded: &[u8],
-        origin: ext4::Origin,
+        relative: bool,
     ) -> StdResult
     {
         let pos = bytes_to_u32(pos_encoded)?;
+        let pos = if relative {
+            SeekFrom::Current(pos as i64)
+        } else {
+            SeekFrom::Start(pos as u64)
+        };
 
         match self.file {
             Some(ref mut file) => {
-                file.seek(pos as usize, origin)?;
-                print_to_async!(&COMCDC, "ack\n");
+                file.seek(pos)?;
+                print_to!(self.com, "ack\n");
                 Ok(())
             },
             None => Err(ERR_FILE_NOT_OPEN),
@@ -275,25 +940,700 @@ impl FTrans {
 
     fn handle_invalid(&self)
     {
-        print_to_async!(&COMCDC, "error invalid_byte_or_command\n");
+        print_to!(self.com, "error invalid_byte_or_command\n");
     }
 
     fn handle_error(&self, e: Error)
     {
-        print_to_async!(&COMCDC, "error {}\n", e);
+        print_to!(self.com, "error {}\n", e);
     }
 }
 
-fn crc32(data: &[u8]) -> u32
+// Binary mode
+//
+// Frames are COBS-encoded and separated by zero bytes; replies also start
+// with one, so that stray output (debug messages on the USART) is cut off
+// from them. Clients skip whatever won't decode. Decoded, a frame is:
+//
+//   offset  size  field
+//   0       1     opcode
+//   1       4     sequence number, chosen by the client and echoed back
+//   5       4     payload length n
+//   9       n     payload
+//   9 + n   4     CRC32 of everything before it
+//
+// Integers are little endian. Requests may be pipelined. A successful reply
+// has the request's opcode with OP_REPLY set; a failed one has OP_ERROR, a
+// u16 error code from the ERRC_* table in ftrans_proto, and the error
+// message. Damaged frames are answered with ERRC_CHECKSUM or ERRC_FRAME and
+// whatever sequence number could be read, else 0.
+//
+// Before version 7 the error reply was only a u16 index into ERROR_TABLE,
+// which changed whenever messages.rs did, and OP_ERRMSG had to be asked for
+// the text.
+//
+// Request        Payload                Reply payload
+// OP_HELLO       -                      version u32, max data length u32
+// OP_OPEN        path                   -
+// OP_CLOSE       -                      -
+// OP_SYNC        -                      -
+// OP_READ        offset u64, length u32 data, short at end of file
+// OP_WRITE       offset u64, data       -
+// OP_TRUNCATE    size u64               -
+// OP_SIZE        -                      size u64
+// OP_STAT        path                   stat record
+// OP_LIST        path                   one reply per entry: stat record and
+//                                       name; then an empty reply
+// OP_MKDIR       path                   -
+// OP_REMOVE      path                   -
+// OP_RENAME      old path, NUL, new     -
+// OP_ERRMSG      code u16               message text for the code
+// OP_EXIT        -                      -, then back to line mode
+// OP_HASH        algorithm u8, offset   bytes hashed u64, digest
+//                u64, length u64
+// OP_UPLOAD      path                   -
+// OP_COMMIT      SHA-256 of the file    -
+//
+// A stat record is type u8 (as in line mode), mode u16, size u64 and mtime
+// i64.
+//
+// OP_UPLOAD and OP_COMMIT work as the `upload` and `commit` commands do.
+// OP_HASH hashes up to length bytes of the open file from offset, as the
+// `hash` command does. The algorithm is HASH_CRC32 (4 byte digest, big
+// endian) or HASH_SHA256 (32 bytes).
+//
+// Writes may arrive out of order. One that starts past the end of the file
+// is held, up to WINDOW_BYTES in total, until the gap has been written; so
+// check OP_SIZE after the last write.
+//
+// If nothing is received for BINARY_IDLE_MS, the client is taken to be gone
+// and the session drops back to line mode. Clients that pause for longer
+// than that between requests should send OP_HELLO now and then.
+
+// The opcodes, error codes and other constants used here are in
+// ftrans_proto, which the host client shares.
+
+static ERROR_CODES: &[(u16, &Error)] = &[
+    (ERRC_CHECKSUM, &ERR_CKSUM),
+    (ERRC_FRAME, &ERR_FRAME),
+    (ERRC_NOT_SUPPORTED, &ERR_ENOTSUP),
+    (ERRC_ARG_RANGE, &ERR_ARG_RANGE),
+    (ERRC_UTF8, &ERR_UTF8),
+    (ERRC_EXPECTED_ARGS, &ERR_EXPECTED_ARGS),
+    (ERRC_FILE_NOT_OPEN, &ERR_FILE_NOT_OPEN),
+    (ERRC_NOT_FOUND, &ERR_ENOENT),
+    (ERRC_EXISTS, &ERR_EEXIST),
+    (ERRC_NOT_DIR, &ERR_ENOTDIR),
+    (ERRC_IS_DIR, &ERR_EISDIR),
+    (ERRC_NOT_EMPTY, &ERR_ENOTEMPTY),
+    (ERRC_READ_ONLY, &ERR_EROFS),
+    (ERRC_NO_SPACE, &ERR_ENOSPC),
+    (ERRC_IO, &ERR_EIO),
+    (ERRC_BUSY, &ERR_BUSY),
+    (ERRC_NO_UPLOAD, &ERR_NO_UPLOAD),
+    (ERRC_UPLOAD_MISMATCH, &ERR_UPLOAD_MISMATCH),
+];
+
+/// Protocol error code for `e`.
+fn error_code(e: Error) -> u16
 {
-    let len = data.len();
-    !unsafe {
-        lwext4_crc32::ext4_crc32(
-            0xffffffff,
-            data as *const _ as *const ctypes::c_void,
-            len as u32,
-        )
+    match ERROR_CODES.iter().find(|&&(_, x)| *x == e) {
+        Some(&(code, _)) => code,
+        None => ERRC_OTHER,
+    }
+}
+
+/// Largest payload accepted: a write of MAX_CHUNK bytes and its offset
+const MAX_PAYLOAD: usize = MAX_CHUNK + 8;
+
+impl<'c> FTrans<'c> {
+    /// Run binary mode until OP_EXIT or BINARY_IDLE_MS without input.
+    fn run_binary(&mut self)
+    {
+        let max_frame =
+            cobs::max_encoded_len(HEADER_LEN + MAX_PAYLOAD + CRC_LEN);
+        let mut frame: Vec<u8> = Vec::with_capacity(max_frame);
+        let mut overflow = false;
+        let mut rx = [0u8; 64];
+
+        loop {
+            let n = self.read_idle(&mut rx);
+            if n == 0 {
+                debug!(DEBUG_FS, "ftrans: binary mode idle, back to line mode");
+                return;
+            }
+
+            for &c in &rx[.. n] {
+                if c != 0 {
+                    if frame.len() < max_frame {
+                        frame.push(c);
+                    } else {
+                        overflow = true;
+                    }
+                    continue;
+                }
+
+                let keep_going = if overflow {
+                    self.send_error(0, ERR_FRAME);
+                    true
+                } else if frame.is_empty() {
+                    true
+                } else {
+                    self.binary_frame(&frame)
+                };
+
+                frame.clear();
+                overflow = false;
+
+                if !keep_going {
+                    return;
+                }
+            }
+        }
     }
+
+    /// Read what has arrived into `buf`, waiting up to BINARY_IDLE_MS for
+    /// the first byte. Returns 0 if nothing came.
+    fn read_idle(&self, buf: &mut [u8]) -> usize
+    {
+        let start = os::ticks();
+        let mut n = 0;
+
+        while n < buf.len() {
+            match self.com.getc() {
+                Some(c) => {
+                    buf[n] = c;
+                    n += 1;
+                },
+                None if n > 0 => break,
+                None if os::ticks().wrapping_sub(start) >= BINARY_IDLE_MS => {
+                    break
+                },
+                None => os::yield_task(),
+            }
+        }
+        n
+    }
+
+    /// Handle one received frame, still COBS-encoded. Returns false on
+    /// OP_EXIT.
+    fn binary_frame(&mut self, raw: &[u8]) -> bool
+    {
+        let mut buf = vec::from_elem(0u8, raw.len());
+        let len = match cobs::decode(&mut buf, raw) {
+            Ok(len) if len >= HEADER_LEN + CRC_LEN => len,
+            _ => {
+                self.send_error(0, ERR_FRAME);
+                return true;
+            },
+        };
+
+        let op = buf[0];
+        let seq = get_le(&buf[1 .. 5]) as u32;
+        let body = HEADER_LEN + get_le(&buf[5 .. 9]) as usize;
+
+        if body + CRC_LEN != len {
+            self.send_error(seq, ERR_FRAME);
+            return true;
+        }
+
+        if crc32(&buf[.. body]) != get_le(&buf[body .. len]) as u32 {
+            self.send_error(seq, ERR_CKSUM);
+            return true;
+        }
+
+        if op == OP_EXIT {
+            self.send_frame(OP_EXIT | OP_REPLY, seq, &[]);
+            return false;
+        }
+
+        if let Err(e) = self.binary_op(op, seq, &buf[HEADER_LEN .. body]) {
+            self.send_error(seq, e);
+        }
+        true
+    }
+
+    fn binary_op(&mut self, op: u8, seq: u32, payload: &[u8]) -> StdResult
+    {
+        let reply = op | OP_REPLY;
+
+        match op {
+            OP_HELLO => {
+                let mut info = [0u8; 8];
+                put_le(&mut info[0 .. 4], VERSION as u64);
+                put_le(&mut info[4 .. 8], MAX_CHUNK as u64);
+                self.send_frame(reply, seq, &info);
+            },
+
+            OP_OPEN => {
+                self.open_file(bytes_to_str(payload)?)?;
+                self.send_frame(reply, seq, &[]);
+            },
+
+            OP_CLOSE => {
+                if !self.close_file()? {
+                    return Err(ERR_FILE_NOT_OPEN);
+                }
+                self.send_frame(reply, seq, &[]);
+            },
+
+            OP_SYNC => {
+                ext4::sync("/")?;
+                self.send_frame(reply, seq, &[]);
+            },
+
+            OP_READ => {
+                if payload.len() != 12 {
+                    return Err(ERR_FRAME);
+                }
+                let pos = get_le(&payload[0 .. 8]);
+                let mut len = get_le(&payload[8 .. 12]) as usize;
+                if len > MAX_CHUNK {
+                    len = MAX_CHUNK;
+                }
+
+                let mut buf = vec::from_elem(0u8, len);
+                let len = self.read_at(pos, &mut buf)?;
+                self.send_frame(reply, seq, &buf[.. len]);
+            },
+
+            OP_WRITE => {
+                if payload.len() < 8 {
+                    return Err(ERR_FRAME);
+                }
+                self.write_at(get_le(&payload[0 .. 8]), &payload[8 ..])?;
+                self.send_frame(reply, seq, &[]);
+            },
+
+            OP_TRUNCATE => {
+                if payload.len() != 8 {
+                    return Err(ERR_FRAME);
+                }
+                match self.file {
+                    Some(ref mut file) => {
+                        file.truncate(get_le(payload) as usize)?
+                    },
+                    None => return Err(ERR_FILE_NOT_OPEN),
+                }
+                self.send_frame(reply, seq, &[]);
+            },
+
+            OP_SIZE => {
+                let size = match self.file {
+                    Some(ref mut file) => file.size()? as u64,
+                    None => return Err(ERR_FILE_NOT_OPEN),
+                };
+                let mut encoded = [0u8; 8];
+                put_le(&mut encoded, size);
+                self.send_frame(reply, seq, &encoded);
+            },
+
+            OP_STAT => {
+                let st = ext4::stat(bytes_to_str(payload)?)?;
+                let mut record = Vec::new();
+                stat_record_bin(&mut record, &st, "");
+                self.send_frame(reply, seq, &record);
+            },
+
+            OP_LIST => {
+                let walk = ext4::walk(bytes_to_str(payload)?)?.max_depth(1);
+                let mut record = Vec::new();
+
+                for entry in walk {
+                    let entry = entry?;
+
+                    record.clear();
+                    stat_record_bin(&mut record, entry.stat(), entry.name());
+                    self.send_frame(reply, seq, &record);
+                }
+                self.send_frame(reply, seq, &[]);
+            },
+
+            OP_MKDIR => {
+                ext4::mkdir(bytes_to_str(payload)?)?;
+                self.send_frame(reply, seq, &[]);
+            },
+
+            OP_REMOVE => {
+                remove_path(bytes_to_str(payload)?)?;
+                self.send_frame(reply, seq, &[]);
+            },
+
+            OP_RENAME => {
+                let (old_path, new_path) = split_paths(payload)?;
+                ext4::rename(old_path, new_path)?;
+                self.send_frame(reply, seq, &[]);
+            },
+
+            OP_ERRMSG => {
+                if payload.len() != 2 {
+                    return Err(ERR_FRAME);
+                }
+                let code = get_le(payload) as u16;
+                let e = if code == ERRC_OTHER {
+                    &ERR_UNKNOWN
+                } else {
+                    ERROR_CODES
+                        .iter()
+                        .find(|&&(c, _)| c == code)
+                        .ok_or(ERR_ARG_RANGE)?
+                        .1
+                };
+                self.send_frame(reply, seq, e.message.as_bytes());
+            },
+
+            OP_UPLOAD => {
+                self.start_upload(bytes_to_str(payload)?)?;
+                self.send_frame(reply, seq, &[]);
+            },
+
+            OP_COMMIT => {
+                self.commit(payload)?;
+                self.send_frame(reply, seq, &[]);
+            },
+
+            OP_HASH => {
+                if payload.len() != 17 {
+                    return Err(ERR_FRAME);
+                }
+                let mut hasher = Hasher::from_code(payload[0])?;
+                let hashed = self.hash_at(
+                    &mut hasher,
+                    get_le(&payload[1 .. 9]),
+                    get_le(&payload[9 .. 17]),
+                )?;
+
+                let mut result = [0u8; 40];
+                put_le(&mut result[0 .. 8], hashed);
+                let mut digest = [0u8; 32];
+                let digest_len = hasher.finish(&mut digest);
+                result[8 .. 8 + digest_len]
+                    .copy_from_slice(&digest[.. digest_len]);
+                self.send_frame(reply, seq, &result[.. 8 + digest_len]);
+            },
+
+            _ => return Err(ERR_ENOTSUP),
+        }
+
+        Ok(())
+    }
+
+    /// Write at an offset for binary mode. See above for writes past the
+    /// end of the file.
+    fn write_at(&mut self, pos: u64, data: &[u8]) -> StdResult
+    {
+        let file = match self.file {
+            Some(ref mut file) => file,
+            None => return Err(ERR_FILE_NOT_OPEN),
+        };
+
+        if pos > file.size()? as u64 {
+            let held: usize = self.held.iter().map(|&(_, ref d)| d.len()).sum();
+            if held + data.len() > WINDOW_BYTES {
+                return Err(ERR_ARG_RANGE);
+            }
+
+            let mut copy = Vec::with_capacity(data.len());
+            copy.extend_from_slice(data);
+            self.held.push((pos, copy.into_boxed_slice()));
+            return Ok(());
+        }
+
+        file.seek(SeekFrom::Start(pos))?;
+        file.write_all(data)?;
+
+        // Anything held that now meets the end of the file can go too
+        loop {
+            let size = file.size()? as u64;
+            match self.held.iter().position(|&(p, _)| p <= size) {
+                Some(i) => {
+                    let (p, d) = self.held.swap_remove(i);
+                    file.seek(SeekFrom::Start(p))?;
+                    file.write_all(&d)?;
+                },
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn send_frame(&self, op: u8, seq: u32, payload: &[u8])
+    {
+        let body = HEADER_LEN + payload.len();
+        let mut frame = vec::from_elem(0u8, body + CRC_LEN);
+
+        frame[0] = op;
+        put_le(&mut frame[1 .. 5], seq as u64);
+        put_le(&mut frame[5 .. 9], payload.len() as u64);
+        frame[HEADER_LEN .. body].copy_from_slice(payload);
+        let crc = crc32(&frame[.. body]);
+        put_le(&mut frame[body ..], crc as u64);
+
+        // Delimiters on both sides: anything else printed on the interface
+        // since the last frame then spoils only an empty frame of its own
+        let mut encoded =
+            vec::from_elem(0u8, cobs::max_encoded_len(frame.len()) + 2);
+        let n = cobs::encode(&mut encoded[1 ..], &frame).unwrap();
+        encoded[n + 1] = 0;
+
+        let _ = ComStream::new(self.com).write_all(&encoded[.. n + 2]);
+    }
+
+    fn send_error(&self, seq: u32, e: Error)
+    {
+        let mut code = [0u8; 2];
+        let mut payload = Vec::with_capacity(2 + e.message.len());
+
+        put_le(&mut code, error_code(e) as u64);
+        payload.extend_from_slice(&code);
+        payload.extend_from_slice(e.message.as_bytes());
+        self.send_frame(OP_ERROR, seq, &payload);
+    }
+}
+
+/// Clean up after uploads cut short by a reset: remove temporaries that
+/// were never committed, and finish putting in place those that were. Call
+/// after mounting read-write.
+///
+/// Only the directories in the upload log are searched. A directory that
+/// can't be cleaned up is skipped and left in the log for the next mount.
+pub fn recover_uploads(mount_point: &str) -> StdResult
+{
+    let log = log_path(mount_point);
+    let dirs = match read_upload_log(&log) {
+        Ok(dirs) => dirs,
+        Err(e) if e == ERR_ENOENT => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    let mut clean = true;
+    for dir in &dirs {
+        if let Err(e) = recover_dir(dir) {
+            debug!(DEBUG_FS, "ftrans: cannot clean up uploads in {}: {}",
+                   dir, e);
+            clean = false;
+        }
+    }
+
+    if clean {
+        ext4::unlink(&log)?;
+    }
+    ext4::sync(mount_point)
+}
+
+/// Clean up the uploads directly inside `dir`, carrying on past errors.
+/// Returns the first one.
+fn recover_dir(dir: &str) -> StdResult
+{
+    let mut result = Ok(());
+
+    // Collect first; the directory shouldn't change under the walk
+    let mut found: Vec<String> = Vec::new();
+    let walk = match ext4::walk(dir) {
+        Ok(walk) => walk.max_depth(1),
+        // Removed since the upload
+        Err(e) if e == ERR_ENOENT => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in walk {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                result = result.and(Err(e));
+                continue;
+            },
+        };
+        let name = entry.name();
+
+        if entry.stat().inode_type() == ext4::InodeType::File &&
+            name.starts_with('.') &&
+            (name.ends_with(TEMP_SUFFIX) || name.ends_with(NEW_SUFFIX))
+        {
+            found.push(String::from(entry.path()));
+        }
+    }
+
+    for path in &found {
+        let r = if path.ends_with(NEW_SUFFIX) {
+            let target = target_path(path, NEW_SUFFIX);
+            debug!(DEBUG_FS, "ftrans: finishing upload of {}", target);
+            replace(path, &target)
+        } else {
+            debug!(DEBUG_FS, "ftrans: removing abandoned upload {}", path);
+            ext4::unlink(path)
+        };
+        result = result.and(r);
+    }
+    result
+}
+
+/// Add `dir` to the upload log, if it isn't there yet. The log is synced
+/// before returning, so that it lists the directory before any temporary
+/// is created in it.
+fn log_upload_dir(dir: &str) -> StdResult
+{
+    let log = log_path("/");
+    match read_upload_log(&log) {
+        Ok(ref dirs) if dirs.iter().any(|d| d == dir) => return Ok(()),
+        Err(e) if e != ERR_ENOENT => return Err(e),
+        _ => {},
+    }
+
+    let mut line = String::with_capacity(dir.len() + 1);
+    line.push_str(dir);
+    line.push('\n');
+    {
+        let mut file = ext4::fopen(&log, ext4::OpenFlags::Append)?;
+        file.write_all(line.as_bytes())?;
+    }
+    ext4::sync("/")
+}
+
+fn read_upload_log(log: &str) -> Result<Vec<String>, Error>
+{
+    let file = ext4::fopen(log, ext4::OpenFlags::Read)?;
+    let mut dirs = Vec::new();
+
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if !line.is_empty() {
+            dirs.push(line);
+        }
+    }
+    Ok(dirs)
+}
+
+fn log_path(mount_point: &str) -> String
+{
+    let mut s = String::from(mount_point.trim_right_matches('/'));
+    s.push('/');
+    s.push_str(UPLOAD_LOG);
+    s
+}
+
+/// Put a verified upload in place of `target`. The renamed temporary marks
+/// the upload as committed, so that if power is lost between removing the
+/// old target and the last rename, recover_uploads() can finish the job.
+fn finish_upload(temp: &str, target: &str) -> StdResult
+{
+    let new = sibling_path(target, NEW_SUFFIX);
+
+    ext4::sync("/")?;
+    replace(temp, &new)?;
+    ext4::sync("/")?;
+    replace(&new, target)?;
+    ext4::sync("/")
+}
+
+/// Rename `from` to `to`, replacing `to` if it exists, which lwext4's rename
+/// won't do by itself.
+fn replace(from: &str, to: &str) -> StdResult
+{
+    match ext4::unlink(to) {
+        Err(e) if e != ERR_ENOENT => return Err(e),
+        _ => {},
+    }
+    ext4::rename(from, to)
+}
+
+/// Hidden file next to `path`: "/dir/name" becomes "/dir/.name{suffix}".
+fn sibling_path(path: &str, suffix: &str) -> String
+{
+    let split = path.rfind('/').map_or(0, |i| i + 1);
+    let mut s = String::with_capacity(path.len() + suffix.len() + 1);
+
+    s.push_str(&path[.. split]);
+    s.push('.');
+    s.push_str(&path[split ..]);
+    s.push_str(suffix);
+    s
+}
+
+/// Directory holding `path`: "/dir/name" gives "/dir", "/name" gives "/".
+fn parent_dir(path: &str) -> &str
+{
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(i) => &path[.. i],
+    }
+}
+
+/// Reverse of sibling_path(), for a path known to be in that form.
+fn target_path(path: &str, suffix: &str) -> String
+{
+    let split = path.rfind('/').map_or(0, |i| i + 1);
+    let mut s = String::with_capacity(path.len());
+
+    s.push_str(&path[.. split]);
+    s.push_str(&path[split + 1 .. path.len() - suffix.len()]);
+    s
+}
+
+/// Format a binary stat record, followed by `name`.
+fn stat_record_bin(dest: &mut Vec<u8>, st: &ext4::Stat, name: &str)
+{
+    let mut fields = [0u8; STAT_LEN];
+
+    fields[0] = type_char(st);
+    put_le(&mut fields[1 .. 3], (st.mode() & 0o7777) as u64);
+    put_le(&mut fields[3 .. 11], st.size());
+    put_le(&mut fields[11 .. STAT_LEN], st.mtime().sec as u64);
+
+    dest.extend_from_slice(&fields);
+    dest.extend_from_slice(name.as_bytes());
+}
+
+fn bytes_to_str(data: &[u8]) -> Result<&str, Error>
+{
+    str::from_utf8(data).or(Err(ERR_UTF8))
+}
+
+/// Delete a file, or a directory if it is empty.
+fn remove_path(path: &str) -> StdResult
+{
+    if ext4::stat(path)?.inode_type() == ext4::InodeType::Dir {
+        ext4::rmdir(path)
+    } else {
+        ext4::unlink(path)
+    }
+}
+
+/// Split "{old path} NUL {new path}" as sent for rename.
+fn split_paths(paths: &[u8]) -> Result<(&str, &str), Error>
+{
+    let sep = paths.iter().position(|&c| c == 0).ok_or(ERR_EXPECTED_ARGS)?;
+
+    Ok((bytes_to_str(&paths[.. sep])?, bytes_to_str(&paths[sep + 1 ..])?))
+}
+
+/// Type of an inode as used in stat records: one of fdlpcbs?
+fn type_char(st: &ext4::Stat) -> u8
+{
+    match st.inode_type() {
+        ext4::InodeType::File => b'f',
+        ext4::InodeType::Dir => b'd',
+        ext4::InodeType::Symlink => b'l',
+        ext4::InodeType::Fifo => b'p',
+        ext4::InodeType::Chardev => b'c',
+        ext4::InodeType::Blockdev => b'b',
+        ext4::InodeType::Socket => b's',
+        ext4::InodeType::Other => b'?',
+    }
+}
+
+/// Format a stat record, as described for the `stat` command.
+fn write_stat_record(dest: &mut String, st: &ext4::Stat, name: &str)
+{
+    let _ = write!(
+        dest,
+        "{} {} {} {:o} {}",
+        type_char(st) as char,
+        st.size(),
+        st.mtime().sec,
+        st.mode() & 0o7777,
+        name
+    );
 }
 
 fn bytes_to_u32(data: &[u8]) -> Result<u32, Error>
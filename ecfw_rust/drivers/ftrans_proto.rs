@@ -0,0 +1,101 @@
+// c4puter embedded controller firmware
+// Copyright (C) 2017 Chris Pavlina
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+//
+
+//! Constants and encodings of the ftrans binary protocol, which is described
+//! in `ftrans`.
+//!
+//! The host client (`host/ftrans`) builds this file into itself, so it may
+//! only use `core`, and must build with both compilers.
+
+/// Protocol version reported by `hello`. Bump when commands are added or
+/// changed.
+pub const VERSION: u32 = 7;
+
+/// Largest chunk size that can be negotiated
+pub const MAX_CHUNK: usize = 8192;
+
+/// Memory allowed for write chunks held back until the ones before them
+/// arrive. Sets the window size.
+pub const WINDOW_BYTES: usize = 32768;
+
+pub const OP_HELLO: u8 = 0x01;
+pub const OP_OPEN: u8 = 0x02;
+pub const OP_CLOSE: u8 = 0x03;
+pub const OP_SYNC: u8 = 0x04;
+pub const OP_READ: u8 = 0x05;
+pub const OP_WRITE: u8 = 0x06;
+pub const OP_TRUNCATE: u8 = 0x07;
+pub const OP_SIZE: u8 = 0x08;
+pub const OP_STAT: u8 = 0x09;
+pub const OP_LIST: u8 = 0x0a;
+pub const OP_MKDIR: u8 = 0x0b;
+pub const OP_REMOVE: u8 = 0x0c;
+pub const OP_RENAME: u8 = 0x0d;
+pub const OP_ERRMSG: u8 = 0x0e;
+pub const OP_EXIT: u8 = 0x0f;
+pub const OP_HASH: u8 = 0x10;
+pub const OP_UPLOAD: u8 = 0x11;
+pub const OP_COMMIT: u8 = 0x12;
+pub const OP_REPLY: u8 = 0x80;
+pub const OP_ERROR: u8 = 0xff;
+
+// Error codes. These are part of the protocol and don't follow ERROR_TABLE:
+// add new ones at the end and never renumber or reuse one. Errors without a
+// code of their own are sent as ERRC_OTHER; their message still says what
+// went wrong.
+pub const ERRC_OTHER: u16 = 0;
+pub const ERRC_CHECKSUM: u16 = 1;
+pub const ERRC_FRAME: u16 = 2;
+pub const ERRC_NOT_SUPPORTED: u16 = 3;
+pub const ERRC_ARG_RANGE: u16 = 4;
+pub const ERRC_UTF8: u16 = 5;
+pub const ERRC_EXPECTED_ARGS: u16 = 6;
+pub const ERRC_FILE_NOT_OPEN: u16 = 7;
+pub const ERRC_NOT_FOUND: u16 = 8;
+pub const ERRC_EXISTS: u16 = 9;
+pub const ERRC_NOT_DIR: u16 = 10;
+pub const ERRC_IS_DIR: u16 = 11;
+pub const ERRC_NOT_EMPTY: u16 = 12;
+pub const ERRC_READ_ONLY: u16 = 13;
+pub const ERRC_NO_SPACE: u16 = 14;
+pub const ERRC_IO: u16 = 15;
+pub const ERRC_BUSY: u16 = 16;
+pub const ERRC_NO_UPLOAD: u16 = 17;
+pub const ERRC_UPLOAD_MISMATCH: u16 = 18;
+
+pub const HASH_CRC32: u8 = 0;
+pub const HASH_SHA256: u8 = 1;
+
+pub const HEADER_LEN: usize = 9;
+pub const CRC_LEN: usize = 4;
+
+/// Length of a binary stat record, before the name
+pub const STAT_LEN: usize = 19;
+
+/// Store the low bytes of `value` in `dest`, little endian.
+pub fn put_le(dest: &mut [u8], value: u64)
+{
+    for (i, b) in dest.iter_mut().enumerate() {
+        *b = (value >> (8 * i)) as u8;
+    }
+}
+
+/// Read a little endian integer of up to 8 bytes.
+pub fn get_le(src: &[u8]) -> u64
+{
+    src.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64)
+}
@@ -17,12 +17,15 @@
 
 //! GUID partition table driver.
 
-use data::utf;
+use data::{Crc32, crc32, utf};
+use drivers::bootslot;
 use drivers::sd::*;
 use messages::*;
 use os::Mutex;
-use core::fmt;
-use core::str;
+use os::freertos;
+use core::{cmp, fmt, str};
+use alloc::vec;
+use alloc::vec::Vec;
 
 pub const BOOT_GUID: Guid =
     Guid::from_raw(0x7cca2c66, 0xb705, 0x58cb, 0xb9d9, 0xe16a166b84d9);
@@ -40,6 +43,11 @@ pub const BOOT_GUID: Guid =
 // Signature is ASCII string "EFI PART"
 // Revision expected to be 00 00 01 00
 // Header size: 92 bytes
+// CRC32 covers the header size, with the CRC32 field itself zeroed
+//
+// The backup header lives in the last block of the disk, with its entries
+// just before it. Its Current and Backup LBA fields are swapped relative to
+// the main header.
 //
 //
 // Entry layout:
@@ -64,15 +72,29 @@ pub const BOOT_GUID: Guid =
 const GPT_HEADER_LBA: usize = 1;
 const BLOCK_SIZE: usize = 512;
 const SIGNATURE: u64 = 0x4546492050415254; // "EFI PART"
+const HEADER_SIZE_MIN: usize = 92;
+const MIN_ENTRY_LEN: usize = 128;
+const REVISION: u32 = 0x00010000;
+
+/// Which copy of the GPT a Gpt was read from.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum GptCopy {
+    Primary,
+    Backup,
+}
 
 /// GPT header.
 pub struct Gpt<'a> {
     buffer: [u8; BLOCK_SIZE],
     iblock: usize,
     guid: Guid,
+    first_usable: usize,
+    last_usable: usize,
     entry_len: usize,
     lba_entries: usize,
     number_entries: usize,
+    copy: GptCopy,
+    primary_error: Option<Error>,
     sd: &'a Mutex<Sd>,
 }
 
@@ -83,45 +105,172 @@ impl<'a> Gpt<'a> {
             buffer: [0u8; BLOCK_SIZE],
             iblock: 0,
             guid: Guid::new(),
+            first_usable: 0,
+            last_usable: 0,
             entry_len: 0,
             lba_entries: 0,
             number_entries: 0,
+            copy: GptCopy::Primary,
+            primary_error: None,
             sd: sd,
         }
     }
 
-    /// Read the initial GPT header from the card
+    /// Read the GPT header from the card. The primary header and its
+    /// partition array are checked first; if either is damaged, the backup
+    /// at the end of the disk is used instead. Fails only if both are bad,
+    /// with the primary's error.
     pub fn read_header(&mut self) -> StdResult
     {
-        self.buffer_block(GPT_HEADER_LBA)?;
+        let last_lba = self.last_lba()?;
+
+        self.copy = GptCopy::Primary;
+        self.primary_error = None;
+
+        let e = match self.load_header(GPT_HEADER_LBA) {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+
+        self.copy = GptCopy::Backup;
+        self.primary_error = Some(e);
+
+        match self.load_header(last_lba) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(e),
+        }
+    }
+
+    /// Which copy of the table was read by read_header().
+    pub fn copy(&self) -> GptCopy
+    {
+        self.copy
+    }
+
+    /// If the backup copy is in use, why the primary was rejected.
+    pub fn primary_error(&self) -> Option<Error>
+    {
+        self.primary_error
+    }
+
+    /// Read and validate one header and its partition array. The header
+    /// must say it lives at `lba`, and point at a copy somewhere else. Where
+    /// that other copy is isn't checked further: an image written to a
+    /// larger card has its backup short of the end, which is harmless.
+    fn load_header(&mut self, lba: usize) -> StdResult
+    {
+        self.buffer_block(lba)?;
 
         let sig = read_be(&self.buffer[0 .. 8]);
         if sig != SIGNATURE {
             return Err(ERR_GPT_SIGNATURE);
         }
 
-        self.entry_len = read_le(&self.buffer[0x54 .. 0x58]) as usize;
-        if self.entry_len == 0 {
+        let header_size = read_le(&self.buffer[0x0c .. 0x10]) as usize;
+        if header_size < HEADER_SIZE_MIN || header_size > BLOCK_SIZE {
+            return Err(ERR_GPT_HEADER_SIZE);
+        }
+
+        let header_crc = read_le(&self.buffer[0x10 .. 0x14]) as u32;
+        let mut crc = Crc32::new();
+        crc.update(&self.buffer[0 .. 0x10]);
+        crc.update(&[0u8; 4]);
+        crc.update(&self.buffer[0x14 .. header_size]);
+        if crc.finish() != header_crc {
+            return Err(ERR_GPT_HEADER_CRC);
+        }
+
+        let current_lba = read_le(&self.buffer[0x18 .. 0x20]) as usize;
+        let backup_lba = read_le(&self.buffer[0x20 .. 0x28]) as usize;
+        if current_lba != lba || backup_lba == lba {
+            return Err(ERR_GPT_LBA);
+        }
+
+        let entry_len = read_le(&self.buffer[0x54 .. 0x58]) as usize;
+        if entry_len == 0 {
             return Err(ERR_GPT_ZEROLEN);
         }
-        if BLOCK_SIZE % self.entry_len != 0 {
+        // Entries are at least 128 bytes (the fields GptEntry reads), and
+        // the spec requires 128 * 2^n
+        if entry_len < MIN_ENTRY_LEN
+            || !entry_len.is_power_of_two()
+            || BLOCK_SIZE % entry_len != 0
+        {
             return Err(ERR_GPT_SIZEMULT);
         }
 
-        self.lba_entries = read_le(&self.buffer[0x48 .. 0x50]) as usize;
-        self.number_entries = read_le(&self.buffer[0x50 .. 0x54]) as usize;
+        let first_usable = read_le(&self.buffer[0x28 .. 0x30]) as usize;
+        let last_usable = read_le(&self.buffer[0x30 .. 0x38]) as usize;
+        let lba_entries = read_le(&self.buffer[0x48 .. 0x50]) as usize;
+        let number_entries = read_le(&self.buffer[0x50 .. 0x54]) as usize;
+        let array_crc = read_le(&self.buffer[0x58 .. 0x5c]) as u32;
+        let guid = Guid::from_bytes(&self.buffer[0x38 .. 0x48]);
+
+        let array_len = number_entries
+            .checked_mul(entry_len)
+            .ok_or(ERR_NRANGE)?;
+        if self.array_crc(lba_entries, array_len)? != array_crc {
+            return Err(ERR_GPT_ARRAY_CRC);
+        }
 
-        self.guid = Guid::from_bytes(&self.buffer[0x38 .. 0x48]);
+        self.first_usable = first_usable;
+        self.last_usable = last_usable;
+        self.entry_len = entry_len;
+        self.lba_entries = lba_entries;
+        self.number_entries = number_entries;
+        self.guid = guid;
 
         Ok(())
     }
 
+    /// Compute the CRC32 of `len` bytes of partition array starting at
+    /// block `lba`.
+    fn array_crc(&mut self, lba: usize, len: usize) -> Result<u32, Error>
+    {
+        let mut crc = Crc32::new();
+        let mut remaining = len;
+        let mut iblock = lba;
+
+        while remaining > 0 {
+            let n = if remaining < BLOCK_SIZE {
+                remaining
+            } else {
+                BLOCK_SIZE
+            };
+            self.buffer_block(iblock)?;
+            crc.update(&self.buffer[0 .. n]);
+            remaining -= n;
+            iblock += 1;
+        }
+
+        Ok(crc.finish())
+    }
+
+    /// Get the index of the last block on the card, where the backup header
+    /// lives.
+    fn last_lba(&self) -> Result<usize, Error>
+    {
+        last_lba(self.sd)
+    }
+
     /// Return the disk's GUID. Must be initialized.
     pub fn guid(&self) -> &Guid
     {
         &self.guid
     }
 
+    /// Return the first block partitions may use. Must be initialized.
+    pub fn first_usable_lba(&self) -> usize
+    {
+        self.first_usable
+    }
+
+    /// Return the last block partitions may use. Must be initialized.
+    pub fn last_usable_lba(&self) -> usize
+    {
+        self.last_usable
+    }
+
     /// Get the total number of partition entries. Note that this is not the
     /// number of *used* entries; you must read them to know if they're
     /// used.
@@ -149,22 +298,19 @@ impl<'a> Gpt<'a> {
 
     /// Populate a GptEntry structure with the boot partition. If no boot
     /// partition is found, returns success but the entry will be invalid.
-    /// If multiple boot partitions are found, returns the first.
+    /// If multiple boot partitions are found, the boot slot with the highest
+    /// priority is returned; see bootslot.
     pub fn read_boot(&mut self, gptentry: &mut GptEntry) -> StdResult
     {
-        for i in 0 .. self.number_entries() {
-            self.read_entry(i, gptentry)?;
-
-            if gptentry.valid() {
-                if gptentry.type_guid == BOOT_GUID {
-                    return Ok(());
-                } else {
-                    gptentry.clear();
-                }
-            }
+        let slots = bootslot::read_slots_from(self)?;
+
+        match bootslot::choose(&slots, &[]) {
+            Some(slot) => self.read_entry(slot.index, gptentry),
+            None => {
+                gptentry.clear();
+                Ok(())
+            },
         }
-
-        Ok(())
     }
 
     /// Read a block into the buffer, unless it's currently in the buffer.
@@ -186,6 +332,339 @@ impl<'a> Gpt<'a> {
     }
 }
 
+/// Size of the partition array in a table made by GptWriter::create(). These
+/// are the usual values, giving a 16 KiB array.
+const NEW_NUMBER_ENTRIES: usize = 128;
+const NEW_ENTRY_LEN: usize = 128;
+
+/// In-memory copy of a GPT for editing. Nothing is changed on the card until
+/// write() is called.
+pub struct GptWriter<'a> {
+    sd: &'a Mutex<Sd>,
+    guid: Guid,
+    last_lba: usize,
+    first_usable: usize,
+    last_usable: usize,
+    entry_len: usize,
+    array: Vec<u8>,
+    new_table: bool,
+}
+
+impl<'a> GptWriter<'a> {
+    /// Start a fresh, empty table covering the whole card, with a new disk
+    /// GUID. write() will also write a protective MBR.
+    pub fn create(sd: &Mutex<Sd>) -> Result<GptWriter, Error>
+    {
+        let last_lba = last_lba(sd)?;
+        let array_len = NEW_NUMBER_ENTRIES * NEW_ENTRY_LEN;
+        let array_blocks = array_len / BLOCK_SIZE;
+        let first_usable = GPT_HEADER_LBA + 1 + array_blocks;
+
+        if last_lba < first_usable + 2 * array_blocks + 1 {
+            return Err(ERR_GPT_RANGE);
+        }
+
+        Ok(GptWriter {
+            sd: sd,
+            guid: Guid::generate(),
+            last_lba: last_lba,
+            first_usable: first_usable,
+            last_usable: last_lba - array_blocks - 1,
+            entry_len: NEW_ENTRY_LEN,
+            array: vec::from_elem(0u8, array_len),
+            new_table: true,
+        })
+    }
+
+    /// Load the table on the card for editing. If only the backup copy is
+    /// intact, it is loaded, and write() repairs the primary.
+    pub fn open(sd: &Mutex<Sd>) -> Result<GptWriter, Error>
+    {
+        let mut table = Gpt::new(sd);
+        table.read_header()?;
+
+        let array_len = table.number_entries * table.entry_len;
+        let mut array = vec::from_elem(0u8, array_len);
+
+        for (i, chunk) in array.chunks_mut(BLOCK_SIZE).enumerate() {
+            table.buffer_block(table.lba_entries + i)?;
+            let n = chunk.len();
+            chunk.copy_from_slice(&table.buffer[0 .. n]);
+        }
+
+        let writer = GptWriter {
+            sd: sd,
+            guid: table.guid,
+            last_lba: table.last_lba()?,
+            first_usable: table.first_usable,
+            last_usable: table.last_usable,
+            entry_len: table.entry_len,
+            array: array,
+            new_table: false,
+        };
+
+        // The arrays are rewritten in their standard places, which must not
+        // overlap the usable space.
+        let array_blocks = writer.array_blocks();
+        if writer.first_usable < GPT_HEADER_LBA + 1 + array_blocks ||
+            writer.last_usable + array_blocks >= writer.last_lba
+        {
+            return Err(ERR_GPT_RANGE);
+        }
+
+        Ok(writer)
+    }
+
+    /// Return the disk's GUID.
+    pub fn guid(&self) -> &Guid
+    {
+        &self.guid
+    }
+
+    /// Get the total number of partition entries, used or not.
+    pub fn number_entries(&self) -> usize
+    {
+        self.array.len() / self.entry_len
+    }
+
+    /// Return the first block partitions may use.
+    pub fn first_usable_lba(&self) -> usize
+    {
+        self.first_usable
+    }
+
+    /// Return the last block partitions may use.
+    pub fn last_usable_lba(&self) -> usize
+    {
+        self.last_usable
+    }
+
+    /// Populate a GptEntry structure with a given entry.
+    pub fn read_entry(
+        &self,
+        ientry: usize,
+        gptentry: &mut GptEntry,
+    ) -> StdResult
+    {
+        gptentry.load(self.entry_data(ientry)?)
+    }
+
+    /// Replace an entry. Valid entries must lie within the usable space and
+    /// not overlap any other valid entry.
+    pub fn write_entry(
+        &mut self,
+        ientry: usize,
+        gptentry: &GptEntry,
+    ) -> StdResult
+    {
+        self.entry_data(ientry)?;
+
+        if gptentry.valid() {
+            self.check_range(ientry, gptentry.start_lba, gptentry.end_lba)?;
+        }
+
+        let start = ientry * self.entry_len;
+        let end = start + self.entry_len;
+        gptentry.store(&mut self.array[start .. end])
+    }
+
+    /// Put an entry in the first unused slot, returning its index.
+    pub fn add_entry(&mut self, gptentry: &GptEntry) -> Result<usize, Error>
+    {
+        let mut existing = GptEntry::new();
+
+        for i in 0 .. self.number_entries() {
+            self.read_entry(i, &mut existing)?;
+            if !existing.valid() {
+                self.write_entry(i, gptentry)?;
+                return Ok(i);
+            }
+        }
+
+        Err(ERR_GPT_FULL)
+    }
+
+    /// Mark an entry unused.
+    pub fn delete_entry(&mut self, ientry: usize) -> StdResult
+    {
+        self.entry_data(ientry)?;
+
+        let start = ientry * self.entry_len;
+        for i in start .. start + self.entry_len {
+            self.array[i] = 0;
+        }
+        Ok(())
+    }
+
+    /// Write the table to the card. The backup is written first, so that if
+    /// this is interrupted, read_header() finds either the old primary or
+    /// the new backup intact.
+    pub fn write(&mut self) -> StdResult
+    {
+        let array_crc = crc32(&self.array);
+        let array_blocks = self.array_blocks();
+        let backup_entries = self.last_lba - array_blocks;
+
+        let mut sd = self.sd.lock();
+
+        self.write_array(&mut sd, backup_entries)?;
+        let header = self.header(
+            self.last_lba,
+            GPT_HEADER_LBA,
+            backup_entries,
+            array_crc,
+        );
+        sd.write_block(self.last_lba, &header)?;
+
+        self.write_array(&mut sd, GPT_HEADER_LBA + 1)?;
+        let header = self.header(
+            GPT_HEADER_LBA,
+            self.last_lba,
+            GPT_HEADER_LBA + 1,
+            array_crc,
+        );
+        sd.write_block(GPT_HEADER_LBA, &header)?;
+
+        if self.new_table {
+            sd.write_block(0, &self.protective_mbr())?;
+            self.new_table = false;
+        }
+
+        Ok(())
+    }
+
+    /// Get the slice of the array holding one entry.
+    fn entry_data(&self, ientry: usize) -> Result<&[u8], Error>
+    {
+        if ientry >= self.number_entries() {
+            return Err(ERR_ARG_RANGE);
+        }
+
+        let start = ientry * self.entry_len;
+        Ok(&self.array[start .. start + self.entry_len])
+    }
+
+    /// Check that a partition for entry `ientry` may occupy blocks `start`
+    /// through `end` inclusive.
+    fn check_range(
+        &self,
+        ientry: usize,
+        start: usize,
+        end: usize,
+    ) -> StdResult
+    {
+        if start > end || start < self.first_usable || end > self.last_usable
+        {
+            return Err(ERR_GPT_RANGE);
+        }
+
+        for i in 0 .. self.number_entries() {
+            if i == ientry {
+                continue;
+            }
+
+            let data = self.entry_data(i)?;
+            let other_start = read_le(&data[0x20 .. 0x28]) as usize;
+            let other_end = read_le(&data[0x28 .. 0x30]) as usize;
+
+            if other_start <= GPT_HEADER_LBA {
+                continue;
+            }
+            if start <= other_end && other_start <= end {
+                return Err(ERR_GPT_OVERLAP);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn array_blocks(&self) -> usize
+    {
+        (self.array.len() + BLOCK_SIZE - 1) / BLOCK_SIZE
+    }
+
+    /// Write the partition array starting at block `lba`, padding the last
+    /// block with zeros.
+    fn write_array(&self, sd: &mut Sd, lba: usize) -> StdResult
+    {
+        let mut block = [0u8; BLOCK_SIZE];
+
+        for (i, chunk) in self.array.chunks(BLOCK_SIZE).enumerate() {
+            block[0 .. chunk.len()].copy_from_slice(chunk);
+            for c in block[chunk.len() ..].iter_mut() {
+                *c = 0;
+            }
+            sd.write_block(lba + i, &block)?;
+        }
+
+        Ok(())
+    }
+
+    /// Build a header block.
+    fn header(
+        &self,
+        lba: usize,
+        alt_lba: usize,
+        entries_lba: usize,
+        array_crc: u32,
+    ) -> [u8; BLOCK_SIZE]
+    {
+        let mut block = [0u8; BLOCK_SIZE];
+
+        write_be(&mut block[0x00 .. 0x08], SIGNATURE);
+        write_le(&mut block[0x08 .. 0x0c], REVISION as u64);
+        write_le(&mut block[0x0c .. 0x10], HEADER_SIZE_MIN as u64);
+        write_le(&mut block[0x18 .. 0x20], lba as u64);
+        write_le(&mut block[0x20 .. 0x28], alt_lba as u64);
+        write_le(&mut block[0x28 .. 0x30], self.first_usable as u64);
+        write_le(&mut block[0x30 .. 0x38], self.last_usable as u64);
+        self.guid.to_bytes(&mut block[0x38 .. 0x48]);
+        write_le(&mut block[0x48 .. 0x50], entries_lba as u64);
+        write_le(&mut block[0x50 .. 0x54], self.number_entries() as u64);
+        write_le(&mut block[0x54 .. 0x58], self.entry_len as u64);
+        write_le(&mut block[0x58 .. 0x5c], array_crc as u64);
+
+        let header_crc = crc32(&block[0 .. HEADER_SIZE_MIN]);
+        write_le(&mut block[0x10 .. 0x14], header_crc as u64);
+
+        block
+    }
+
+    /// Build a protective MBR: one partition of type 0xEE covering the whole
+    /// disk (or as much as MBR can describe), so that MBR-only tools see the
+    /// disk as in use.
+    fn protective_mbr(&self) -> [u8; BLOCK_SIZE]
+    {
+        let mut block = [0u8; BLOCK_SIZE];
+        let size = cmp::min(self.last_lba as u64, 0xffffffff);
+
+        {
+            let entry = &mut block[0x1be .. 0x1ce];
+            entry[0x01 .. 0x04].copy_from_slice(&[0x00, 0x02, 0x00]);
+            entry[0x04] = 0xee;
+            entry[0x05 .. 0x08].copy_from_slice(&[0xff, 0xff, 0xff]);
+            write_le(&mut entry[0x08 .. 0x0c], GPT_HEADER_LBA as u64);
+            write_le(&mut entry[0x0c .. 0x10], size);
+        }
+
+        block[0x1fe] = 0x55;
+        block[0x1ff] = 0xaa;
+        block
+    }
+}
+
+/// Get the index of the last block on the card.
+fn last_lba(sd: &Mutex<Sd>) -> Result<usize, Error>
+{
+    let kbytes = sd.lock().capacity() as usize;
+
+    if kbytes == 0 {
+        Err(ERR_NO_CARD)
+    } else {
+        Ok(kbytes * (1024 / BLOCK_SIZE) - 1)
+    }
+}
+
 #[derive(Copy, Clone, PartialEq)]
 pub struct Guid {
     a: u32,
@@ -227,8 +706,88 @@ impl Guid {
             e: read_be(&data[10 .. 16]),
         }
     }
+
+    /// Write a GUID as bytes as stored in GPT.
+    /// data must be 16 bytes long.
+    pub fn to_bytes(&self, data: &mut [u8])
+    {
+        assert!(data.len() == 16);
+        write_le(&mut data[0 .. 4], self.a as u64);
+        write_le(&mut data[4 .. 6], self.b as u64);
+        write_le(&mut data[6 .. 8], self.c as u64);
+        write_be(&mut data[8 .. 10], self.d as u64);
+        write_be(&mut data[10 .. 16], self.e);
+    }
+
+    /// Parse a GUID from its textual form,
+    /// `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`. Either case is accepted.
+    pub fn parse(s: &str) -> Result<Guid, Error>
+    {
+        let mut fields = [0u64; 5];
+        let mut n = 0;
+
+        for (i, field) in s.split('-').enumerate() {
+            if i >= fields.len() || field.len() != GUID_FIELD_LENS[i] {
+                return Err(ERR_GUID);
+            }
+            if !field.chars().all(|c| c.is_digit(16)) {
+                return Err(ERR_GUID);
+            }
+            fields[i] = match u64::from_str_radix(field, 16) {
+                Ok(v) => v,
+                Err(_) => return Err(ERR_GUID),
+            };
+            n += 1;
+        }
+
+        if n != fields.len() {
+            return Err(ERR_GUID);
+        }
+
+        Ok(Guid::from_raw(
+            fields[0] as u32,
+            fields[1] as u16,
+            fields[2] as u16,
+            fields[3] as u16,
+            fields[4],
+        ))
+    }
+
+    /// Generate a new random (version 4) GUID. There is no hardware random
+    /// number generator, so this stirs the tick count into a xorshift
+    /// generator on every call. Good enough to keep GUIDs from colliding,
+    /// not for anything secret.
+    pub fn generate() -> Guid
+    {
+        let mut state = GUID_STATE.lock();
+
+        *state ^= (freertos::ticks() as u64).wrapping_mul(0x9e3779b97f4a7c15);
+        let mut next = || {
+            *state ^= *state << 13;
+            *state ^= *state >> 7;
+            *state ^= *state << 17;
+            *state
+        };
+
+        let hi = next();
+        let lo = next();
+
+        Guid::from_raw(
+            (hi >> 32) as u32,
+            (hi >> 16) as u16,
+            (hi as u16 & 0x0fff) | 0x4000,
+            ((lo >> 48) as u16 & 0x3fff) | 0x8000,
+            lo,
+        )
+    }
 }
 
+/// Lengths in hex digits of the fields of a textual GUID.
+const GUID_FIELD_LENS: [usize; 5] = [8, 4, 4, 4, 12];
+
+/// State of the Guid::generate() generator. Must never be zero.
+static GUID_STATE: Mutex<u64> = Mutex::new(0x2545f4914f6cdd1d);
+
 impl fmt::Display for Guid {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
@@ -241,7 +800,7 @@ pub struct GptEntry {
     pub part_guid: Guid,
     pub start_lba: usize,
     pub end_lba: usize,
-    pub attributes: u32,
+    pub attributes: u64,
     name_buf: [u8; /* codepoints */ 72 * /* UTF8 max per cp */ 4],
     name_len: usize,
 }
@@ -268,7 +827,7 @@ impl GptEntry {
         self.part_guid = Guid::from_bytes(&data[0x10 .. 0x20]);
         self.start_lba = read_le(&data[0x20 .. 0x28]) as usize;
         self.end_lba = read_le(&data[0x28 .. 0x30]) as usize;
-        self.attributes = read_le(&data[0x30 .. 0x38]) as u32;
+        self.attributes = read_le(&data[0x30 .. 0x38]);
 
         self.name_len = utf::read_utf16le_into_utf8(
             &mut self.name_buf,
@@ -277,6 +836,37 @@ impl GptEntry {
         Ok(())
     }
 
+    /// Store a GPT entry into a block of raw data, as long as one entry.
+    /// Anything past the name is zeroed.
+    pub fn store(&self, data: &mut [u8]) -> StdResult
+    {
+        for c in data.iter_mut() {
+            *c = 0;
+        }
+
+        self.type_guid.to_bytes(&mut data[0x00 .. 0x10]);
+        self.part_guid.to_bytes(&mut data[0x10 .. 0x20]);
+        write_le(&mut data[0x20 .. 0x28], self.start_lba as u64);
+        write_le(&mut data[0x28 .. 0x30], self.end_lba as u64);
+        write_le(&mut data[0x30 .. 0x38], self.attributes);
+
+        utf::write_utf8_into_utf16le(&mut data[0x38 .. 0x80], self.name())?;
+        Ok(())
+    }
+
+    /// Set the partition name. Fails with ERR_STRLEN if it is longer than
+    /// the 36 UTF-16 code units GPT allows.
+    pub fn set_name(&mut self, name: &str) -> StdResult
+    {
+        if name.encode_utf16().count() > NAME_UNITS {
+            return Err(ERR_STRLEN);
+        }
+
+        self.name_buf[0 .. name.len()].copy_from_slice(name.as_bytes());
+        self.name_len = name.len();
+        Ok(())
+    }
+
     /// Clear a GPT entry so .valid() returns false
     pub fn clear(&mut self)
     {
@@ -294,6 +884,9 @@ impl GptEntry {
     }
 }
 
+/// Length of a partition name in UTF-16 code units.
+const NAME_UNITS: usize = 36;
+
 /// Read up to eight bytes in little endian.
 fn read_be(data: &[u8]) -> u64
 {
@@ -312,3 +905,25 @@ fn read_le(data: &[u8]) -> u64
 {
     read_be(data).swap_bytes() >> (8 * (8 - data.len()))
 }
+
+/// Write up to eight bytes in big endian.
+fn write_be(data: &mut [u8], value: u64)
+{
+    let mut value = value;
+
+    for i in data.iter_mut().rev() {
+        *i = value as u8;
+        value >>= 8;
+    }
+}
+
+/// Write up to eight bytes in little endian.
+fn write_le(data: &mut [u8], value: u64)
+{
+    let mut value = value;
+
+    for i in data.iter_mut() {
+        *i = value as u8;
+        value >>= 8;
+    }
+}
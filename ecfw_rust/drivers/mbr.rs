@@ -0,0 +1,257 @@
+// c4puter embedded controller firmware
+// Copyright (C) 2017 Chris Pavlina
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+//
+
+//! MBR partition table driver.
+
+use drivers::sd::*;
+use messages::*;
+use os::Mutex;
+use alloc::vec::Vec;
+
+// MBR layout (block 0):
+//
+// 0x1b8 Disk signature (4 bytes, little endian)
+// 0x1be Partition entries, 4 x 16 bytes
+// 0x1fe Boot signature 55 AA
+//
+// Entry layout:            all values little endian
+//
+//      00     01 02 03   04    05 06 07   08 09 0a 0b   0c 0d 0e 0f
+//      Status CHS first  Type  CHS last   First LBA__   Nb blocks__
+//
+// Status 0x80 marks the active (bootable) partition. CHS values are ignored.
+//
+// An extended partition holds a chain of EBRs, one per logical partition.
+// Each has the MBR layout but only uses two entries: the logical partition,
+// with its first LBA relative to the EBR, and a link to the next EBR, with
+// its first LBA relative to the start of the extended partition.
+
+const BLOCK_SIZE: usize = 512;
+const ENTRIES_OFFSET: usize = 0x1be;
+const ENTRY_LEN: usize = 16;
+const NUMBER_PRIMARY: usize = 4;
+
+/// Upper limit on logical partitions, so a looped EBR chain can't hang us.
+const MAX_LOGICAL: usize = 128;
+
+pub const TYPE_LINUX: u8 = 0x83;
+pub const TYPE_GPT_PROTECTIVE: u8 = 0xee;
+
+/// MBR partition table.
+pub struct Mbr<'a> {
+    buffer: [u8; BLOCK_SIZE],
+    disk_id: u32,
+    entries: Vec<MbrEntry>,
+    sd: &'a Mutex<Sd>,
+}
+
+impl<'a> Mbr<'a> {
+    pub fn new(sd: &Mutex<Sd>) -> Mbr
+    {
+        Mbr {
+            buffer: [0u8; BLOCK_SIZE],
+            disk_id: 0,
+            entries: Vec::new(),
+            sd: sd,
+        }
+    }
+
+    /// Read the MBR and any logical partitions from the card. Fails with
+    /// ERR_MBR_PROTECTIVE if the MBR only exists to protect a GPT.
+    pub fn read(&mut self) -> StdResult
+    {
+        self.entries.clear();
+        self.read_block(0)?;
+
+        self.disk_id = read_le(&self.buffer[0x1b8 .. 0x1bc]);
+
+        let mut primary = [MbrEntry::new(); NUMBER_PRIMARY];
+        for i in 0 .. NUMBER_PRIMARY {
+            primary[i] = MbrEntry::load(i + 1, entry_data(&self.buffer, i), 0);
+        }
+
+        if primary.iter().any(|e| e.part_type == TYPE_GPT_PROTECTIVE) {
+            return Err(ERR_MBR_PROTECTIVE);
+        }
+
+        let mut extended = None;
+        for entry in primary.iter() {
+            if entry.is_extended() {
+                if extended.is_none() {
+                    extended = Some(*entry);
+                }
+            } else if entry.valid() {
+                self.entries.push(*entry);
+            }
+        }
+
+        if let Some(ext) = extended {
+            self.read_logical(&ext)?;
+        }
+
+        Ok(())
+    }
+
+    /// Return the disk signature.
+    pub fn disk_id(&self) -> u32
+    {
+        self.disk_id
+    }
+
+    /// Return the partitions found, primary first, then logical. Extended
+    /// partitions themselves are not included.
+    pub fn entries(&self) -> &[MbrEntry]
+    {
+        &self.entries
+    }
+
+    /// Find the boot partition: the first active Linux partition, or if
+    /// none is marked active, the only Linux partition.
+    pub fn find_boot(&self) -> Option<MbrEntry>
+    {
+        let mut only = None;
+        let mut count = 0;
+
+        for e in self.entries.iter().filter(|e| e.part_type == TYPE_LINUX) {
+            if e.active {
+                return Some(*e);
+            }
+            only = Some(*e);
+            count += 1;
+        }
+
+        if count == 1 { only } else { None }
+    }
+
+    /// Follow the EBR chain of an extended partition.
+    fn read_logical(&mut self, ext: &MbrEntry) -> StdResult
+    {
+        let mut ebr_lba = ext.start_lba;
+
+        for n in 0 .. MAX_LOGICAL {
+            if ebr_lba < ext.start_lba || ebr_lba > ext.end_lba {
+                return Err(ERR_MBR_EBR);
+            }
+
+            self.read_block(ebr_lba)?;
+
+            let logical = MbrEntry::load(
+                NUMBER_PRIMARY + 1 + n,
+                entry_data(&self.buffer, 0),
+                ebr_lba,
+            );
+            let link = MbrEntry::load(0, entry_data(&self.buffer, 1), 0);
+
+            if logical.valid() {
+                self.entries.push(logical);
+            }
+
+            if !link.valid() {
+                return Ok(());
+            }
+            ebr_lba = ext.start_lba + link.start_lba;
+        }
+
+        Err(ERR_MBR_EBR)
+    }
+
+    /// Read a block into the buffer, checking the boot signature.
+    fn read_block(&mut self, iblock: usize) -> StdResult
+    {
+        self.sd.lock().read_block(iblock, &mut self.buffer)?;
+
+        if self.buffer[0x1fe] != 0x55 || self.buffer[0x1ff] != 0xaa {
+            return Err(ERR_MBR_SIGNATURE);
+        }
+
+        Ok(())
+    }
+}
+
+/// One MBR partition.
+#[derive(Copy, Clone)]
+pub struct MbrEntry {
+    /// Partition number as Linux counts them: 1-4 are primary, logical
+    /// partitions start at 5.
+    pub number: usize,
+    pub part_type: u8,
+    pub active: bool,
+    pub start_lba: usize,
+    pub end_lba: usize,
+}
+
+impl MbrEntry {
+    /// Return a new, empty entry.
+    pub const fn new() -> MbrEntry
+    {
+        MbrEntry {
+            number: 0,
+            part_type: 0,
+            active: false,
+            start_lba: 0,
+            end_lba: 0,
+        }
+    }
+
+    /// Load an entry from 16 bytes of raw data. `base_lba` is added to the
+    /// first LBA, for logical partitions.
+    fn load(number: usize, data: &[u8], base_lba: usize) -> MbrEntry
+    {
+        let start = read_le(&data[0x08 .. 0x0c]) as usize;
+        let size = read_le(&data[0x0c .. 0x10]) as usize;
+
+        if data[0x04] == 0 || size == 0 {
+            return MbrEntry::new();
+        }
+
+        MbrEntry {
+            number: number,
+            part_type: data[0x04],
+            active: data[0x00] & 0x80 != 0,
+            start_lba: base_lba + start,
+            end_lba: base_lba + start + size - 1,
+        }
+    }
+
+    /// Whether this is an extended partition, containing logical ones.
+    pub fn is_extended(&self) -> bool
+    {
+        match self.part_type {
+            0x05 | 0x0f | 0x85 => true,
+            _ => false,
+        }
+    }
+
+    pub fn valid(&self) -> bool
+    {
+        self.part_type != 0 && self.start_lba > 0
+    }
+}
+
+/// Get the raw data of entry `i` of an MBR or EBR.
+fn entry_data(block: &[u8], i: usize) -> &[u8]
+{
+    let start = ENTRIES_OFFSET + i * ENTRY_LEN;
+    &block[start .. start + ENTRY_LEN]
+}
+
+/// Read four bytes in little endian.
+fn read_le(data: &[u8]) -> u32
+{
+    (data[0] as u32) | ((data[1] as u32) << 8) | ((data[2] as u32) << 16) |
+        ((data[3] as u32) << 24)
+}
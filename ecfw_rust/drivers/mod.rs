@@ -29,8 +29,12 @@ pub mod fpga;
 pub mod northbridge;
 pub mod sdram;
 pub mod gpt;
+pub mod mbr;
+pub mod part;
+pub mod bootslot;
 pub mod power;
 pub mod ftrans;
+pub mod ftrans_proto;
 #[macro_use] pub mod com;
 pub mod com_usart;
 pub mod com_cdc;
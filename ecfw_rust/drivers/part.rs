@@ -0,0 +1,178 @@
+// c4puter embedded controller firmware
+// Copyright (C) 2017 Chris Pavlina
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+//
+
+//! Partition table detection, for cards with either a GPT or an MBR.
+
+use drivers::gpt::{Gpt, GptEntry};
+use drivers::mbr::{Mbr, MbrEntry};
+use drivers::sd::*;
+use messages::*;
+use os::Mutex;
+
+/// Kind of partition table on a card.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Scheme {
+    Gpt,
+    Mbr,
+}
+
+/// Location of a partition on the card, whichever table it came from.
+#[derive(Copy, Clone)]
+pub struct Partition {
+    pub start_lba: usize,
+    pub end_lba: usize,
+}
+
+impl Partition {
+    /// A pseudo-partition covering the whole card. Must be initialized.
+    pub fn whole(sd: &Mutex<Sd>) -> Result<Partition, Error>
+    {
+        let kbytes = sd.lock().capacity() as usize;
+
+        if kbytes == 0 {
+            return Err(ERR_NO_CARD);
+        }
+
+        Ok(Partition {
+            start_lba: 0,
+            end_lba: kbytes * 2 - 1,
+        })
+    }
+
+    /// Number of blocks in the partition.
+    pub fn blocks(&self) -> usize
+    {
+        self.end_lba - self.start_lba + 1
+    }
+}
+
+impl<'a> From<&'a GptEntry> for Partition {
+    fn from(entry: &GptEntry) -> Partition
+    {
+        Partition {
+            start_lba: entry.start_lba,
+            end_lba: entry.end_lba,
+        }
+    }
+}
+
+impl<'a> From<&'a MbrEntry> for Partition {
+    fn from(entry: &MbrEntry) -> Partition
+    {
+        Partition {
+            start_lba: entry.start_lba,
+            end_lba: entry.end_lba,
+        }
+    }
+}
+
+/// A partition table that has been read from the card.
+pub enum Table<'a> {
+    Gpt(Gpt<'a>),
+    Mbr(Mbr<'a>),
+}
+
+impl<'a> Table<'a> {
+    /// Read whichever partition table the card has. A GPT, from either
+    /// copy, is preferred. The MBR is only used if it is not the protective
+    /// MBR of a GPT, so a damaged GPT reports its own error rather than
+    /// being misread.
+    pub fn read(sd: &Mutex<Sd>) -> Result<Table, Error>
+    {
+        let mut gpt = Gpt::new(sd);
+        let gpt_err = match gpt.read_header() {
+            Ok(()) => return Ok(Table::Gpt(gpt)),
+            Err(e) => e,
+        };
+
+        let mut mbr = Mbr::new(sd);
+        let mbr_err = match mbr.read() {
+            Ok(()) => return Ok(Table::Mbr(mbr)),
+            Err(e) => e,
+        };
+
+        let no_mbr = mbr_err == ERR_MBR_SIGNATURE;
+
+        if no_mbr && gpt_err == ERR_GPT_SIGNATURE {
+            Err(ERR_NO_PART_TABLE)
+        } else if no_mbr || mbr_err == ERR_MBR_PROTECTIVE {
+            Err(gpt_err)
+        } else {
+            Err(mbr_err)
+        }
+    }
+
+    pub fn scheme(&self) -> Scheme
+    {
+        match *self {
+            Table::Gpt(_) => Scheme::Gpt,
+            Table::Mbr(_) => Scheme::Mbr,
+        }
+    }
+
+    /// Find the partition to boot from: the BOOT_GUID partition of a GPT,
+    /// or for an MBR, see Mbr::find_boot().
+    pub fn find_boot(&mut self) -> Result<Partition, Error>
+    {
+        match *self {
+            Table::Gpt(ref mut gpt) => {
+                let mut entry = GptEntry::new();
+                gpt.read_boot(&mut entry)?;
+
+                if entry.valid() {
+                    Ok(Partition::from(&entry))
+                } else {
+                    Err(ERR_NO_BOOT_PART)
+                }
+            },
+            Table::Mbr(ref mbr) => {
+                match mbr.find_boot() {
+                    Some(ref entry) => Ok(Partition::from(entry)),
+                    None => Err(ERR_NO_BOOT_PART),
+                }
+            },
+        }
+    }
+
+    /// Find a partition by number: the entry index for a GPT, or the
+    /// partition number (1-4 primary, 5 and up logical) for an MBR.
+    pub fn find(&mut self, n: usize) -> Result<Partition, Error>
+    {
+        match *self {
+            Table::Gpt(ref mut gpt) => {
+                if n >= gpt.number_entries() {
+                    return Err(ERR_ARG_RANGE);
+                }
+
+                let mut entry = GptEntry::new();
+                gpt.read_entry(n, &mut entry)?;
+
+                if entry.valid() {
+                    Ok(Partition::from(&entry))
+                } else {
+                    Err(ERR_CANNOT_FIND)
+                }
+            },
+            Table::Mbr(ref mbr) => {
+                match mbr.entries().iter().find(|e| e.number == n) {
+                    Some(entry) => Ok(Partition::from(entry)),
+                    None => Err(ERR_CANNOT_FIND),
+                }
+            },
+        }
+    }
+}
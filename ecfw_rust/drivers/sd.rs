@@ -19,11 +19,74 @@
 
 use asf_sd_mmc;
 use ctypes;
+use core::cell::Cell;
+use core::{fmt, str};
 
+use os::freertos;
 use messages::*;
 
+/// Time allowed for one card initialization attempt, in milliseconds
+const CHECK_TIMEOUT_MS: u32 = 1000;
+
+/// Number of initialization attempts before giving up on a card
+const CHECK_TRIES: u32 = 3;
+
+/// Number of attempts at a block transfer failing with a communication error
+const IO_TRIES: u32 = 4;
+
+/// Time after which a failed block transfer is no longer retried, in
+/// milliseconds. This is not a timeout on the transfer itself, which is
+/// IO_TIMEOUT_MS; it stops retries of transfers that do fail from piling up.
+const IO_RETRY_DEADLINE_MS: u32 = 2000;
+
+/// Time allowed per block for a transfer to end, in milliseconds: the SD
+/// spec's limit on write busy time for SDXC cards, the longest there is. A
+/// transfer that takes longer is abandoned and not retried, as the card has
+/// stopped answering.
+const IO_TIMEOUT_MS: u32 = 500;
+
+/// Pause before retrying a failed transfer, in milliseconds
+const IO_RETRY_DELAY_MS: u32 = 10;
+
 pub struct Sd {
     slot: u8,
+    stats: SdStats,
+}
+
+/// Transfer statistics, kept since the device was created or last reset
+#[derive(Copy, Clone, Debug)]
+pub struct SdStats {
+    pub blocks_read: u64,
+    pub blocks_written: u64,
+    pub reads: u32,
+    pub writes: u32,
+    /// Transfers repeated after a communication error
+    pub retries: u32,
+    /// Transfers not retried because IO_RETRY_DEADLINE_MS had passed
+    pub expired: u32,
+    /// Transfers abandoned after IO_TIMEOUT_MS per block
+    pub timeouts: u32,
+    /// Transfers that failed after all retries, including expired ones and
+    /// timeouts
+    pub errors: u32,
+    pub last_error: Option<Error>,
+}
+
+impl SdStats {
+    pub const fn new() -> SdStats
+    {
+        SdStats {
+            blocks_read: 0,
+            blocks_written: 0,
+            reads: 0,
+            writes: 0,
+            retries: 0,
+            expired: 0,
+            timeouts: 0,
+            errors: 0,
+            last_error: None,
+        }
+    }
 }
 
 fn to_stdresult(code: u8) -> StdResult
@@ -78,29 +141,56 @@ pub fn init()
 impl Sd {
     pub const fn new(slot: u8) -> Sd
     {
-        Sd { slot: slot }
+        Sd {
+            slot: slot,
+            stats: SdStats::new(),
+        }
     }
 
-    /// Check whether the card is ready, initializing
+    /// Check whether the card is ready, initializing it if needed. Gives up
+    /// with the last error after CHECK_TRIES failed attempts; each attempt
+    /// times out after CHECK_TIMEOUT_MS. A missing card is not retried.
     pub fn check(&mut self) -> StdResult
     {
         assert!(self.slot == 0);
 
+        let mut result = Ok(());
+
+        for _ in 0 .. CHECK_TRIES {
+            result = self.check_once();
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) if e == ERR_NO_CARD => return Err(e),
+                Err(_) => (),
+            }
+        }
+
+        result
+    }
+
+    fn check_once(&mut self) -> StdResult
+    {
         // ASF SD driver will return SD_MMC_ERR_NO_CARD once, then
         // SD_MMC_INIT_ONGOING, then success. Call several times to
         // hide this stupid behavior.
 
-        let mut got_no_card = false;
+        let slot = self.slot;
+        let got_no_card = Cell::new(false);
 
-        loop {
-            let ec = unsafe { asf_sd_mmc::sd_mmc_check(self.slot) };
+        freertos::until_timeout(CHECK_TIMEOUT_MS, || {
+            let ec = unsafe { asf_sd_mmc::sd_mmc_check(slot) };
 
-            if ec as u32 == asf_sd_mmc::SD_MMC_ERR_NO_CARD && !got_no_card {
-                got_no_card = true;
+            if ec as u32 == asf_sd_mmc::SD_MMC_ERR_NO_CARD
+                && !got_no_card.get()
+            {
+                got_no_card.set(true);
+                None
             } else if ec as u32 != asf_sd_mmc::SD_MMC_INIT_ONGOING {
-                return to_stdresult(ec);
+                Some(to_stdresult(ec))
+            } else {
+                None
             }
-        }
+        })?
     }
 
     /// Get card type. Must be initialized.
@@ -129,6 +219,46 @@ impl Sd {
         unsafe { asf_sd_mmc::sd_mmc_is_write_protected(self.slot) }
     }
 
+    /// Read the card identification register. Must be initialized.
+    pub fn cid(&mut self) -> Result<Cid, Error>
+    {
+        let mut reg = [0u8; 16];
+        to_stdresult(unsafe {
+            asf_sd_mmc::sd_mmc_get_cid(self.slot, reg.as_mut_ptr())
+        })?;
+        Ok(Cid::load(&reg))
+    }
+
+    /// Read the card-specific data register. Must be initialized.
+    pub fn csd(&mut self) -> Result<Csd, Error>
+    {
+        let mut reg = [0u8; 16];
+        to_stdresult(unsafe {
+            asf_sd_mmc::sd_mmc_get_csd(self.slot, reg.as_mut_ptr())
+        })?;
+        Ok(Csd::load(&reg))
+    }
+
+    /// Read the SD configuration register. Must be initialized; SD only.
+    pub fn scr(&mut self) -> Result<Scr, Error>
+    {
+        let mut reg = [0u8; 8];
+        to_stdresult(unsafe {
+            asf_sd_mmc::sd_mmc_get_scr(self.slot, reg.as_mut_ptr())
+        })?;
+        Ok(Scr::load(&reg))
+    }
+
+    /// Read the SD Status register. Must be initialized; SD only.
+    pub fn ssr(&mut self) -> Result<Ssr, Error>
+    {
+        let mut reg = [0u8; 64];
+        to_stdresult(unsafe {
+            asf_sd_mmc::sd_mmc_get_ssr(self.slot, reg.as_mut_ptr())
+        })?;
+        Ok(Ssr::load(&reg))
+    }
+
     /// Read a block from the card. Blocks are 512B long. Must be
     /// initialized.
     pub fn read_block(
@@ -147,8 +277,23 @@ impl Sd {
         unsafe { self.write_blocks(iblock, 1, src.as_ptr()) }
     }
 
+    /// Get the transfer statistics.
+    pub fn stats(&self) -> SdStats
+    {
+        self.stats
+    }
+
+    /// Zero the transfer statistics.
+    pub fn reset_stats(&mut self)
+    {
+        self.stats = SdStats::new();
+    }
+
     /// Read an arbitrary number of blocks into a pointer. Unsafe, intended
-    /// for C interaction.
+    /// for C interaction. Communication errors are retried up to IO_TRIES
+    /// times, unless IO_RETRY_DEADLINE_MS has passed, which gives
+    /// ERR_TIMEOUT. So does a transfer that doesn't end in IO_TIMEOUT_MS per
+    /// block, which is not retried.
     pub unsafe fn read_blocks(
         &mut self,
         iblock: usize,
@@ -156,24 +301,17 @@ impl Sd {
         dest: *mut u8,
     ) -> StdResult
     {
-        to_stdresult(asf_sd_mmc::sd_mmc_init_read_blocks(
-            self.slot,
-            iblock as u32,
-            nblocks,
-        ))?;
-
-        to_stdresult(asf_sd_mmc::sd_mmc_start_read_blocks(
-            dest as *mut ctypes::c_void,
-            nblocks,
-        ))?;
-
-        to_stdresult(asf_sd_mmc::sd_mmc_wait_end_of_read_blocks(false))?;
+        let slot = self.slot;
 
+        self.stats.reads += 1;
+        self.retry(|| read_blocks_once(slot, iblock, nblocks, dest))?;
+        self.stats.blocks_read += nblocks as u64;
         Ok(())
     }
 
     /// Write an arbitrary number of blocks from a pointer. Unsafe, intended
-    /// for C interaction.
+    /// for C interaction. Returns ERR_EROFS if the card's write-protect
+    /// switch is set. Retries as for read_blocks().
     pub unsafe fn write_blocks(
         &mut self,
         iblock: usize,
@@ -181,21 +319,128 @@ impl Sd {
         src: *const u8,
     ) -> StdResult
     {
-        to_stdresult(asf_sd_mmc::sd_mmc_init_write_blocks(
-            self.slot,
-            iblock as u32,
-            nblocks,
-        ))?;
-
-        to_stdresult(asf_sd_mmc::sd_mmc_start_write_blocks(
-            src as *const ctypes::c_void,
-            nblocks,
-        ))?;
+        if self.writeprotected() {
+            return Err(ERR_EROFS);
+        }
 
-        to_stdresult(asf_sd_mmc::sd_mmc_wait_end_of_write_blocks(false))?;
+        let slot = self.slot;
 
+        self.stats.writes += 1;
+        self.retry(|| write_blocks_once(slot, iblock, nblocks, src))?;
+        self.stats.blocks_written += nblocks as u64;
         Ok(())
     }
+
+    /// Run a transfer, retrying communication errors, and keep count.
+    fn retry<F>(&mut self, f: F) -> StdResult
+    where
+        F: Fn() -> StdResult,
+    {
+        let start = freertos::ticks();
+        let mut tries = 0;
+
+        loop {
+            let mut err = match f() {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+            tries += 1;
+
+            if err == ERR_TIMEOUT {
+                // Abandoned halfway by wait_transfer()
+                unsafe {
+                    asf_sd_mmc::sd_mmc_stop_transfer(self.slot);
+                }
+                self.stats.timeouts += 1;
+            } else if err == ERR_SD_COMM {
+                // The transfer may have died halfway; get the card out of
+                // the data state before anything else is sent.
+                unsafe {
+                    asf_sd_mmc::sd_mmc_stop_transfer(self.slot);
+                }
+
+                let elapsed = freertos::ticks().wrapping_sub(start);
+
+                if elapsed >= IO_RETRY_DEADLINE_MS {
+                    self.stats.expired += 1;
+                    err = ERR_TIMEOUT;
+                } else if tries < IO_TRIES {
+                    self.stats.retries += 1;
+                    freertos::delay(IO_RETRY_DELAY_MS);
+                    continue;
+                }
+            }
+
+            self.stats.errors += 1;
+            self.stats.last_error = Some(err);
+            return Err(err);
+        }
+    }
+}
+
+unsafe fn read_blocks_once(
+    slot: u8,
+    iblock: usize,
+    nblocks: u16,
+    dest: *mut u8,
+) -> StdResult
+{
+    to_stdresult(asf_sd_mmc::sd_mmc_init_read_blocks(
+        slot,
+        iblock as u32,
+        nblocks,
+    ))?;
+
+    to_stdresult(asf_sd_mmc::sd_mmc_start_read_blocks(
+        dest as *mut ctypes::c_void,
+        nblocks,
+    ))?;
+
+    wait_transfer(nblocks)?;
+    to_stdresult(asf_sd_mmc::sd_mmc_wait_end_of_read_blocks(false))
+}
+
+unsafe fn write_blocks_once(
+    slot: u8,
+    iblock: usize,
+    nblocks: u16,
+    src: *const u8,
+) -> StdResult
+{
+    to_stdresult(asf_sd_mmc::sd_mmc_init_write_blocks(
+        slot,
+        iblock as u32,
+        nblocks,
+    ))?;
+
+    to_stdresult(asf_sd_mmc::sd_mmc_start_write_blocks(
+        src as *const ctypes::c_void,
+        nblocks,
+    ))?;
+
+    wait_transfer(nblocks)?;
+    to_stdresult(asf_sd_mmc::sd_mmc_wait_end_of_write_blocks(false))
+}
+
+/// Wait for the transfer just started to end, so that ASF's own wait, which
+/// has no time limit, returns at once. A transfer of `nblocks` that takes
+/// longer than IO_TIMEOUT_MS per block is abandoned, giving ERR_TIMEOUT; the
+/// card still has to be stopped.
+fn wait_transfer(nblocks: u16) -> StdResult
+{
+    let timeout = IO_TIMEOUT_MS * nblocks as u32;
+    let ended = freertos::until_timeout(timeout, || {
+        if unsafe { asf_sd_mmc::sd_mmc_transfer_ended() } {
+            Some(())
+        } else {
+            None
+        }
+    });
+
+    if ended.is_err() {
+        unsafe { asf_sd_mmc::sd_mmc_abort_transfer() };
+    }
+    ended
 }
 
 impl CardType {
@@ -228,3 +473,307 @@ impl CardVersion {
         }
     }
 }
+
+/// Extract `len` bits (at most 32) from a card register, stored most
+/// significant byte first. `lsb` is the bit number as given in the SD
+/// specification, counting from the least significant bit of the register.
+fn bits(reg: &[u8], lsb: usize, len: usize) -> u32
+{
+    let nbits = reg.len() * 8;
+    let mut value = 0u32;
+
+    for i in (lsb .. lsb + len).rev() {
+        let byte = reg[(nbits - 1 - i) / 8];
+        value = (value << 1) | ((byte >> (i % 8)) & 1) as u32;
+    }
+
+    value
+}
+
+/// Interpret a fixed-size ASCII field from a register, or "?" if it is not
+/// valid text.
+fn ascii(field: &[u8]) -> &str
+{
+    match str::from_utf8(field) {
+        Ok(s) => s.trim_right_matches(|c: char| c == '\0' || c == ' '),
+        Err(_) => "?",
+    }
+}
+
+/// Card identification register.
+#[derive(Copy, Clone, Debug)]
+pub struct Cid {
+    pub manufacturer: u8,
+    pub oem: [u8; 2],
+    pub product: [u8; 5],
+    pub revision: (u8, u8),
+    pub serial: u32,
+    pub year: u16,
+    pub month: u8,
+}
+
+impl Cid {
+    pub fn load(reg: &[u8; 16]) -> Cid
+    {
+        let prv = bits(reg, 56, 8) as u8;
+
+        Cid {
+            manufacturer: bits(reg, 120, 8) as u8,
+            oem: [reg[1], reg[2]],
+            product: [reg[3], reg[4], reg[5], reg[6], reg[7]],
+            revision: (prv >> 4, prv & 0xf),
+            serial: bits(reg, 24, 32),
+            year: 2000 + bits(reg, 12, 8) as u16,
+            month: bits(reg, 8, 4) as u8,
+        }
+    }
+
+    pub fn oem_str(&self) -> &str
+    {
+        ascii(&self.oem)
+    }
+
+    pub fn product_str(&self) -> &str
+    {
+        ascii(&self.product)
+    }
+
+    /// Name of the manufacturer. The SD Association doesn't publish the ID
+    /// assignments, so these are only the commonly known ones.
+    pub fn manufacturer_name(&self) -> Option<&'static str>
+    {
+        match self.manufacturer {
+            0x01 => Some("Panasonic"),
+            0x02 => Some("Toshiba"),
+            0x03 => Some("SanDisk"),
+            0x1b => Some("Samsung"),
+            0x1d => Some("ADATA"),
+            0x27 => Some("Phison"),
+            0x28 => Some("Lexar"),
+            0x31 => Some("Silicon Power"),
+            0x41 => Some("Kingston"),
+            0x74 => Some("Transcend"),
+            0x76 => Some("Patriot"),
+            0x82 => Some("Sony"),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Cid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self.manufacturer_name() {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "MID {:02x}", self.manufacturer)?,
+        }
+
+        write!(
+            f,
+            " {} rev {}.{}, serial {:08x}, {}-{:02}",
+            self.product_str(),
+            self.revision.0,
+            self.revision.1,
+            self.serial,
+            self.year,
+            self.month
+        )
+    }
+}
+
+/// Card-specific data register. Only the fields of interest are decoded;
+/// layout is as for SD cards (CSD versions 1.0 and 2.0).
+#[derive(Copy, Clone, Debug)]
+pub struct Csd {
+    pub structure: u8,
+    pub tran_speed: u8,
+    pub read_bl_len: u8,
+    pub write_bl_len: u8,
+    pub c_size: u32,
+    pub c_size_mult: u8,
+    pub erase_blk_en: bool,
+    pub sector_size: u8,
+    pub perm_write_protect: bool,
+    pub tmp_write_protect: bool,
+}
+
+impl Csd {
+    pub fn load(reg: &[u8; 16]) -> Csd
+    {
+        let structure = bits(reg, 126, 2) as u8;
+
+        Csd {
+            structure: structure,
+            tran_speed: bits(reg, 96, 8) as u8,
+            read_bl_len: bits(reg, 80, 4) as u8,
+            write_bl_len: bits(reg, 22, 4) as u8,
+            c_size: if structure == 0 {
+                bits(reg, 62, 12)
+            } else {
+                bits(reg, 48, 22)
+            },
+            c_size_mult: bits(reg, 47, 3) as u8,
+            erase_blk_en: bits(reg, 46, 1) != 0,
+            sector_size: bits(reg, 39, 7) as u8,
+            perm_write_protect: bits(reg, 13, 1) != 0,
+            tmp_write_protect: bits(reg, 12, 1) != 0,
+        }
+    }
+
+    /// Capacity in kB
+    pub fn capacity(&self) -> u64
+    {
+        if self.structure == 0 {
+            let shift = self.c_size_mult as u32 + 2 + self.read_bl_len as u32;
+            ((self.c_size as u64 + 1) << shift) / 1024
+        } else {
+            (self.c_size as u64 + 1) * 512
+        }
+    }
+
+    /// Maximum transfer rate in kbit/s
+    pub fn max_rate(&self) -> u32
+    {
+        const VALUES: [u32; 16] =
+            [0, 10, 12, 13, 15, 20, 25, 30, 35, 40, 45, 50, 55, 60, 70, 80];
+
+        let unit = (self.tran_speed & 0x7) as u32;
+        let value = VALUES[(self.tran_speed >> 3) as usize & 0xf];
+
+        if unit > 3 {
+            // Reserved
+            0
+        } else {
+            value * 10 * 10u32.pow(unit)
+        }
+    }
+
+    /// Smallest erasable unit in bytes
+    pub fn erase_size(&self) -> u32
+    {
+        if self.erase_blk_en {
+            512
+        } else {
+            (self.sector_size as u32 + 1) << self.write_bl_len
+        }
+    }
+}
+
+/// SD configuration register.
+#[derive(Copy, Clone, Debug)]
+pub struct Scr {
+    pub structure: u8,
+    pub sd_spec: u8,
+    pub sd_spec3: bool,
+    pub sd_spec4: bool,
+    pub sd_specx: u8,
+    pub data_after_erase: bool,
+    pub security: u8,
+    pub bus_widths: u8,
+}
+
+impl Scr {
+    pub fn load(reg: &[u8; 8]) -> Scr
+    {
+        Scr {
+            structure: bits(reg, 60, 4) as u8,
+            sd_spec: bits(reg, 56, 4) as u8,
+            data_after_erase: bits(reg, 55, 1) != 0,
+            security: bits(reg, 52, 3) as u8,
+            bus_widths: bits(reg, 48, 4) as u8,
+            sd_spec3: bits(reg, 47, 1) != 0,
+            sd_spec4: bits(reg, 42, 1) != 0,
+            sd_specx: bits(reg, 38, 4) as u8,
+        }
+    }
+
+    /// Physical layer specification version the card conforms to
+    pub fn spec_version(&self) -> &'static str
+    {
+        match (self.sd_spec, self.sd_spec3, self.sd_spec4, self.sd_specx) {
+            (0, _, _, _) => "1.0",
+            (1, _, _, _) => "1.10",
+            (2, false, _, _) => "2.00",
+            (2, true, false, 0) => "3.0x",
+            (2, true, true, 0) => "4.xx",
+            (2, true, _, 1) => "5.xx",
+            (2, true, _, 2) => "6.xx",
+            (2, true, _, 3) => "7.xx",
+            (2, true, _, 4) => "8.xx",
+            (2, true, _, 5) => "9.xx",
+            _ => "unknown",
+        }
+    }
+
+    pub fn security_str(&self) -> &'static str
+    {
+        match self.security {
+            0 => "none",
+            2 => "SDSC",
+            3 => "SDHC",
+            4 => "SDXC",
+            _ => "unknown",
+        }
+    }
+
+    pub fn supports_4bit(&self) -> bool
+    {
+        self.bus_widths & 0x4 != 0
+    }
+}
+
+/// SD Status register.
+#[derive(Copy, Clone, Debug)]
+pub struct Ssr {
+    pub bus_width: u8,
+    pub speed_class: u8,
+    pub uhs_grade: u8,
+    pub video_class: u8,
+    pub au_size: u8,
+    pub erase_size: u16,
+    pub erase_timeout: u8,
+    pub erase_offset: u8,
+}
+
+impl Ssr {
+    pub fn load(reg: &[u8; 64]) -> Ssr
+    {
+        Ssr {
+            bus_width: bits(reg, 510, 2) as u8,
+            speed_class: bits(reg, 440, 8) as u8,
+            au_size: bits(reg, 428, 4) as u8,
+            erase_size: bits(reg, 408, 16) as u16,
+            erase_timeout: bits(reg, 402, 6) as u8,
+            erase_offset: bits(reg, 400, 2) as u8,
+            uhs_grade: bits(reg, 396, 4) as u8,
+            video_class: bits(reg, 384, 8) as u8,
+        }
+    }
+
+    /// Speed class in MB/s (the number on the label), or None if the card
+    /// has no class or reports a reserved value.
+    pub fn speed_class_mbs(&self) -> Option<u8>
+    {
+        match self.speed_class {
+            1 => Some(2),
+            2 => Some(4),
+            3 => Some(6),
+            4 => Some(10),
+            _ => None,
+        }
+    }
+
+    /// Allocation unit size in kB, or None if not defined
+    pub fn au_size_kb(&self) -> Option<u32>
+    {
+        match self.au_size {
+            0 => None,
+            n @ 1 ... 0xa => Some(8 << n),
+            0xb => Some(12 * 1024),
+            0xc => Some(16 * 1024),
+            0xd => Some(24 * 1024),
+            0xe => Some(32 * 1024),
+            _ => Some(64 * 1024),
+        }
+    }
+}
@@ -38,6 +38,7 @@ extern crate ctypes;
 extern crate esh;
 extern crate lwext4;
 extern crate lwext4_crc32;
+extern crate lwext4_mkfs;
 
 extern crate bindgen_mcu;
 
@@ -16,16 +16,22 @@
 //
 
 use os;
-use drivers::{ext4, gpt, sdram};
+use os::Mutex;
+use drivers::{bootslot, ext4, gpt, mbr, part, sd, sdram};
+use drivers::com;
 use drivers::gpio::Gpio;
-use drivers::ftrans::FTrans;
+use drivers::ftrans::{self, FTrans};
 use devices;
-use data::{ParseInt, hexprint};
+use data::{DateTime, HumanSize, ParseInt, glob, hexprint, path};
+use data::io::{BufReader, Read};
 use devices::pins::*;
 use main::{reset, sysman};
+use main::main::{CONSOLES, console};
 use messages::*;
-use core::fmt;
+use core::{cmp, fmt, str};
 use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
 
 pub struct Command<'a> {
     pub name: &'a str,
@@ -59,20 +65,38 @@ pub static COMMAND_TABLE: &[Command] = &[
 
     Command{ name: "pwr_stat",  f: cmd_pwr_stat,    descr: "display status of SUPPLY" },
 
-    Command{ name: "mount",     f: cmd_mount,       descr: "mount SD card" },
+    Command{ name: "mount",     f: cmd_mount,       descr: "mount SD card [-r|-w] [-o remount,ro|rw]" },
     Command{ name: "umount",    f: cmd_umount,      descr: "unmount SD card" },
     Command{ name: "sync",      f: cmd_sync,        descr: "flush filesystem cache" },
-    Command{ name: "sdinfo",    f: cmd_sdinfo,      descr: "print SD card info" },
+    Command{ name: "flushcfg",  f: cmd_flushcfg,    descr: "set background flush [IDLE_MS DIRTY_BYTES]" },
+    Command{ name: "fslock",    f: cmd_fslock,      descr: "show filesystem lock state" },
+    Command{ name: "df",        f: cmd_df,          descr: "show filesystem usage [-h]" },
+    Command{ name: "mkfs",      f: cmd_mkfs,        descr: "format TARGET as TYPE [-b BSIZE] [-L LABEL] [-j|-J] confirm" },
+    Command{ name: "fsinfo",    f: cmd_fsinfo,      descr: "show filesystem superblock details" },
+    Command{ name: "sdinfo",    f: cmd_sdinfo,      descr: "print SD card info [-v]" },
+    Command{ name: "sdstat",    f: cmd_sdstat,      descr: "show SD transfer and error counts [-r to reset]" },
+    Command{ name: "sdbench",   f: cmd_sdbench,     descr: "measure card speed on TARGET [-w to include writes]" },
+    Command{ name: "sdscan",    f: cmd_sdscan,      descr: "read all of TARGET and report bad blocks" },
     Command{ name: "readblock", f: cmd_readblock,   descr: "read block N from card" },
     Command{ name: "writeblock",f: cmd_writeblock,  descr: "write to block N, DATA..." },
     Command{ name: "partinfo",  f: cmd_partinfo,    descr: "dump GPT partition info" },
-    Command{ name: "ls",        f: cmd_ls,          descr: "list PATH" },
+    Command{ name: "bootslot",  f: cmd_bootslot,    descr: "show boot slots [good|next N|set N PRIO TRIES OK]" },
+    Command{ name: "gpt",       f: cmd_gpt,         descr: "edit GPT: create|add|del|name|type|attr (gpt help)" },
+    Command{ name: "cd",        f: cmd_cd,          descr: "change working directory to PATH" },
+    Command{ name: "pwd",       f: cmd_pwd,         descr: "print working directory" },
+    Command{ name: "ls",        f: cmd_ls,          descr: "list PATH [-lahrSt]" },
+    Command{ name: "find",      f: cmd_find,        descr: "find under PATH [-name GLOB]" },
+    Command{ name: "du",        f: cmd_du,          descr: "disk usage and file size under PATH" },
+    Command{ name: "tree",      f: cmd_tree,        descr: "display directory tree at PATH" },
+    Command{ name: "cat",       f: cmd_cat,         descr: "print text file PATH" },
     Command{ name: "hd",        f: cmd_hd,          descr: "hexdump the first block of PATH" },
     Command{ name: "bitstream", f: cmd_bitstream,   descr: "load fpga N with PATH" },
     Command{ name: "readlink",  f: cmd_readlink,    descr: "readlink" },
     Command{ name: "rm",        f: cmd_rm,          descr: "delete PATH" },
     Command{ name: "expand",    f: cmd_expand,      descr: "expand PATH, following links" },
-    Command{ name: "ftrans",    f: cmd_ftrans,      descr: "open file transfer (requires USB)" },
+    Command{ name: "getfattr",  f: cmd_getfattr,    descr: "print extended attributes of PATH [NAME]" },
+    Command{ name: "setfattr",  f: cmd_setfattr,    descr: "set PATH NAME VALUE, or remove -x PATH NAME" },
+    Command{ name: "ftrans",    f: cmd_ftrans,      descr: "open file transfer on this console" },
 
     Command{ name: "sdram_init",f: cmd_sdram_init,  descr: "initialize sdram" },
     Command{ name: "peek",      f: cmd_peek,        descr: "read 32 bits at ADDR" },
@@ -475,33 +499,87 @@ fn cmd_pwr_stat(args: &[&str]) -> StdResult
     Ok(())
 }
 
-fn cmd_mount(_args: &[&str]) -> StdResult
+/// mount [-r] [-o ro|rw|remount,ro|remount,rw]
+fn cmd_mount(args: &[&str]) -> StdResult
 {
+    let mut read_only = None;
+    let mut remount = false;
+
+    let mut i = 1;
+    while i < args.len() {
+        match args[i] {
+            "-r" => read_only = Some(true),
+            "-w" => read_only = Some(false),
+            "-o" if i + 1 == args.len() => return Err(ERR_EXPECTED_ARGS),
+            "-o" => {
+                i += 1;
+                for opt in args[i].split(',') {
+                    match opt {
+                        "remount" => remount = true,
+                        "ro" => read_only = Some(true),
+                        "rw" => read_only = Some(false),
+                        _ => return Err(ERR_PARSE_ARGUMENT),
+                    }
+                }
+            },
+            _ => return Err(ERR_PARSE_ARGUMENT),
+        }
+        i += 1;
+    }
+
     if !CARD.get() {
         return Err(ERR_NO_CARD);
     }
 
+    if remount {
+        let read_only = read_only.ok_or(ERR_EXPECTED_ARGS)?;
+        if !read_only && devices::SD.lock().writeprotected() {
+            return Err(ERR_EROFS);
+        }
+        ext4::remount("/", read_only)?;
+        if !read_only {
+            recover_uploads();
+        }
+        return Ok(());
+    }
+
     CARDEN.set(true);
     os::delay(1);
     devices::SD.lock().check()?;
 
-    let mut table = gpt::Gpt::new(&devices::SD);
-    let mut entry = gpt::GptEntry::new();
-
-    table.read_header()?;
-    table.read_boot(&mut entry)?;
-
-    if !entry.valid() {
-        return Err(ERR_NO_BOOT_PART);
-    }
+    let protected = devices::SD.lock().writeprotected();
+    let read_only = match read_only {
+        Some(false) if protected => return Err(ERR_EROFS),
+        Some(ro) => ro,
+        None => {
+            if protected {
+                println!("card is write protected, mounting read-only");
+            }
+            protected
+        },
+    };
 
-    let bd = ext4::SdBlockDev::new(&devices::SD, &entry);
+    let partition = part::Table::read(&devices::SD)?.find_boot()?;
+    let bd = ext4::SdBlockDev::new(&devices::SD, &partition);
     ext4::register_device(bd, "root")?;
 
-    ext4::mount("root", "/", false)?;
+    ext4::mount("root", "/", read_only)?;
+
+    if !read_only {
+        recover_uploads();
+    }
     Ok(())
 }
 
+/// Clean up after interrupted uploads once "/" is writable. Failing to isn't
+/// reason enough to fail the mount.
+fn recover_uploads()
+{
+    if let Err(e) = ftrans::recover_uploads("/") {
+        println!("warning: cannot clean up uploads: {}", e);
+    }
+}
+
 fn cmd_umount(_args: &[&str]) -> StdResult
 {
     ext4::umount("/")?;
@@ -519,8 +597,225 @@ fn cmd_sync(_args: &[&str]) -> StdResult
     ext4::sync("/")
 }
 
-fn cmd_sdinfo(_args: &[&str]) -> StdResult
+fn cmd_df(args: &[&str]) -> StdResult
 {
+    let human = match args.len() {
+        1 => false,
+        2 if args[1] == "-h" => true,
+        2 => return Err(ERR_PARSE_ARGUMENT),
+        _ => return Err(ERR_TOO_MANY_ARGS),
+    };
+
+    let stats = ext4::statfs("/")?;
+
+    println!("{:>10} {:>10} {:>10} {:>4}  Mounted on", "Size", "Used",
+             "Avail", "Use%");
+    if human {
+        println!(
+            "{:>10} {:>10} {:>10} {:>3}%  /",
+            HumanSize(stats.size_bytes()),
+            HumanSize(stats.used_bytes()),
+            HumanSize(stats.free_bytes()),
+            stats.used_percent()
+        );
+    } else {
+        println!(
+            "{:>10} {:>10} {:>10} {:>3}%  /",
+            stats.size_bytes() / 1024,
+            stats.used_bytes() / 1024,
+            stats.free_bytes() / 1024,
+            stats.used_percent()
+        );
+    }
+    Ok(())
+}
+
+fn cmd_flushcfg(args: &[&str]) -> StdResult
+{
+    match args.len() {
+        1 => (),
+        3 => {
+            let idle_ms = argv_parsed(args, 1, "IDLE_MS", u32::parseint)?;
+            let threshold =
+                argv_parsed(args, 2, "DIRTY_BYTES", u32::parseint)? as usize;
+            ext4::set_flush_policy(idle_ms, threshold);
+        },
+        2 => return Err(ERR_EXPECTED_ARGS),
+        _ => return Err(ERR_TOO_MANY_ARGS),
+    }
+
+    let flush = ext4::flush_stats();
+    println!(
+        "flush after {} ms idle or {} dirty",
+        flush.idle_ms,
+        HumanSize(flush.threshold as u64)
+    );
+    Ok(())
+}
+
+fn cmd_fslock(_args: &[&str]) -> StdResult
+{
+    let stats = ext4::lock_stats();
+
+    match stats.owner {
+        Some(task) => {
+            println!(
+                "Owner:        {} (depth {})",
+                os::freertos::task_name(task),
+                stats.depth
+            )
+        },
+        None => println!("Owner:        none"),
+    }
+    println!("Acquisitions: {}", stats.acquisitions);
+    println!("Contentions:  {}", stats.contentions);
+    println!("Open handles: {}", stats.open_handles);
+    Ok(())
+}
+
+fn cmd_fsinfo(_args: &[&str]) -> StdResult
+{
+    let stats = ext4::statfs("/")?;
+
+    println!("Type:        {}", stats.fs_type());
+    println!("Label:       {}", stats.volume_name());
+    println!("UUID:        {}", stats.uuid);
+    println!("Block size:  {}", stats.block_size);
+    println!(
+        "Blocks:      {} total, {} free",
+        stats.blocks_count,
+        stats.free_blocks
+    );
+    println!(
+        "Inodes:      {} total, {} free",
+        stats.inodes_count,
+        stats.free_inodes
+    );
+    println!("Groups:      {}", stats.block_group_count);
+    println!("Mounts:      {}", stats.mount_count);
+    println!("Journal:     {}", if stats.journaled() { "yes" } else { "no" });
+    println!("Mode:        {}", if stats.read_only { "ro" } else { "rw" });
+    println!(
+        "State:       {}",
+        match stats.clean_at_mount() {
+            Some(true) => "clean at mount",
+            Some(false) => "not clean at mount",
+            None => "unknown",
+        }
+    );
+
+    print!("Features:   ");
+    for feature in stats.features() {
+        print!(" {}", feature);
+    }
+    println!("");
+
+    let flush = ext4::flush_stats();
+    println!("Unflushed:   {}", HumanSize(flush.dirty_bytes as u64));
+    println!(
+        "Flushes:     {} ({} errors), {} total",
+        flush.flushes,
+        flush.errors,
+        HumanSize(flush.bytes_flushed as u64)
+    );
+    if flush.flushes > 0 {
+        println!(
+            "Last flush:  {} ms ago, took {} ms",
+            os::ticks().wrapping_sub(flush.last_tick),
+            flush.last_ms
+        );
+    }
+
+    Ok(())
+}
+
+/// Format a partition. TARGET is "boot" for the GPT boot partition, a GPT
+/// entry index, or "disk" for the whole card. -j/-J turn the journal on/off
+/// (on by default for ext3/ext4). The final argument must be "confirm".
+fn cmd_mkfs(args: &[&str]) -> StdResult
+{
+    if args.len() < 3 {
+        return Err(ERR_EXPECTED_ARGS);
+    }
+
+    if args[args.len() - 1] != "confirm" {
+        return Err(ERR_NOT_CONFIRMED);
+    }
+
+    let target = args[1];
+    let fs_type = match ext4::FsType::from_name(args[2]) {
+        Some(t) => t,
+        None => return Err(ERR_PARSE_ARGUMENT),
+    };
+    let mut opts = ext4::MkfsOptions {
+        fs_type: fs_type,
+        block_size: 0,
+        label: "",
+        journal: fs_type != ext4::FsType::Ext2,
+    };
+
+    let opt_args = &args[3 .. args.len() - 1];
+    let mut i = 0;
+    while i < opt_args.len() {
+        match opt_args[i] {
+            "-j" => opts.journal = true,
+            "-J" => opts.journal = false,
+            "-b" | "-L" if i + 1 == opt_args.len() => {
+                return Err(ERR_EXPECTED_ARGS);
+            },
+            "-b" => {
+                i += 1;
+                opts.block_size =
+                    argv_parsed(opt_args, i, "BSIZE", u32::parseint)?;
+            },
+            "-L" => {
+                i += 1;
+                opts.label = opt_args[i];
+            },
+            _ => return Err(ERR_PARSE_ARGUMENT),
+        }
+        i += 1;
+    }
+
+    if opts.label.len() > 16 {
+        return Err(ERR_STRLEN);
+    }
+
+    if !CARD.get() {
+        return Err(ERR_NO_CARD);
+    }
+    CARDEN.set(true);
+    os::delay(1);
+    devices::SD.lock().check()?;
+
+    let bd = if target == "disk" {
+        ext4::SdBlockDev::whole(&devices::SD)
+    } else {
+        let mut table = part::Table::read(&devices::SD)?;
+
+        let partition = if target == "boot" {
+            table.find_boot()?
+        } else {
+            let i = argv_parsed(args, 1, "TARGET", u32::parseint)? as usize;
+            table.find(i)?
+        };
+        ext4::SdBlockDev::new(&devices::SD, &partition)
+    };
+
+    println!("formatting, this may take a while...");
+    ext4::mkfs(bd, &opts)?;
+    println!("done");
+    Ok(())
+}
+
+fn cmd_sdinfo(args: &[&str]) -> StdResult
+{
+    let verbose = match args.len() {
+        1 => false,
+        2 if args[1] == "-v" => true,
+        _ => return Err(ERR_PARSE_ARGUMENT),
+    };
+
     if !CARD.get() {
         return Err(ERR_NO_CARD);
     }
@@ -535,9 +830,385 @@ fn cmd_sdinfo(_args: &[&str]) -> StdResult
         if sd.writeprotected() { "yes" } else { "no" }
     );
 
+    if verbose {
+        sdinfo_registers(&mut sd)?;
+    }
+
+    Ok(())
+}
+
+fn sdinfo_registers(sd: &mut sd::Sd) -> StdResult
+{
+    let cid = sd.cid()?;
+    match cid.manufacturer_name() {
+        Some(name) => {
+            println!("Maker:     {} ({:02x})", name, cid.manufacturer)
+        },
+        None => println!("Maker:     {:02x}", cid.manufacturer),
+    }
+    println!("OEM:       {}", cid.oem_str());
+    println!("Product:   {}", cid.product_str());
+    println!("Revision:  {}.{}", cid.revision.0, cid.revision.1);
+    println!("Serial:    {:08x}", cid.serial);
+    println!("Date:      {}-{:02}", cid.year, cid.month);
+
+    let csd = sd.csd()?;
+    println!("CSD ver:   {}.0", csd.structure + 1);
+    println!("Max rate:  {} kbit/s", csd.max_rate());
+    println!("Erase blk: {} B", csd.erase_size());
+    if csd.perm_write_protect || csd.tmp_write_protect {
+        println!(
+            "Card WP:   {}",
+            if csd.perm_write_protect { "permanent" } else { "temporary" }
+        );
+    }
+
+    // SCR and SD Status don't exist on MMC
+    let scr = match sd.scr() {
+        Ok(scr) => scr,
+        Err(e) if e == ERR_SD_PARAM => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    println!("Spec:      {}", scr.spec_version());
+    println!("Security:  {}", scr.security_str());
+    println!("Bus width: {}", if scr.supports_4bit() { "1, 4" } else { "1" });
+    println!(
+        "Erased:    {}",
+        if scr.data_after_erase { "ones" } else { "zeros" }
+    );
+
+    let ssr = sd.ssr()?;
+    match ssr.speed_class_mbs() {
+        Some(c) => println!("Class:     {}", c),
+        None => println!("Class:     none"),
+    }
+    if ssr.uhs_grade != 0 {
+        println!("UHS grade: {}", ssr.uhs_grade);
+    }
+    if ssr.video_class != 0 {
+        println!("Video:     V{}", ssr.video_class);
+    }
+    match ssr.au_size_kb() {
+        Some(au) => println!("AU size:   {} kB", au),
+        None => println!("AU size:   undefined"),
+    }
+    if ssr.erase_size != 0 {
+        println!(
+            "Erase:     {} AU in {} s (+{} s)",
+            ssr.erase_size,
+            ssr.erase_timeout,
+            ssr.erase_offset
+        );
+    }
+
     Ok(())
 }
 
+fn cmd_sdstat(args: &[&str]) -> StdResult
+{
+    let reset = match args.len() {
+        1 => false,
+        2 if args[1] == "-r" => true,
+        _ => return Err(ERR_PARSE_ARGUMENT),
+    };
+
+    let mut sd = devices::SD.lock();
+    let stats = sd.stats();
+
+    println!(
+        "Read:      {} ops, {} blocks ({})",
+        stats.reads,
+        stats.blocks_read,
+        HumanSize(stats.blocks_read * 512)
+    );
+    println!(
+        "Written:   {} ops, {} blocks ({})",
+        stats.writes,
+        stats.blocks_written,
+        HumanSize(stats.blocks_written * 512)
+    );
+    println!("Retries:   {}", stats.retries);
+    println!("Expired:   {}", stats.expired);
+    println!("Timeouts:  {}", stats.timeouts);
+    println!("Errors:    {}", stats.errors);
+    if let Some(e) = stats.last_error {
+        println!("Last:      {}", e);
+    }
+
+    if reset {
+        sd.reset_stats();
+    }
+
+    Ok(())
+}
+
+/// Blocks per transfer for sdbench sequential tests and sdscan
+const SD_CHUNK_BLOCKS: usize = 32;
+
+/// Size of the sdbench scratch region at the start of the target, in blocks
+const BENCH_REGION_BLOCKS: usize = 16384;
+
+/// Blocks per sdbench random transfer (4 KiB)
+const BENCH_RANDOM_BLOCKS: usize = 8;
+
+/// Number of transfers in each sdbench random test
+const BENCH_RANDOM_OPS: usize = 256;
+
+/// Check for ^C from the console.
+fn interrupted() -> bool
+{
+    com::interrupted(&CONSOLES)
+}
+
+/// Power up the card and find the partition named by args[n]: "boot" for
+/// the boot partition, a partition number, or "disk" for the whole card.
+fn card_target(args: &[&str], n: usize) -> Result<part::Partition, Error>
+{
+    if !CARD.get() {
+        return Err(ERR_NO_CARD);
+    }
+    CARDEN.set(true);
+    os::delay(1);
+    devices::SD.lock().check()?;
+
+    if args[n] == "disk" {
+        return part::Partition::whole(&devices::SD);
+    }
+
+    let mut table = part::Table::read(&devices::SD)?;
+
+    if args[n] == "boot" {
+        table.find_boot()
+    } else {
+        let i = argv_parsed(args, n, "TARGET", u32::parseint)? as usize;
+        table.find(i)
+    }
+}
+
+/// Transfer throughput, displayed in MB/s
+struct Rate {
+    bytes: u64,
+    ms: u32,
+}
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        // Bytes per millisecond is kB/s
+        let kb_s = self.bytes / cmp::max(self.ms, 1) as u64;
+        write!(f, "{}.{:02} MB/s", kb_s / 1000, (kb_s % 1000) / 10)
+    }
+}
+
+fn xorshift32(state: &mut u32) -> u32
+{
+    *state ^= *state << 13;
+    *state ^= *state >> 17;
+    *state ^= *state << 5;
+    *state
+}
+
+/// Run one sdbench test over the scratch region, returning the bytes moved
+/// and the milliseconds it took. Writes put back the data already there, so
+/// each block is read first; only the write is timed.
+fn bench_run(
+    partition: &part::Partition,
+    buf: &mut [u8],
+    random: bool,
+    write: bool,
+) -> Result<(u64, u32), Error>
+{
+    let nblocks = buf.len() / 512;
+    let slots = cmp::min(partition.blocks(), BENCH_REGION_BLOCKS) / nblocks;
+    let ops = if random { BENCH_RANDOM_OPS } else { slots };
+    let mut rng = os::ticks() | 1;
+    let mut ms = 0u32;
+
+    for i in 0 .. ops {
+        if interrupted() {
+            return Err(ERR_INTERRUPTED);
+        }
+
+        let slot = if random {
+            xorshift32(&mut rng) as usize % slots
+        } else {
+            i
+        };
+        let lba = partition.start_lba + slot * nblocks;
+        let mut sd = devices::SD.lock();
+
+        unsafe {
+            if write {
+                sd.read_blocks(lba, nblocks as u16, buf.as_mut_ptr())?;
+            }
+
+            let start = os::ticks();
+            if write {
+                sd.write_blocks(lba, nblocks as u16, buf.as_ptr())?;
+            } else {
+                sd.read_blocks(lba, nblocks as u16, buf.as_mut_ptr())?;
+            }
+            ms += os::ticks().wrapping_sub(start);
+        }
+    }
+
+    Ok(((ops * nblocks * 512) as u64, ms))
+}
+
+/// Measure card throughput over the first BENCH_REGION_BLOCKS of a target
+/// (see card_target()). The write tests, enabled by -w, rewrite the data
+/// already on the card, but are refused while a filesystem is mounted.
+fn cmd_sdbench(args: &[&str]) -> StdResult
+{
+    let write = match args.len() {
+        0 ... 1 => return Err(ERR_EXPECTED_ARGS),
+        2 => false,
+        3 if args[2] == "-w" => true,
+        _ => return Err(ERR_PARSE_ARGUMENT),
+    };
+
+    if write && ext4::device_registered() {
+        return Err(ERR_BUSY);
+    }
+
+    let partition = card_target(args, 1)?;
+
+    if partition.blocks() < SD_CHUNK_BLOCKS {
+        return Err(ERR_ARG_RANGE);
+    }
+
+    let mut buf = vec::from_elem(0u8, SD_CHUNK_BLOCKS * 512);
+    let tests = [
+        ("seq read:", false, false),
+        ("rand read:", true, false),
+        ("seq write:", false, true),
+        ("rand write:", true, true),
+    ];
+
+    for &(name, random, is_write) in tests.iter() {
+        if is_write && !write {
+            continue;
+        }
+
+        let len = if random {
+            BENCH_RANDOM_BLOCKS * 512
+        } else {
+            buf.len()
+        };
+        let (bytes, ms) =
+            bench_run(&partition, &mut buf[.. len], random, is_write)?;
+        let rate = Rate {
+            bytes: bytes,
+            ms: ms,
+        };
+
+        if random {
+            let iops = BENCH_RANDOM_OPS as u32 * 1000 / cmp::max(ms, 1);
+            println!("{:<12}{}, {} IOPS", name, rate, iops);
+        } else {
+            println!("{:<12}{}", name, rate);
+        }
+    }
+
+    Ok(())
+}
+
+/// Unreadable block ranges found by sdscan
+struct BadRanges {
+    start: Option<usize>,
+    error: Error,
+    blocks: usize,
+    ranges: usize,
+}
+
+impl BadRanges {
+    fn bad(&mut self, lba: usize, e: Error)
+    {
+        if self.start.is_none() {
+            self.start = Some(lba);
+            self.error = e;
+            self.ranges += 1;
+        }
+        self.blocks += 1;
+    }
+
+    /// End the current range, if any; `lba` is the first readable block.
+    fn close(&mut self, lba: usize)
+    {
+        if let Some(start) = self.start.take() {
+            println!("unreadable: {} - {} ({})", start, lba - 1, self.error);
+        }
+    }
+}
+
+/// Read every block of a target (see card_target()) and report the ranges,
+/// by LBA, that could not be read.
+fn cmd_sdscan(args: &[&str]) -> StdResult
+{
+    if args.len() < 2 {
+        return Err(ERR_EXPECTED_ARGS);
+    } else if args.len() > 2 {
+        return Err(ERR_TOO_MANY_ARGS);
+    }
+
+    let partition = card_target(args, 1)?;
+    let total = partition.blocks();
+    let mut buf = vec::from_elem(0u8, SD_CHUNK_BLOCKS * 512);
+    let mut bad = BadRanges {
+        start: None,
+        error: ERR_UNKNOWN,
+        blocks: 0,
+        ranges: 0,
+    };
+    let mut result = Ok(());
+    let mut done = 0;
+    let mut next_report = 10;
+    let start = os::ticks();
+
+    while done < total {
+        if interrupted() {
+            result = Err(ERR_INTERRUPTED);
+            break;
+        }
+
+        let lba = partition.start_lba + done;
+        let n = cmp::min(SD_CHUNK_BLOCKS, total - done);
+        let mut sd = devices::SD.lock();
+
+        if unsafe { sd.read_blocks(lba, n as u16, buf.as_mut_ptr()) }.is_ok() {
+            bad.close(lba);
+        } else {
+            // Narrow it down to the individual blocks
+            for i in lba .. lba + n {
+                match unsafe { sd.read_blocks(i, 1, buf.as_mut_ptr()) } {
+                    Ok(()) => bad.close(i),
+                    Err(e) => bad.bad(i, e),
+                }
+            }
+        }
+
+        done += n;
+
+        while done * 100 / total >= next_report {
+            println!("{}%", next_report);
+            next_report += 10;
+        }
+    }
+
+    bad.close(partition.start_lba + done);
+
+    println!(
+        "scanned {} of {} blocks in {} s, {} unreadable in {} range(s)",
+        done,
+        total,
+        os::ticks().wrapping_sub(start) / 1000,
+        bad.blocks,
+        bad.ranges
+    );
+
+    result
+}
+
 fn cmd_readblock(args: &[&str]) -> StdResult
 {
     if args.len() < 2 {
@@ -553,12 +1224,18 @@ fn cmd_readblock(args: &[&str]) -> StdResult
     Ok(())
 }
 
+/// Write raw bytes to a card block. Refused while a filesystem is mounted,
+/// which would not see the change and could write over it.
 fn cmd_writeblock(args: &[&str]) -> StdResult
 {
     if args.len() < 2 {
         return Err(ERR_EXPECTED_ARGS);
     }
 
+    if ext4::device_registered() {
+        return Err(ERR_BUSY);
+    }
+
     let iblock = argv_parsed(args, 1, "BLOCK", u32::parseint)? as usize;
 
     let mut buf = [0u8; 512];
@@ -573,12 +1250,22 @@ fn cmd_writeblock(args: &[&str]) -> StdResult
 
 fn cmd_partinfo(_args: &[&str]) -> StdResult
 {
-    let mut table = gpt::Gpt::new(&devices::SD);
-    let mut entry = gpt::GptEntry::new();
+    match part::Table::read(&devices::SD)? {
+        part::Table::Gpt(mut table) => partinfo_gpt(&mut table),
+        part::Table::Mbr(table) => partinfo_mbr(&table),
+    }
+}
 
-    table.read_header()?;
+fn partinfo_gpt(table: &mut gpt::Gpt) -> StdResult
+{
+    let mut entry = gpt::GptEntry::new();
 
+    println!("Scheme:    GPT");
     println!("Disk GUID: {}", table.guid());
+    match table.primary_error() {
+        None => println!("Table:     primary"),
+        Some(e) => println!("Table:     backup (primary: {})", e),
+    }
 
     for i in 0 .. table.number_entries() {
         table.read_entry(i, &mut entry)?;
@@ -594,34 +1281,463 @@ fn cmd_partinfo(_args: &[&str]) -> StdResult
             entry.start_lba,
             entry.end_lba
         );
-        println!("  Attributes:  {:08x}", entry.attributes);
+        println!("  Attributes:  {:016x}", entry.attributes);
         println!("  Name:        {}", entry.name());
     }
 
     Ok(())
 }
 
+fn partinfo_mbr(table: &mbr::Mbr) -> StdResult
+{
+    println!("Scheme:    MBR");
+    println!("Disk ID:   {:08x}", table.disk_id());
+
+    for entry in table.entries() {
+        println!("Partition {}:", entry.number);
+        println!("  Type:        {:02x}", entry.part_type);
+        println!(
+            "  Range:       {:08x}...{:08x}",
+            entry.start_lba,
+            entry.end_lba
+        );
+        println!("  Active:      {}", if entry.active { "yes" } else { "no" });
+    }
+
+    Ok(())
+}
+
+fn cmd_gpt(args: &[&str]) -> StdResult
+{
+    if args.len() < 2 || args[1] == "help" {
+        println!("gpt create confirm         new table on whole card");
+        println!("gpt add START END NAME [T] add partition, LBAs inclusive");
+        println!("gpt del N confirm          delete entry N");
+        println!("gpt name N NAME            rename entry N");
+        println!("gpt type N T               set type of entry N");
+        println!("gpt attr N ATTR            set attribute bits of entry N");
+        println!("T is a GUID or \"boot\"; the default is boot");
+        return Ok(());
+    }
+
+    if !CARD.get() {
+        return Err(ERR_NO_CARD);
+    }
+    CARDEN.set(true);
+    os::delay(1);
+    devices::SD.lock().check()?;
+
+    let sub = args[1];
+    let args = &args[1 ..];
+
+    if (sub == "create" || sub == "del") && args[args.len() - 1] != "confirm"
+    {
+        return Err(ERR_NOT_CONFIRMED);
+    }
+
+    // Every subcommand rewrites both tables and the protective MBR
+    if ext4::device_registered() {
+        return Err(ERR_BUSY);
+    }
+
+    if sub == "create" {
+        let mut table = gpt::GptWriter::create(&devices::SD)?;
+        table.write()?;
+        println!("Disk GUID: {}", table.guid());
+        println!(
+            "Usable:    {:08x}...{:08x}",
+            table.first_usable_lba(),
+            table.last_usable_lba()
+        );
+        return Ok(());
+    }
+
+    let mut table = gpt::GptWriter::open(&devices::SD)?;
+    let mut entry = gpt::GptEntry::new();
+
+    match sub {
+        "add" => {
+            if args.len() < 4 {
+                return Err(ERR_EXPECTED_ARGS);
+            } else if args.len() > 5 {
+                return Err(ERR_TOO_MANY_ARGS);
+            }
+
+            let start = argv_parsed(args, 1, "START", u32::parseint)?;
+            let end = argv_parsed(args, 2, "END", u32::parseint)?;
+
+            entry.start_lba = start as usize;
+            entry.end_lba = end as usize;
+            entry.set_name(args[3])?;
+            entry.type_guid = match args.get(4) {
+                Some(t) => parse_part_type(t)?,
+                None => gpt::BOOT_GUID,
+            };
+            entry.part_guid = gpt::Guid::generate();
+
+            let i = table.add_entry(&entry)?;
+            println!("Entry {}: {}", i, entry.part_guid);
+        },
+        "del" | "name" | "type" | "attr" => {
+            if args.len() < 3 {
+                return Err(ERR_EXPECTED_ARGS);
+            } else if args.len() > 3 {
+                return Err(ERR_TOO_MANY_ARGS);
+            }
+
+            let i = argv_parsed(args, 1, "N", u32::parseint)? as usize;
+            table.read_entry(i, &mut entry)?;
+            if !entry.valid() {
+                return Err(ERR_CANNOT_FIND);
+            }
+
+            match sub {
+                "del" => table.delete_entry(i)?,
+                "name" => entry.set_name(args[2])?,
+                "type" => entry.type_guid = parse_part_type(args[2])?,
+                _ => {
+                    let attr = argv_parsed(args, 2, "ATTR", u64::parseint)?;
+                    entry.attributes = attr;
+                },
+            }
+
+            if sub != "del" {
+                table.write_entry(i, &entry)?;
+            }
+        },
+        _ => return Err(ERR_PARSE_ARGUMENT),
+    }
+
+    table.write()
+}
+
+fn cmd_bootslot(args: &[&str]) -> StdResult
+{
+    if args.len() == 2 && args[1] == "good" {
+        sysman::report_healthy();
+        return Ok(());
+    }
+
+    if !CARD.get() {
+        return Err(ERR_NO_CARD);
+    }
+
+    match args.get(1) {
+        None => (),
+        Some(&"next") if args.len() == 3 => {
+            let i = argv_parsed(args, 2, "N", u32::parseint)? as usize;
+            bootslot::prioritize(&devices::SD, i)?;
+        },
+        Some(&"set") if args.len() == 6 => {
+            let i = argv_parsed(args, 2, "N", u32::parseint)? as usize;
+            let prio = argv_parsed(args, 3, "PRIO", u8::parseint)?;
+            let tries = argv_parsed(args, 4, "TRIES", u8::parseint)?;
+            let ok = argv_parsed(args, 5, "OK", u8::parseint)? != 0;
+
+            if prio > bootslot::MAX_PRIORITY || tries > bootslot::MAX_TRIES {
+                return Err(ERR_ARG_RANGE);
+            }
+
+            bootslot::update(&devices::SD, i, |s| {
+                s.priority = prio;
+                s.tries = tries;
+                s.successful = ok;
+            })?;
+        },
+        Some(&"next") | Some(&"set") => return Err(ERR_EXPECTED_ARGS),
+        Some(_) => return Err(ERR_PARSE_ARGUMENT),
+    }
+
+    let slots = bootslot::read_slots(&devices::SD)?;
+    let next = bootslot::choose(&slots, &[]).map(|s| s.index);
+
+    for slot in slots.iter() {
+        println!(
+            "Slot {}: prio {:2} tries {:2} {} {:<9}{:<5}{}",
+            slot.index,
+            slot.priority,
+            slot.tries,
+            if slot.successful { "ok " } else { "new" },
+            if sysman::boot_slot() == Some(slot.index) { "booted" } else { "" },
+            if next == Some(slot.index) { "next" } else { "" },
+            slot.name
+        );
+    }
+
+    Ok(())
+}
+
+/// Parse a partition type argument: a GUID, or "boot" for the type the
+/// firmware boots from.
+fn parse_part_type(s: &str) -> Result<gpt::Guid, Error>
+{
+    if s == "boot" {
+        Ok(gpt::BOOT_GUID)
+    } else {
+        gpt::Guid::parse(s)
+    }
+}
+
+/// Shell working directory, always absolute and normalized. None is the
+/// root.
+static CWD: Mutex<Option<String>> = Mutex::new(None);
+
+/// Resolve a command-line path against the working directory.
+fn abspath(p: &str) -> String
+{
+    match *CWD.lock() {
+        Some(ref cwd) => path::join(cwd, p),
+        None => path::join("/", p),
+    }
+}
+
+fn cmd_cd(args: &[&str]) -> StdResult
+{
+    let target = match args.len() {
+        1 => String::from("/"),
+        2 => abspath(args[1]),
+        _ => return Err(ERR_TOO_MANY_ARGS),
+    };
+
+    let stat = ext4::stat(&ext4::expand(&target)?)?;
+    if stat.inode_type() != ext4::InodeType::Dir {
+        return Err(ERR_ENOTDIR);
+    }
+
+    *CWD.lock() = Some(target);
+    Ok(())
+}
+
+fn cmd_pwd(_args: &[&str]) -> StdResult
+{
+    match *CWD.lock() {
+        Some(ref cwd) => println!("{}", cwd),
+        None => println!("/"),
+    }
+    Ok(())
+}
+
+/// One line of `ls` output, collected so it can be sorted.
+struct LsEntry {
+    name: String,
+    path: String,
+    stat: ext4::Stat,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum LsSort {
+    Name,
+    Size,
+    Time,
+    Unsorted,
+}
+
 fn cmd_ls(args: &[&str]) -> StdResult
 {
-    let path = if args.len() == 2 { args[1] } else { "/" };
+    let mut arg = None;
+    let mut long = false;
+    let mut all = false;
+    let mut human = false;
+    let mut reverse = false;
+    let mut sort = LsSort::Name;
+
+    for &a in &args[1..] {
+        if a.len() > 1 && a.starts_with('-') {
+            for c in a[1..].chars() {
+                match c {
+                    'l' => long = true,
+                    'a' => all = true,
+                    'h' => human = true,
+                    'r' => reverse = true,
+                    'S' => sort = LsSort::Size,
+                    't' => sort = LsSort::Time,
+                    'U' => sort = LsSort::Unsorted,
+                    _ => return Err(ERR_PARSE_ARGUMENT),
+                }
+            }
+        } else if arg.is_some() {
+            return Err(ERR_TOO_MANY_ARGS);
+        } else {
+            arg = Some(a);
+        }
+    }
+
+    let arg = arg.unwrap_or(".");
+    let path = abspath(arg);
+    let path = &path;
+    let mut entries: Vec<LsEntry> = Vec::new();
+    let top = ext4::stat(path)?;
+
+    if top.inode_type() != ext4::InodeType::Dir {
+        entries.push(LsEntry {
+            name: String::from(arg),
+            path: String::from(path.as_str()),
+            stat: top,
+        });
+    } else {
+        if all {
+            let mut parent = String::from(path.trim_right_matches('/'));
+            parent.push_str("/..");
+            entries.push(LsEntry {
+                name: String::from("."),
+                path: String::from(path.as_str()),
+                stat: top,
+            });
+            entries.push(LsEntry {
+                name: String::from(".."),
+                stat: ext4::stat(&parent)?,
+                path: parent,
+            });
+        }
+
+        for entry in ext4::walk(path)?.max_depth(1) {
+            let entry = entry?;
+            if !all && entry.name().starts_with('.') {
+                continue;
+            }
+            entries.push(LsEntry {
+                name: String::from(entry.name()),
+                path: String::from(entry.path()),
+                stat: *entry.stat(),
+            });
+        }
+    }
+
+    match sort {
+        LsSort::Name => entries.sort_by(|a, b| a.name.cmp(&b.name)),
+        LsSort::Size => {
+            entries.sort_by(|a, b| b.stat.size().cmp(&a.stat.size()))
+        },
+        LsSort::Time => {
+            entries.sort_by(|a, b| b.stat.mtime().cmp(&a.stat.mtime()))
+        },
+        LsSort::Unsorted => (),
+    }
+    if reverse {
+        entries.reverse();
+    }
+
+    for entry in &entries {
+        let stat = &entry.stat;
+
+        if !long {
+            println!("{} {:8} {}", stat, stat.size(), entry.name);
+            continue;
+        }
+
+        print!("{} {:3} {:5} {:5} ", stat, stat.linkcount(), stat.uid(),
+               stat.gid());
+        if human {
+            print!("{:>5} ", HumanSize(stat.size()));
+        } else {
+            print!("{:10} ", stat.size());
+        }
+        print!("{} {}", DateTime::from_unix(stat.mtime().sec), entry.name);
+
+        if stat.inode_type() == ext4::InodeType::Symlink {
+            match ext4::readlink(&entry.path) {
+                Ok(target) => println!(" -> {}", target),
+                Err(_) => println!(" -> ?"),
+            }
+        } else {
+            println!("");
+        }
+    }
+    Ok(())
+}
+
+fn cmd_find(args: &[&str]) -> StdResult
+{
+    if args.len() < 2 {
+        return Err(ERR_EXPECTED_ARGS);
+    }
 
-    // Use a stringbuilder to append each item to the path, for stat()
-    let mut s = String::with_capacity(1024);
-    s.push_str(path);
-    let pab = path.as_bytes();
-    if pab[pab.len() - 1] != '/' as u8 {
-        s.push_str("/");
+    let pattern = match args.len() {
+        2 => None,
+        3 => return Err(ERR_EXPECTED_ARGS),
+        4 if args[2] == "-name" => Some(args[3]),
+        4 => return Err(ERR_PARSE_ARGUMENT),
+        _ => return Err(ERR_TOO_MANY_ARGS),
+    };
+
+    for entry in ext4::walk(&abspath(args[1]))? {
+        match entry {
+            Ok(entry) => {
+                if pattern.map_or(true, |p| glob::matches(p, entry.name())) {
+                    println!("{}", entry.path());
+                }
+            },
+            Err(e) => println!("find: {}", e),
+        }
     }
-    let only_path = s.len();
+    Ok(())
+}
 
-    let mut dir = ext4::dir_open(path)?;
-    for de in dir.iter() {
-        let name = de.name()?;
-        s.push_str(name);
-        let stat = ext4::stat(&s)?;
-        s.truncate(only_path);
+fn cmd_du(args: &[&str]) -> StdResult
+{
+    if args.len() < 2 {
+        return Err(ERR_EXPECTED_ARGS);
+    }
 
-        println!("{} {:8} {}", stat, stat.size(), name);
+    let path = abspath(args[1]);
+    let mut apparent = 0u64;
+    let mut used = ext4::stat(&path)?.blocks() * 512;
+    let mut nfiles = 0usize;
+
+    for entry in ext4::walk(&path)? {
+        match entry {
+            Ok(entry) => {
+                used += entry.stat().blocks() * 512;
+                if entry.stat().inode_type() == ext4::InodeType::File {
+                    apparent += entry.stat().size();
+                    nfiles += 1;
+                }
+            },
+            Err(e) => println!("du: {}", e),
+        }
+    }
+
+    println!(
+        "{} used, {} in {} files  {}",
+        HumanSize(used),
+        HumanSize(apparent),
+        nfiles,
+        args[1]
+    );
+    Ok(())
+}
+
+fn cmd_tree(args: &[&str]) -> StdResult
+{
+    if args.len() < 2 {
+        return Err(ERR_EXPECTED_ARGS);
+    }
+
+    println!("{}", args[1]);
+
+    for entry in ext4::walk(&abspath(args[1]))? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                println!("tree: {}", e);
+                continue;
+            },
+        };
+
+        for _ in 0 .. entry.depth() {
+            print!("  ");
+        }
+
+        match entry.stat().inode_type() {
+            ext4::InodeType::Dir => println!("{}/", entry.name()),
+            ext4::InodeType::Symlink => {
+                match ext4::readlink(entry.path()) {
+                    Ok(target) => println!("{} -> {}", entry.name(), target),
+                    Err(_) => println!("{} -> ?", entry.name()),
+                }
+            },
+            _ => println!("{}", entry.name()),
+        }
     }
     Ok(())
 }
@@ -632,9 +1748,9 @@ fn cmd_hd(args: &[&str]) -> StdResult
         return Err(ERR_EXPECTED_ARGS);
     }
 
-    let path = args[1];
+    let path = abspath(args[1]);
 
-    let mut file = ext4::fopen_expand(path, ext4::OpenFlags::Read)?;
+    let mut file = ext4::fopen_expand(&path, ext4::OpenFlags::Read)?;
     let mut buf = [0u8; 512];
 
     let bytes = file.read(&mut buf)?;
@@ -643,20 +1759,35 @@ fn cmd_hd(args: &[&str]) -> StdResult
     Ok(())
 }
 
+fn cmd_cat(args: &[&str]) -> StdResult
+{
+    if args.len() < 2 {
+        return Err(ERR_EXPECTED_ARGS);
+    }
+
+    let path = abspath(args[1]);
+    let file = ext4::fopen_expand(&path, ext4::OpenFlags::Read)?;
+
+    for line in BufReader::new(file).lines() {
+        println!("{}", line?);
+    }
+    Ok(())
+}
+
 fn cmd_bitstream(args: &[&str]) -> StdResult
 {
     if args.len() < 3 {
         return Err(ERR_EXPECTED_ARGS);
     }
 
-    let path = args[2];
+    let path = abspath(args[2]);
     let nfpga = argv_parsed(args, 1, "N", u32::parseint)? as usize;
 
     if nfpga >= devices::FPGAS.len() {
         return Err(ERR_ARG_RANGE);
     }
 
-    devices::FPGAS[nfpga].load(path)
+    devices::FPGAS[nfpga].load(&path)
 }
 
 fn cmd_readlink(args: &[&str]) -> StdResult
@@ -665,9 +1796,9 @@ fn cmd_readlink(args: &[&str]) -> StdResult
         return Err(ERR_EXPECTED_ARGS);
     }
 
-    let path = args[1];
+    let path = abspath(args[1]);
 
-    let link = ext4::readlink(path)?;
+    let link = ext4::readlink(&path)?;
 
     println!("{}", link);
     Ok(())
@@ -679,20 +1810,100 @@ fn cmd_rm(args: &[&str]) -> StdResult
         return Err(ERR_EXPECTED_ARGS);
     }
 
-    let path = args[1];
+    let path = abspath(args[1]);
 
-    ext4::unlink(path)
+    ext4::unlink(&path)
 }
 
-fn cmd_expand(args: &[&str]) -> StdResult
+/// Print an attribute value as text if it is printable ASCII, otherwise as
+/// hex with a 0x prefix (the form setfattr accepts).
+fn print_xattr(name: &str, value: &[u8])
+{
+    let text = value.len() > 0 && value.iter().all(|&c| c >= 0x20 && c < 0x7f);
+
+    if text {
+        let value = unsafe { str::from_utf8_unchecked(value) };
+        println!("{}=\"{}\"", name, value);
+    } else {
+        print!("{}=0x", name);
+        for c in value {
+            print!("{:02x}", c);
+        }
+        println!("");
+    }
+}
+
+/// Parse a setfattr value: 0x followed by hex digits is binary, anything
+/// else is taken as text.
+fn parse_xattr_value(s: &str) -> Result<Vec<u8>, Error>
+{
+    if !s.starts_with("0x") {
+        return Ok(Vec::from(s.as_bytes()));
+    }
+
+    let digits = &s.as_bytes()[2..];
+    if digits.len() % 2 != 0 {
+        return Err(ERR_PARSE_ARGUMENT);
+    }
+
+    let mut value = Vec::with_capacity(digits.len() / 2);
+    for pair in digits.chunks(2) {
+        let pair = unsafe { str::from_utf8_unchecked(pair) };
+        match u8::from_str_radix(pair, 16) {
+            Ok(c) => value.push(c),
+            Err(_) => return Err(ERR_PARSE_ARGUMENT),
+        }
+    }
+    Ok(value)
+}
+
+fn cmd_getfattr(args: &[&str]) -> StdResult
 {
     if args.len() < 2 {
         return Err(ERR_EXPECTED_ARGS);
+    } else if args.len() > 3 {
+        return Err(ERR_TOO_MANY_ARGS);
+    }
+
+    let path = abspath(args[1]);
+
+    if args.len() == 3 {
+        let value = ext4::getxattr(&path, args[2])?;
+        print_xattr(args[2], &value);
+    } else {
+        for name in ext4::listxattr(&path)? {
+            let value = ext4::getxattr(&path, &name)?;
+            print_xattr(&name, &value);
+        }
+    }
+    Ok(())
+}
+
+fn cmd_setfattr(args: &[&str]) -> StdResult
+{
+    if args.len() < 4 {
+        return Err(ERR_EXPECTED_ARGS);
+    } else if args.len() > 4 {
+        return Err(ERR_TOO_MANY_ARGS);
     }
 
-    let path = args[1];
+    if args[1] == "-x" {
+        let path = abspath(args[2]);
+        ext4::removexattr(&path, args[3])
+    } else {
+        let path = abspath(args[1]);
+        let value = parse_xattr_value(args[3])?;
+        ext4::setxattr(&path, args[2], &value)
+    }
+}
+
+fn cmd_expand(args: &[&str]) -> StdResult
+{
+    if args.len() < 2 {
+        return Err(ERR_EXPECTED_ARGS);
+    }
 
-    let path = ext4::expand(path)?;
+    let path = ext4::expand(&abspath(args[1]))?;
 
     println!("{}", path);
     Ok(())
@@ -700,7 +1911,7 @@ fn cmd_expand(args: &[&str]) -> StdResult
 
 fn cmd_ftrans(_args: &[&str]) -> StdResult
 {
-    let mut ftrans = FTrans::new();
+    let mut ftrans = FTrans::new(console());
     ftrans.run();
     Ok(())
 }
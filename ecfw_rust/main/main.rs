@@ -25,6 +25,14 @@ use bindgen_mcu;
 use os;
 
 use core::str;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Interfaces the shell reads commands from
+pub static CONSOLES: [&'static Com; 2] = [&devices::COMUSART, &devices::COMCDC];
+
+/// Index into CONSOLES of the one that last sent the shell input, which is
+/// the one the running command was typed on
+static CONSOLE: AtomicUsize = AtomicUsize::new(0);
 
 fn command_dispatch(_esh: &esh::Esh, args: &[&str])
 {
@@ -52,6 +60,13 @@ fn esh_print_cb(_esh: &esh::Esh, c: char)
     print!("{}", c);
 }
 
+/// Interface the running shell command was invoked from. Output still goes
+/// to all of them; this is for commands that take over the console.
+pub fn console() -> &'static Com
+{
+    CONSOLES[CONSOLE.load(Ordering::SeqCst)]
+}
+
 pub fn esh_task()
 {
     debug!(DEBUG_ECBOOT, "start debug console");
@@ -60,10 +75,9 @@ pub fn esh_task()
     esh.register_print(esh_print_cb);
     esh.rx(b'\n');
 
-    let com_ifs = [&devices::COMUSART as &Com, &devices::COMCDC as &Com];
-
     loop {
-        let c = drivers::com::getc_any_blocking(&com_ifs, true);
+        let (n, c) = drivers::com::getc_which_blocking(&CONSOLES, true);
+        CONSOLE.store(n, Ordering::SeqCst);
         let c_replaced = if c == b'\r' { b'\n' } else { c };
         esh.rx(c_replaced);
     }
@@ -133,6 +147,7 @@ pub fn init_task()
 
     os::Task::new(sysman::run_event, "event", 1000, 0);
     os::Task::new(sysman::run_status, "status", 500, 0);
+    os::Task::new(sysman::run_flush, "flush", 1000, 0);
     os::yield_task(); // Let above tasks emit status messages
 
     // Don't run esh_task() as a task; we can't free heap, so if we just spin
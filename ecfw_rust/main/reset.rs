@@ -26,6 +26,12 @@ use devices;
 /// after a timeout.
 pub fn hard_reset()
 {
+    // Get cached filesystem writes onto the card while tasks still run
+    debug!(DEBUG_RESET, "flush filesystem");
+    if let Err(e) = drivers::ext4::sync_all() {
+        debug!(DEBUG_RESET, "flush failed: {}", e);
+    }
+
     // Grab locks before suspending tasks. This allows any pending
     // transactions to complete, avoiding putting the VRM's I2C slave
     // in an unknown state.
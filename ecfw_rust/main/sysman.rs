@@ -19,22 +19,35 @@ use os;
 use drivers;
 use devices;
 use drivers::gpio::Gpio;
-use drivers::{ext4, gpt};
+use drivers::{bootslot, ext4, ftrans, part};
 use devices::pins::*;
 use devices::supplies::*;
 use main::reset;
 use messages::*;
 use core::sync::atomic::*;
+use alloc::vec::Vec;
 
 // Power button delays
 const POWER_BUTTON_START_CYCLES_MAX: u32 = 5; // <1s: start
 const POWER_BUTTON_STOP_CYCLES_MIN: u32 = 20; // >4s: stop
 
+// Time to wait for open files to be closed before unmounting at shutdown, ms
+const UMOUNT_WAIT_MS: u32 = 2000;
+
+// Background flush polling period, ms
+const FLUSH_POLL_MS: u32 = 250;
+
+// Card detect must read the same for this many cycles to count as a change
+const CARD_DEBOUNCE_CYCLES: u32 = 3;
+
 #[derive(Copy, Clone, Debug)]
 pub enum Event {
     Boot,
     Shutdown,
     Reboot,
+    CardInserted,
+    CardRemoved,
+    BootHealthy,
 }
 
 static POWER_STATE: AtomicUsize = ATOMIC_USIZE_INIT;
@@ -43,7 +56,11 @@ const STATE_SUSP: usize = 3;
 const STATE_OFF: usize = 5;
 const STATE_SHUTDOWN_FAIL: usize = 100;
 
-queue_static_new!(EVENTS: [Event; 2]);
+// GPT index of the boot slot the system was loaded from, plus one. Zero if
+// boot slots are not in use.
+static BOOT_SLOT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+queue_static_new!(EVENTS: [Event; 4]);
 
 /// Post an event. Returns immediately after the event is added to the queue.
 pub fn post(event: Event)
@@ -71,11 +88,25 @@ fn handle_one_event(evt: Event) -> StdResult
         Event::Boot => do_safe_boot()?,
         Event::Shutdown => do_safe_shutdown()?,
         Event::Reboot => do_reboot()?,
+        Event::CardInserted => do_card_inserted()?,
+        Event::CardRemoved => do_card_removed()?,
+        Event::BootHealthy => do_boot_healthy()?,
     }
 
     Ok(())
 }
 
+/// Background filesystem flush task
+pub fn run_flush()
+{
+    loop {
+        os::delay(FLUSH_POLL_MS);
+        if let Err(e) = ext4::flush_if_due() {
+            debug!(DEBUG_FS, "background flush failed: {}", e);
+        }
+    }
+}
+
 /// Supply/LED status indication struct. This pairs a power supply with the LEDs
 /// that indicate its status.
 #[derive(Copy, Clone)]
@@ -107,6 +138,8 @@ pub fn run_status()
     let mut powerbtn_handled = false;
     let mut lastwake = os::ticks_running();
     let mut cycle_count = 0;
+    let mut card_present = CARD.get();
+    let mut card_cycles_changed = 0u32;
 
     POWER_STATE.store(STATE_OFF, Ordering::SeqCst);
 
@@ -162,6 +195,22 @@ pub fn run_status()
             powerbtn_cycles_held = 0;
         }
 
+        // Handle card detect
+        if CARD.get() != card_present {
+            card_cycles_changed += 1;
+            if card_cycles_changed >= CARD_DEBOUNCE_CYCLES {
+                card_present = !card_present;
+                card_cycles_changed = 0;
+                post(if card_present {
+                    Event::CardInserted
+                } else {
+                    Event::CardRemoved
+                });
+            }
+        } else {
+            card_cycles_changed = 0;
+        }
+
         os::delay_period(&mut lastwake, 100);
         cycle_count = (cycle_count + 1) % 6;
     }
@@ -240,20 +289,7 @@ fn do_boot() -> StdResult
         return Ok(());
     }
 
-    if let Err(e) = boot_mount_card() {
-        if e == ERR_NO_CARD {
-            CARD_R.set_blink();
-        } else {
-            CARD_R.set(true);
-        }
-        CARD_G.set(false);
-        return Err(e);
-    } else {
-        CARD_G.set(true);
-        CARD_R.set(false);
-    }
-
-    boot_load_fpgas()?;
+    boot_from_card()?;
 
     unsafe {
         os::freertos::suspend_all();
@@ -267,7 +303,132 @@ fn do_boot() -> StdResult
     Ok(())
 }
 
-fn boot_mount_card() -> StdResult
+/// Report that the host came up healthy, so the boot slot it was loaded
+/// from is marked successful. Returns immediately.
+pub fn report_healthy()
+{
+    post(Event::BootHealthy);
+}
+
+/// GPT index of the boot slot the running system was loaded from, if boot
+/// slots are in use.
+pub fn boot_slot() -> Option<usize>
+{
+    match BOOT_SLOT.load(Ordering::SeqCst) {
+        0 => None,
+        n => Some(n - 1),
+    }
+}
+
+/// Mount the card and load the FPGAs from it. If the card has prioritized
+/// boot slots, a try is used up before each slot is attempted, and if it
+/// fails, the failure is recorded and the next best one is tried.
+fn boot_from_card() -> StdResult
+{
+    BOOT_SLOT.store(0, Ordering::SeqCst);
+
+    let slots = show_card_result(boot_read_slots())?;
+
+    if !bootslot::counted(&slots) {
+        show_card_result(boot_mount_card(None))?;
+        return boot_load_fpgas();
+    }
+
+    let mut tried = Vec::new();
+    let mut result = Err(ERR_NO_BOOT_PART);
+
+    while let Some(slot) = bootslot::choose(&slots, &tried) {
+        tried.push(slot.index);
+
+        result = boot_slot_attempt(&slot);
+        if result.is_ok() {
+            BOOT_SLOT.store(slot.index + 1, Ordering::SeqCst);
+            return Ok(());
+        }
+
+        debug!(DEBUG_SYSMAN, "boot slot {} failed", slot.index);
+        if let Err(e) = bootslot::mark_failed(&devices::SD, slot.index) {
+            debug!(DEBUG_SYSMAN, "cannot mark slot failed: {}", e);
+        }
+        boot_release_card();
+        reset_fpgas();
+    }
+
+    if tried.is_empty() {
+        show_card_result(result)
+    } else {
+        result
+    }
+}
+
+/// Power up the card and read its boot slots. MBR cards have none.
+fn boot_read_slots() -> Result<Vec<bootslot::Slot>, Error>
+{
+    boot_power_card()?;
+
+    match part::Table::read(&devices::SD)? {
+        part::Table::Gpt(mut gpt) => bootslot::read_slots_from(&mut gpt),
+        part::Table::Mbr(_) => Ok(Vec::new()),
+    }
+}
+
+/// Boot from one slot, using up one of its tries first.
+fn boot_slot_attempt(slot: &bootslot::Slot) -> StdResult
+{
+    debug!(
+        DEBUG_SYSMAN,
+        "boot slot {} \"{}\": priority {}, tries {}, successful {}",
+        slot.index,
+        slot.name,
+        slot.priority,
+        slot.tries,
+        slot.successful
+    );
+
+    if devices::SD.lock().writeprotected() {
+        debug!(DEBUG_SYSMAN, "card is write protected, attempt not counted");
+    } else {
+        bootslot::begin_attempt(&devices::SD, slot.index)?;
+    }
+
+    show_card_result(boot_mount_card(Some(slot.partition)))?;
+    boot_load_fpgas()
+}
+
+/// Show the result of mounting the card on the card LEDs.
+fn show_card_result<T>(result: Result<T, Error>) -> Result<T, Error>
+{
+    match result {
+        Err(e) => {
+            if e == ERR_NO_CARD {
+                CARD_R.set_blink();
+            } else {
+                CARD_R.set(true);
+            }
+            CARD_G.set(false);
+            Err(e)
+        },
+        Ok(v) => {
+            CARD_G.set(true);
+            CARD_R.set(false);
+            Ok(v)
+        },
+    }
+}
+
+/// Undo boot_mount_card() after a failed boot, so another slot can be
+/// mounted.
+fn boot_release_card()
+{
+    if let Err(_) = ext4::umount("/") {
+        debug!(DEBUG_SYSMAN, "card not mounted, ignore umount failure");
+    }
+    if let Err(_) = ext4::unregister_device("root") {
+        debug!(DEBUG_SYSMAN, "card not registered, ignore failure");
+    }
+}
+
+fn boot_power_card() -> StdResult
 {
     if !CARD.get() {
         return Err(ERR_NO_CARD);
@@ -275,19 +436,48 @@ fn boot_mount_card() -> StdResult
 
     CARDEN.set(true);
     os::delay(1);
-    devices::SD.lock().check()?;
 
-    let mut table = gpt::Gpt::new(&devices::SD);
-    let mut entry = gpt::GptEntry::new();
+    let mut sd = devices::SD.lock();
+    sd.check()?;
 
-    table.read_header()?;
-    table.read_boot(&mut entry)?;
-
-    if !entry.valid() {
-        return Err(ERR_NO_BOOT_PART);
+    match sd.cid() {
+        Ok(cid) => debug!(DEBUG_SYSMAN, "card: {}", cid),
+        Err(e) => debug!(DEBUG_SYSMAN, "cannot identify card: {}", e),
     }
 
-    let bd = ext4::SdBlockDev::new(&devices::SD, &entry);
+    Ok(())
+}
+
+/// Mount a partition of the card, or if None, the boot partition.
+fn boot_mount_card(partition: Option<part::Partition>) -> StdResult
+{
+    boot_power_card()?;
+
+    let partition = match partition {
+        Some(p) => p,
+        None => {
+            let mut table = part::Table::read(&devices::SD)?;
+
+            match table {
+                part::Table::Gpt(ref gpt) => {
+                    if let Some(e) = gpt.primary_error() {
+                        debug!(
+                            DEBUG_SYSMAN,
+                            "primary GPT bad ({}), using backup",
+                            e
+                        );
+                    }
+                },
+                part::Table::Mbr(_) => {
+                    debug!(DEBUG_SYSMAN, "no GPT, using MBR");
+                },
+            }
+
+            table.find_boot()?
+        },
+    };
+
+    let bd = ext4::SdBlockDev::new(&devices::SD, &partition);
 
     if let Err(e) = ext4::register_device(bd, "root") {
         if e == ERR_EEXIST {
@@ -298,11 +488,88 @@ fn boot_mount_card() -> StdResult
         }
     }
 
-    ext4::mount("root", "/", false)?;
+    let read_only = devices::SD.lock().writeprotected();
+    if read_only {
+        debug!(DEBUG_SYSMAN, "card is write protected, mounting read-only");
+    }
+
+    ext4::mount("root", "/", read_only)?;
+
+    // Settle uploads cut short by a reset before anything is loaded
+    if !read_only {
+        if let Err(e) = ftrans::recover_uploads("/") {
+            debug!(DEBUG_SYSMAN, "cannot clean up uploads: {}", e);
+        }
+    }
 
     Ok(())
 }
 
+fn do_card_inserted() -> StdResult
+{
+    debug!(DEBUG_SYSMAN, "card inserted");
+    CARD_R.set(false);
+
+    // Only mount automatically while running; boot mounts the card itself.
+    if POWER_STATE.load(Ordering::SeqCst) != STATE_RUN || DEBUG_BOOT.get() {
+        return Ok(());
+    }
+
+    if let Err(e) = boot_mount_card(None) {
+        CARD_R.set(true);
+        CARD_G.set(false);
+        Err(e)
+    } else {
+        CARD_G.set(true);
+        Ok(())
+    }
+}
+
+fn do_card_removed() -> StdResult
+{
+    debug!(DEBUG_SYSMAN, "card removed");
+    CARD_G.set(false);
+    BOOT_SLOT.store(0, Ordering::SeqCst);
+
+    if let Err(_) = ext4::force_umount("/") {
+        debug!(DEBUG_SYSMAN, "card not mounted, ignore umount failure");
+    }
+
+    // The device may be registered even if mounting it failed
+    match ext4::unregister_device("root") {
+        Err(e) if e != ERR_ENOENT => return Err(e),
+        _ => (),
+    }
+
+    CARDEN.set(false);
+
+    if POWER_STATE.load(Ordering::SeqCst) == STATE_RUN {
+        CARD_R.set_blink();
+    } else {
+        CARD_R.set(false);
+    }
+    Ok(())
+}
+
+fn do_boot_healthy() -> StdResult
+{
+    let index = match boot_slot() {
+        Some(i) => i,
+        None => {
+            debug!(DEBUG_SYSMAN, "host healthy, no boot slot in use");
+            return Ok(());
+        },
+    };
+
+    debug!(DEBUG_SYSMAN, "host healthy, boot slot {} successful", index);
+
+    if devices::SD.lock().writeprotected() {
+        debug!(DEBUG_SYSMAN, "card is write protected, not recorded");
+        return Ok(());
+    }
+    bootslot::mark_successful(&devices::SD, index)
+}
+
 fn boot_init_clock() -> StdResult
 {
     debug!(DEBUG_SYSMAN, "initialize clock synthesizer");
@@ -371,8 +638,12 @@ fn do_shutdown() -> StdResult
 
     reset_fpgas();
 
-    if let Err(_) = ext4::umount("/") {
-        debug!(DEBUG_SYSMAN, "card not mounted, ignore umount failure");
+    if let Err(e) = ext4::umount_wait("/", UMOUNT_WAIT_MS) {
+        if e == ERR_BUSY {
+            debug!(DEBUG_SYSMAN, "files still open, card left mounted");
+        } else {
+            debug!(DEBUG_SYSMAN, "card not mounted, ignore umount failure");
+        }
     } else {
         CARD_R.set(true);
         ext4::unregister_device("root")?;
@@ -465,8 +736,12 @@ fn recover_boot() -> StdResult
     debug!(DEBUG_SYSMAN, "reached S5");
     POWER_R.set(false);
 
-    if let Err(_) = ext4::umount("/") {
-        debug!(DEBUG_SYSMAN, "card not mounted, ignore umount failure");
+    if let Err(e) = ext4::umount_wait("/", UMOUNT_WAIT_MS) {
+        if e == ERR_BUSY {
+            debug!(DEBUG_SYSMAN, "files still open, card left mounted");
+        } else {
+            debug!(DEBUG_SYSMAN, "card not mounted, ignore umount failure");
+        }
     } else {
         CARD_R.set(true);
         ext4::unregister_device("root")?;
@@ -32,6 +32,7 @@ debug_table! {
     DEBUG_ECBOOT:       "ecboot",   true;
     DEBUG_RESET:        "reset",    true;
     DEBUG_FS:           "ext3fs",   true;
+    DEBUG_FLUSH:        "flush",    false;
     DEBUG_ALLOC:        "alloc",    false;
     DEBUG_CLOCK:        "clock",    true;
     DEBUG_SDRAM:        "sdram",    true;
@@ -46,10 +47,12 @@ error_table! {
     ERR_UTF16_ORPHAN:           "orphaned UTF-16 surrogate";
     ERR_CODEPOINT:              "invalid Unicode codepoint";
     ERR_BASE64:                 "invalid base64 data";
+    ERR_COBS:                   "invalid COBS data";
     ERR_STRLEN:                 "string too long";
     ERR_DIGIT:                  "digit invalid or out of range for radix";
     ERR_NRANGE:                 "number out of range";
     ERR_CKSUM:                  "invalid checksum";
+    ERR_GUID:                   "invalid GUID";
 
     // Command-related
     ERR_CANNOT_FIND:            "cannot find specified item";
@@ -58,6 +61,8 @@ error_table! {
     ERR_ARG_RANGE:              "argument out of range";
     ERR_PARSE_ARGUMENT:         "cannot parse argument";
     ERR_RESET_FAILED:           "did not reset";
+    ERR_NOT_CONFIRMED:          "confirmation required";
+    ERR_INTERRUPTED:            "interrupted";
 
     ///////////////////////////////////////////////////////////////////
     // Errno
@@ -80,13 +85,18 @@ error_table! {
     ERR_EMLINK:                 "too many links";
     ERR_ERANGE:                 "math result not representable";
     ERR_ENOTEMPTY:              "directory not empty";
+    ERR_ELOOP:                  "too many levels of symbolic links";
     ERR_ENODATA:                "no data available";
     ERR_ENOTSUP:                "not supported";
+    ERR_ETIMEDOUT:              "timed out";
 
     ///////////////////////////////////////////////////////////////////
     // General IO-related
     ERR_BUSY:                   "busy";
     ERR_TIMEOUT:                "timeout";
+    ERR_EOF:                    "unexpected end of file";
+    ERR_WRITE_ZERO:             "failed to write whole buffer";
+    ERR_FRAME:                  "malformed frame";
 
     ///////////////////////////////////////////////////////////////////
     // Disk-related
@@ -103,8 +113,21 @@ error_table! {
     ERR_GPT_SIGNATURE:          "GPT: invalid signature";
     ERR_GPT_ZEROLEN:            "GPT: zero entry length";
     ERR_GPT_SIZEMULT:           "GPT: block size must be multiple of entry length";
+    ERR_GPT_HEADER_SIZE:        "GPT: invalid header size";
+    ERR_GPT_HEADER_CRC:         "GPT: header checksum mismatch";
+    ERR_GPT_ARRAY_CRC:          "GPT: partition array checksum mismatch";
+    ERR_GPT_LBA:                "GPT: header location mismatch";
+    ERR_GPT_RANGE:              "GPT: partition outside usable space";
+    ERR_GPT_OVERLAP:            "GPT: partitions overlap";
+    ERR_GPT_FULL:               "GPT: no free entry";
+    ERR_MBR_SIGNATURE:          "MBR: invalid signature";
+    ERR_MBR_PROTECTIVE:         "MBR: protective MBR of a GPT disk";
+    ERR_MBR_EBR:                "MBR: invalid extended partition chain";
+    ERR_NO_PART_TABLE:          "no partition table found";
     ERR_NO_BOOT_PART:           "no boot parition found";
     ERR_FILE_NOT_OPEN:          "file not open";
+    ERR_NO_UPLOAD:              "no upload in progress";
+    ERR_UPLOAD_MISMATCH:        "upload does not match";
 
     ///////////////////////////////////////////////////////////////////
     // I2C-related
@@ -120,4 +143,5 @@ error_table! {
     // Oddly specific
     ERR_PLL_RANGE:              "PLL frequency out of range";
     ERR_CAS:                    "SDRAM: unsupported CAS latency";
+    ERR_FPGA_TARGET:            "bitstream is for a different FPGA";
 }
@@ -78,6 +78,7 @@ extern "C" {
     fn vTaskSuspendAll();
     fn xTaskResumeAll() -> usize;
     fn xTaskGetCurrentTaskHandle() -> TaskHandle;
+    fn pcTaskGetName(xTaskToQuery: TaskHandle) -> *const u8;
 
     // Notification functions
     fn xTaskGenericNotify(
@@ -253,6 +254,18 @@ pub fn this_task() -> TaskHandle
     unsafe { xTaskGetCurrentTaskHandle() }
 }
 
+/// Get the name of a task, or "?" if it is not valid UTF-8.
+pub fn task_name(task: TaskHandle) -> &'static str
+{
+    // Safe: FreeRTOS keeps a nul-terminated copy of the name in the task
+    // control block, and tasks are never deleted.
+    unsafe {
+        let name = pcTaskGetName(task);
+        str::from_utf8(slice::from_raw_parts(name, strlen(name)))
+            .unwrap_or("?")
+    }
+}
+
 /// Increment the notification counter of a task from within another task.
 pub fn notify_give(task: TaskHandle)
 {
@@ -20,6 +20,7 @@
 #[macro_use] mod queue;
 pub mod freertos;
 mod mutex;
+mod recursive_mutex;
 mod rwlock;
 mod stralloc;
 
@@ -28,6 +29,8 @@ pub use self::freertos::{Task, delay, delay_period, susp_safe_delay, ticks,
 
 pub use self::mutex::{Mutex, MutexLock};
 
+pub use self::recursive_mutex::RecursiveMutex;
+
 pub use self::queue::Queue;
 
 pub use self::rwlock::{RwLock, RwLockReader, RwLockWriter};
@@ -0,0 +1,95 @@
+// c4puter embedded controller firmware
+// Copyright (C) 2017 Chris Pavlina
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along
+// with this program; if not, write to the Free Software Foundation, Inc.,
+// 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+//
+
+//! FreeRTOS recursive mutex. Unlike os::Mutex, a task waiting for it blocks
+//! instead of polling, and the holder inherits the waiter's priority. It is
+//! created on first use, so it can be a static.
+
+#![allow(non_upper_case_globals)]
+
+use core::sync::atomic::*;
+use os::freertos;
+
+type QueueHandle = usize;
+
+const queueQUEUE_TYPE_RECURSIVE_MUTEX: u8 = 4;
+const portMAX_DELAY: u32 = 0xffff_ffff;
+const pdTRUE: i32 = 1;
+
+extern "C" {
+    fn xQueueCreateMutex(ucQueueType: u8) -> QueueHandle;
+    fn xQueueTakeMutexRecursive(xMutex: QueueHandle, xTicksToWait: u32)
+        -> i32;
+    fn xQueueGiveMutexRecursive(xMutex: QueueHandle) -> i32;
+}
+
+pub struct RecursiveMutex {
+    handle: AtomicUsize,
+}
+
+impl RecursiveMutex {
+    pub const fn new() -> RecursiveMutex
+    {
+        RecursiveMutex { handle: ATOMIC_USIZE_INIT }
+    }
+
+    fn handle(&self) -> QueueHandle
+    {
+        let handle = self.handle.load(Ordering::SeqCst);
+        if handle != 0 {
+            return handle;
+        }
+
+        // Two tasks could get here at once; only one may create it
+        unsafe { freertos::suspend_all() };
+        let mut handle = self.handle.load(Ordering::SeqCst);
+        if handle == 0 {
+            handle = unsafe {
+                xQueueCreateMutex(queueQUEUE_TYPE_RECURSIVE_MUTEX)
+            };
+            self.handle.store(handle, Ordering::SeqCst);
+        }
+        unsafe { freertos::resume_all() };
+
+        if handle == 0 {
+            panic!("cannot allocate mutex");
+        }
+        handle
+    }
+
+    /// Take the mutex, waiting up to `nticks` ticks for another task to
+    /// give it. Returns whether it was taken. A task that already holds
+    /// the mutex gets it again at once, and must give it as many times.
+    pub fn take_timeout(&self, nticks: u32) -> bool
+    {
+        unsafe { xQueueTakeMutexRecursive(self.handle(), nticks) == pdTRUE }
+    }
+
+    /// Take the mutex, waiting as long as it takes.
+    pub fn take(&self)
+    {
+        while !self.take_timeout(portMAX_DELAY) {}
+    }
+
+    /// Give the mutex back. Panics if this task doesn't hold it.
+    pub fn give(&self)
+    {
+        if unsafe { xQueueGiveMutexRecursive(self.handle()) } != pdTRUE {
+            panic!("mutex given by a task that doesn't hold it");
+        }
+    }
+}
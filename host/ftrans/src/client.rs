@@ -0,0 +1,1056 @@
+//! Protocol client. A session is started from the esh shell with the line
+//! protocol (`ftrans`, `hello`, `binary`); everything after that is binary
+//! frames.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use crate::crc32::crc32;
+use crate::frame::*;
+use crate::sha256::sha256;
+
+/// Oldest firmware protocol version with binary mode
+pub const MIN_VERSION: u32 = 4;
+
+/// Oldest firmware protocol version with OP_HASH. Transfers with older
+/// firmware aren't verified and can't be resumed.
+pub const HASH_VERSION: u32 = 5;
+
+/// Oldest firmware protocol version with OP_UPLOAD. With older firmware
+/// uploads are written over the target in place.
+pub const UPLOAD_VERSION: u32 = 6;
+
+/// Largest chunk we ask for, even if the firmware allows more
+const MAX_CHUNK: usize = 4096;
+
+/// Times a request is sent before giving up on it
+const TRIES: u32 = 5;
+
+/// Errors the firmware answers damaged frames with. Requests that get
+/// these are sent again.
+const RESEND_ERRORS: &[u16] = &[ERRC_CHECKSUM, ERRC_FRAME];
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// No reply in time, even after resending
+    Timeout,
+    /// The firmware said something we didn't expect
+    Protocol(String),
+    /// The firmware is too old for what was asked
+    TooOld { version: u32, need: u32 },
+    /// The file on the device doesn't match what was sent or received
+    Mismatch,
+    /// A request failed on the firmware side. `code` is one of the ERRC_*
+    /// constants.
+    Remote { code: u16, message: String },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Timeout => write!(f, "timed out waiting for the device"),
+            Error::Protocol(s) => write!(f, "protocol error: {}", s),
+            Error::TooOld { version, need } => write!(
+                f,
+                "firmware speaks protocol version {}, need {}",
+                version, need
+            ),
+            Error::Mismatch => write!(f, "file on the device doesn't match"),
+            Error::Remote { message, .. } => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error
+    {
+        Error::Io(e)
+    }
+}
+
+/// Whether a request that failed this way should be sent again.
+fn resendable(e: &Error) -> bool
+{
+    match e {
+        Error::Timeout => true,
+        Error::Remote { code, .. } => RESEND_ERRORS.contains(code),
+        _ => false,
+    }
+}
+
+fn protocol<T>(what: &str) -> Result<T>
+{
+    Err(Error::Protocol(what.to_string()))
+}
+
+/// Hash algorithms the firmware can compute over a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlg {
+    /// CRC-32, as four bytes big endian
+    Crc32,
+    Sha256,
+}
+
+impl HashAlg {
+    fn code(self) -> u8
+    {
+        match self {
+            HashAlg::Crc32 => HASH_CRC32,
+            HashAlg::Sha256 => HASH_SHA256,
+        }
+    }
+
+    /// Hash local data the way the firmware would.
+    pub fn digest(self, data: &[u8]) -> Vec<u8>
+    {
+        match self {
+            HashAlg::Crc32 => crc32(data).to_be_bytes().to_vec(),
+            HashAlg::Sha256 => sha256(data).to_vec(),
+        }
+    }
+}
+
+/// Inode type, as in firmware stat records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Dir,
+    Symlink,
+    Fifo,
+    Chardev,
+    Blockdev,
+    Socket,
+    Other,
+}
+
+impl FileType {
+    fn from_char(c: u8) -> FileType
+    {
+        match c {
+            b'f' => FileType::File,
+            b'd' => FileType::Dir,
+            b'l' => FileType::Symlink,
+            b'p' => FileType::Fifo,
+            b'c' => FileType::Chardev,
+            b'b' => FileType::Blockdev,
+            b's' => FileType::Socket,
+            _ => FileType::Other,
+        }
+    }
+
+    /// Single character used for the type in listings: one of fdlpcbs?
+    pub fn as_char(self) -> char
+    {
+        match self {
+            FileType::File => 'f',
+            FileType::Dir => 'd',
+            FileType::Symlink => 'l',
+            FileType::Fifo => 'p',
+            FileType::Chardev => 'c',
+            FileType::Blockdev => 'b',
+            FileType::Socket => 's',
+            FileType::Other => '?',
+        }
+    }
+}
+
+/// A stat record. `name` is empty for `stat`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub file_type: FileType,
+    pub mode: u16,
+    pub size: u64,
+    pub mtime: i64,
+    pub name: String,
+}
+
+impl Entry {
+    fn parse(record: &[u8]) -> Result<Entry>
+    {
+        if record.len() < STAT_LEN {
+            return protocol("short stat record");
+        }
+
+        Ok(Entry {
+            file_type: FileType::from_char(record[0]),
+            mode: get_le(&record[1 .. 3]) as u16,
+            size: get_le(&record[3 .. 11]),
+            mtime: get_le(&record[11 .. 19]) as i64,
+            name: String::from_utf8_lossy(&record[STAT_LEN ..]).into_owned(),
+        })
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(
+            f,
+            "{} {:04o} {:>10} {}",
+            self.file_type.as_char(),
+            self.mode,
+            self.size,
+            self.name
+        )
+    }
+}
+
+/// A request in flight during a pipelined transfer
+struct Pending {
+    index: usize,
+    op: u8,
+    sent: Instant,
+    tries: u32,
+    /// Sequence numbers of the copies sent before this one
+    earlier: Vec<u32>,
+}
+
+pub struct Client<P: Read + Write> {
+    port: P,
+    deframer: Deframer,
+    line: Vec<u8>,
+    next_seq: u32,
+    timeout: Duration,
+    version: u32,
+    chunk: usize,
+    messages: HashMap<u16, String>,
+}
+
+impl<P: Read + Write> Client<P> {
+    /// Connect over a port whose reads time out rather than block forever
+    /// (see `serial::open`). With `start_session`, `ftrans` is typed at the
+    /// shell first; otherwise the session must already be running in line
+    /// mode.
+    pub fn connect(port: P, start_session: bool) -> Result<Client<P>>
+    {
+        Client::connect_with_timeout(
+            port,
+            start_session,
+            Duration::from_secs(2),
+        )
+    }
+
+    /// As `connect`, with the time to wait for each reply before resending.
+    pub fn connect_with_timeout(
+        port: P,
+        start_session: bool,
+        timeout: Duration,
+    ) -> Result<Client<P>>
+    {
+        let mut client = Client {
+            port,
+            deframer: Deframer::new(),
+            line: Vec::new(),
+            next_seq: 1,
+            timeout,
+            version: 0,
+            chunk: MAX_CHUNK,
+            messages: HashMap::new(),
+        };
+
+        if start_session {
+            client.port.write_all(b"ftrans\r")?;
+        }
+
+        // Anything before the ack is the shell echoing us
+        client.port.write_all(b"hello\r")?;
+        let hello = client.expect_ack()?;
+        let version = hello
+            .split(' ')
+            .next()
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(|| Error::Protocol(format!("bad hello: {}", hello)))?;
+        if version < MIN_VERSION {
+            return Err(Error::TooOld {
+                version,
+                need: MIN_VERSION,
+            });
+        }
+
+        client.port.write_all(b"binary\r")?;
+        client.expect_ack()?;
+
+        let info = client.request(OP_HELLO, Vec::new())?;
+        if info.len() < 8 {
+            return protocol("short hello reply");
+        }
+        client.version = get_le(&info[0 .. 4]) as u32;
+        let max_chunk = get_le(&info[4 .. 8]) as usize;
+        client.chunk = max_chunk.min(MAX_CHUNK);
+        if client.chunk == 0 {
+            return protocol("firmware allows no data");
+        }
+
+        Ok(client)
+    }
+
+    /// Protocol version of the firmware.
+    pub fn version(&self) -> u32
+    {
+        self.version
+    }
+
+    /// Leave binary mode and the session, handing back the port.
+    pub fn close(mut self) -> Result<P>
+    {
+        self.request(OP_EXIT, Vec::new())?;
+        // ^D ends the line mode session too
+        self.port.write_all(&[4])?;
+        self.port.flush()?;
+        Ok(self.port)
+    }
+
+    /// Upload `data` to `path`, replacing whatever was there, and check
+    /// the result if the firmware can hash files. If the firmware has
+    /// OP_UPLOAD, `path` is only replaced once the whole file is there and
+    /// verified.
+    pub fn put(&mut self, path: &str, data: &[u8]) -> Result<()>
+    {
+        self.open_upload(path)?;
+        self.request(OP_TRUNCATE, 0u64.to_le_bytes().to_vec())?;
+        self.upload(path, data, 0)
+    }
+
+    /// As `put`, but if the upload on the device is the start of `data`
+    /// (an interrupted upload), only send the rest. Returns the number of
+    /// bytes that were already there.
+    pub fn put_resume(&mut self, path: &str, data: &[u8]) -> Result<u64>
+    {
+        self.need_version(HASH_VERSION)?;
+        self.open_upload(path)?;
+
+        // Cut off anything that doesn't match, including a longer tail
+        let start = self.matching_prefix(data)?;
+        self.request(OP_TRUNCATE, start.to_le_bytes().to_vec())?;
+        self.upload(path, data, start)?;
+        Ok(start)
+    }
+
+    /// Download `path`, checking the result if the firmware can hash files.
+    pub fn get(&mut self, path: &str) -> Result<Vec<u8>>
+    {
+        self.get_resume(path, Vec::new())
+    }
+
+    /// As `get`, but if `have` is the start of the file (an interrupted
+    /// download), only fetch the rest. `have` is returned completed.
+    pub fn get_resume(
+        &mut self,
+        path: &str,
+        have: Vec<u8>,
+    ) -> Result<Vec<u8>>
+    {
+        if !have.is_empty() {
+            self.need_version(HASH_VERSION)?;
+        }
+        self.request(OP_OPEN, path.as_bytes().to_vec())?;
+
+        let size = self.size()? as usize;
+        let start = self.matching_prefix(&have[.. have.len().min(size)])?;
+        let chunk = self.chunk;
+        let count = (size - start as usize).div_ceil(chunk);
+        let mut data = have;
+        data.resize(size, 0);
+
+        self.pipeline(
+            count,
+            |i| {
+                let offset = start + (i * chunk) as u64;
+                let mut payload = Vec::with_capacity(12);
+                payload.extend_from_slice(&offset.to_le_bytes());
+                payload.extend_from_slice(&(chunk as u32).to_le_bytes());
+                (OP_READ, payload)
+            },
+            |i, reply| {
+                let begin = start as usize + i * chunk;
+                let end = (begin + chunk).min(size);
+                if reply.len() != end - begin {
+                    return protocol("file changed size during download");
+                }
+                data[begin .. end].copy_from_slice(&reply);
+                Ok(())
+            },
+        )?;
+
+        self.verify(&data)?;
+        self.close_open()?;
+        Ok(data)
+    }
+
+    /// Hash `len` bytes of `path` from `offset` on the device (fewer if the
+    /// file ends first; `u64::MAX` for all of it). Returns the number of
+    /// bytes hashed and the digest.
+    pub fn hash(
+        &mut self,
+        path: &str,
+        alg: HashAlg,
+        offset: u64,
+        len: u64,
+    ) -> Result<(u64, Vec<u8>)>
+    {
+        self.need_version(HASH_VERSION)?;
+        self.request(OP_OPEN, path.as_bytes().to_vec())?;
+        let result = self.hash_open(alg, offset, len)?;
+        self.close_open()?;
+        Ok(result)
+    }
+
+    /// Send `data` from `start` on to the open file, which must already
+    /// hold `data[.. start]`, then check and close it, putting it in place
+    /// at `path` if it is an upload's temporary.
+    fn upload(&mut self, path: &str, data: &[u8], start: u64) -> Result<()>
+    {
+        let chunk = self.chunk;
+        let start = start as usize;
+        let count = (data.len() - start).div_ceil(chunk);
+        self.pipeline(
+            count,
+            |i| {
+                let begin = start + i * chunk;
+                let end = (begin + chunk).min(data.len());
+                let mut payload = Vec::with_capacity(8 + end - begin);
+                payload.extend_from_slice(&(begin as u64).to_le_bytes());
+                payload.extend_from_slice(&data[begin .. end]);
+                (OP_WRITE, payload)
+            },
+            |_, _| Ok(()),
+        )?;
+
+        // Held writes that never met the end of the file are lost silently
+        let size = self.size()?;
+        if size != data.len() as u64 {
+            return Err(Error::Protocol(format!(
+                "wrote {} bytes but file has {}",
+                data.len(),
+                size
+            )));
+        }
+
+        if self.version >= UPLOAD_VERSION {
+            // The firmware checks the hash itself, then syncs, closes and
+            // renames, which takes a while for a large file. A repeat of a
+            // commit whose reply was lost finds no upload.
+            let payload = sha256(data).to_vec();
+            let work = data.len() as u64;
+            return self
+                .slow(work, |client| {
+                    client.request_checked(
+                        OP_COMMIT,
+                        payload,
+                        &[ERRC_NO_UPLOAD],
+                        |client| client.holds(path, data),
+                    )
+                })
+                .map(|_| ())
+                .map_err(|e| match e {
+                    Error::Remote {
+                        code: ERRC_UPLOAD_MISMATCH,
+                        ..
+                    } => Error::Mismatch,
+                    e => e,
+                });
+        }
+
+        self.verify(data)?;
+        self.request(OP_SYNC, Vec::new())?;
+        self.close_open()
+    }
+
+    /// Close the open file. A repeat of a close whose reply was lost finds
+    /// nothing open, which is just as good.
+    fn close_open(&mut self) -> Result<()>
+    {
+        self.request_checked(
+            OP_CLOSE,
+            Vec::new(),
+            &[ERRC_FILE_NOT_OPEN],
+            |_| Ok(true),
+        )?;
+        Ok(())
+    }
+
+    /// Whether `path` on the device holds exactly `data`. Checks the size
+    /// first, as opening a missing file would create it.
+    fn holds(&mut self, path: &str, data: &[u8]) -> Result<bool>
+    {
+        match self.stat(path) {
+            Ok(entry) if entry.size == data.len() as u64 => {},
+            Ok(_) => return Ok(false),
+            Err(Error::Remote {
+                code: ERRC_NOT_FOUND,
+                ..
+            }) => return Ok(false),
+            Err(e) => return Err(e),
+        }
+
+        let (hashed, digest) =
+            self.hash(path, HashAlg::Sha256, 0, u64::MAX)?;
+        Ok(hashed == data.len() as u64 && digest == sha256(data))
+    }
+
+    /// Open `path` for writing, through a temporary if the firmware can.
+    fn open_upload(&mut self, path: &str) -> Result<()>
+    {
+        let op = if self.version >= UPLOAD_VERSION {
+            OP_UPLOAD
+        } else {
+            OP_OPEN
+        };
+        self.request(op, path.as_bytes().to_vec())?;
+        Ok(())
+    }
+
+    /// How much of `data` the open file already starts with: all of `data`
+    /// or the whole file if the hashes match, else nothing.
+    fn matching_prefix(&mut self, data: &[u8]) -> Result<u64>
+    {
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        let len = data.len() as u64;
+        let (hashed, digest) = self.hash_open(HashAlg::Sha256, 0, len)?;
+        if digest != sha256(&data[.. hashed as usize]) {
+            return Ok(0);
+        }
+        Ok(hashed)
+    }
+
+    /// Check the open file against `data`, if the firmware can.
+    fn verify(&mut self, data: &[u8]) -> Result<()>
+    {
+        if self.version < HASH_VERSION {
+            return Ok(());
+        }
+
+        let (hashed, digest) =
+            self.hash_open(HashAlg::Sha256, 0, u64::MAX)?;
+        if hashed != data.len() as u64 || digest != sha256(data) {
+            return Err(Error::Mismatch);
+        }
+        Ok(())
+    }
+
+    fn hash_open(
+        &mut self,
+        alg: HashAlg,
+        offset: u64,
+        len: u64,
+    ) -> Result<(u64, Vec<u8>)>
+    {
+        let mut payload = vec![alg.code()];
+        payload.extend_from_slice(&offset.to_le_bytes());
+        payload.extend_from_slice(&len.to_le_bytes());
+
+        // The firmware reads the whole range before replying
+        let size = self.size()?;
+        let work = size.saturating_sub(offset).min(len);
+        let reply = self.request_slow(OP_HASH, payload, work)?;
+        if reply.len() < 8 {
+            return protocol("short hash reply");
+        }
+        Ok((get_le(&reply[0 .. 8]), reply[8 ..].to_vec()))
+    }
+
+    /// As `request`, for one that makes the firmware read `work` bytes
+    /// before replying: allow it time in proportion, at least 64 kB/s.
+    fn request_slow(
+        &mut self,
+        op: u8,
+        payload: Vec<u8>,
+        work: u64,
+    ) -> Result<Vec<u8>>
+    {
+        self.slow(work, |client| client.request(op, payload))
+    }
+
+    /// Run `f` with the reply timeout stretched for `work` bytes, as for
+    /// `request_slow`.
+    fn slow<T, F>(&mut self, work: u64, f: F) -> T
+    where
+        F: FnOnce(&mut Self) -> T,
+    {
+        let saved = self.timeout;
+        self.timeout += Duration::from_millis(work / 64);
+        let result = f(self);
+        self.timeout = saved;
+        result
+    }
+
+    fn need_version(&self, need: u32) -> Result<()>
+    {
+        if self.version < need {
+            return Err(Error::TooOld {
+                version: self.version,
+                need,
+            });
+        }
+        Ok(())
+    }
+
+    /// List a directory.
+    pub fn list(&mut self, path: &str) -> Result<Vec<Entry>>
+    {
+        let mut entries = Vec::new();
+        let mut tries = 1;
+        let mut seq = self.send(OP_LIST, path.as_bytes());
+
+        // One reply per entry, then an empty one. The request can be resent
+        // until the first entry arrives; after that, entries would come
+        // twice.
+        loop {
+            let reply = match self.recv_for(seq, Instant::now())? {
+                Some(frame) => self.check_reply(OP_LIST, frame),
+                None => Err(Error::Timeout),
+            };
+
+            match reply {
+                Ok(ref payload) if payload.is_empty() => return Ok(entries),
+                Ok(payload) => entries.push(Entry::parse(&payload)?),
+                Err(ref e)
+                    if entries.is_empty() && tries < TRIES && resendable(e) =>
+                {
+                    tries += 1;
+                    seq = self.send(OP_LIST, path.as_bytes());
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn stat(&mut self, path: &str) -> Result<Entry>
+    {
+        Entry::parse(&self.request(OP_STAT, path.as_bytes().to_vec())?)
+    }
+
+    /// Make a directory. If the request had to be resent, finding the
+    /// directory already there counts as success.
+    pub fn mkdir(&mut self, path: &str) -> Result<()>
+    {
+        self.request_checked(
+            OP_MKDIR,
+            path.as_bytes().to_vec(),
+            &[ERRC_EXISTS],
+            |client| Ok(client.file_type(path)? == Some(FileType::Dir)),
+        )?;
+        Ok(())
+    }
+
+    /// Delete a file, or a directory if it is empty. If the request had to
+    /// be resent, finding `path` already gone counts as success.
+    pub fn remove(&mut self, path: &str) -> Result<()>
+    {
+        self.request_checked(
+            OP_REMOVE,
+            path.as_bytes().to_vec(),
+            &[ERRC_NOT_FOUND],
+            |client| Ok(client.file_type(path)?.is_none()),
+        )?;
+        Ok(())
+    }
+
+    /// Rename a file or directory; `new_path` must not exist. If the
+    /// request had to be resent, finding `old_path` gone and `new_path`
+    /// there counts as success.
+    pub fn rename(&mut self, old_path: &str, new_path: &str) -> Result<()>
+    {
+        let mut payload = old_path.as_bytes().to_vec();
+        payload.push(0);
+        payload.extend_from_slice(new_path.as_bytes());
+        self.request_checked(
+            OP_RENAME,
+            payload,
+            &[ERRC_NOT_FOUND, ERRC_EXISTS],
+            |client| {
+                Ok(client.file_type(old_path)?.is_none() &&
+                    client.file_type(new_path)?.is_some())
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Type of `path`, or None if there is nothing there.
+    fn file_type(&mut self, path: &str) -> Result<Option<FileType>>
+    {
+        match self.stat(path) {
+            Ok(entry) => Ok(Some(entry.file_type)),
+            Err(Error::Remote {
+                code: ERRC_NOT_FOUND,
+                ..
+            }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Flush the card's filesystem.
+    pub fn sync(&mut self) -> Result<()>
+    {
+        self.request(OP_SYNC, Vec::new())?;
+        Ok(())
+    }
+
+    /// Set the size of `path`, creating it if needed.
+    pub fn truncate(&mut self, path: &str, size: u64) -> Result<()>
+    {
+        self.request(OP_OPEN, path.as_bytes().to_vec())?;
+        self.request(OP_TRUNCATE, size.to_le_bytes().to_vec())?;
+        self.close_open()
+    }
+
+    fn size(&mut self) -> Result<u64>
+    {
+        let reply = self.request(OP_SIZE, Vec::new())?;
+        if reply.len() != 8 {
+            return protocol("bad size reply");
+        }
+        Ok(get_le(&reply))
+    }
+
+    /// Send one request and wait for its reply, resending if it is lost or
+    /// damaged.
+    fn request(&mut self, op: u8, payload: Vec<u8>) -> Result<Vec<u8>>
+    {
+        self.request_checked(op, payload, &[], |_| Ok(false))
+    }
+
+    /// As `request`, for one that can't simply be repeated. A late reply to
+    /// an earlier copy is taken as well as one to the latest. If the request
+    /// had to be resent and then fails with one of `codes`, an earlier copy
+    /// may have done the job and lost its reply: `check` then asks the
+    /// device, and the request succeeded, with an empty reply, if it says
+    /// so.
+    fn request_checked<C>(
+        &mut self,
+        op: u8,
+        payload: Vec<u8>,
+        codes: &[u16],
+        check: C,
+    ) -> Result<Vec<u8>>
+    where
+        C: FnOnce(&mut Self) -> Result<bool>,
+    {
+        let mut seqs = vec![self.send(op, &payload)];
+        let mut sent = Instant::now();
+
+        let err = loop {
+            let frame = match self.recv(sent + self.timeout)? {
+                Some(frame) if seqs.contains(&frame.seq) => frame,
+                Some(_) => continue,
+                None if seqs.len() < TRIES as usize => {
+                    seqs.push(self.send(op, &payload));
+                    sent = Instant::now();
+                    continue;
+                },
+                None => return Err(Error::Timeout),
+            };
+
+            let latest = frame.seq == seqs[seqs.len() - 1];
+            match self.check_reply(op, frame) {
+                Ok(reply) => return Ok(reply),
+                // A damaged earlier copy; the latest is still on its way
+                Err(ref e) if resendable(e) && !latest => {},
+                Err(ref e) if resendable(e) && seqs.len() < TRIES as usize => {
+                    seqs.push(self.send(op, &payload));
+                    sent = Instant::now();
+                },
+                Err(e) => break e,
+            }
+        };
+
+        match err {
+            Error::Remote { code, .. }
+                if seqs.len() > 1 && codes.contains(&code) =>
+            {
+                if check(self)? {
+                    Ok(Vec::new())
+                } else {
+                    Err(err)
+                }
+            },
+            err => Err(err),
+        }
+    }
+
+    /// Run `count` requests, keeping as many in flight as the firmware's
+    /// window allows. `make(i)` builds request `i`; `done(i, payload)` takes
+    /// its reply. Replies can come in any order.
+    fn pipeline<M, D>(
+        &mut self,
+        count: usize,
+        make: M,
+        mut done: D,
+    ) -> Result<()>
+    where
+        M: Fn(usize) -> (u8, Vec<u8>),
+        D: FnMut(usize, Vec<u8>) -> Result<()>,
+    {
+        // Keep one chunk spare, so a lost frame at the start of the window
+        // doesn't push the writes after it past what the firmware can hold
+        let window = (WINDOW_BYTES / self.chunk).saturating_sub(1).max(1);
+        let mut pending: BTreeMap<u32, Pending> = BTreeMap::new();
+        let mut next = 0;
+        let mut finished = 0;
+
+        while finished < count {
+            // The window runs from the oldest unanswered request, not the
+            // number in flight: writes past a lost one are answered but
+            // held by the firmware
+            let base = pending.values().map(|p| p.index).min().unwrap_or(next);
+            while next < base + window && next < count {
+                let (op, payload) = make(next);
+                let seq = self.send(op, &payload);
+                pending.insert(seq, Pending {
+                    index: next,
+                    op,
+                    sent: Instant::now(),
+                    tries: 1,
+                    earlier: Vec::new(),
+                });
+                next += 1;
+            }
+
+            let oldest = pending.values().map(|p| p.sent).min().unwrap();
+            let frame = match self.recv(oldest + self.timeout)? {
+                Some(frame) => frame,
+                None => {
+                    // Resend whatever has waited too long, under a new
+                    // sequence number; a reply to any copy will do
+                    let now = Instant::now();
+                    let stale: Vec<u32> = pending
+                        .iter()
+                        .filter(|(_, p)| now >= p.sent + self.timeout)
+                        .map(|(&seq, _)| seq)
+                        .collect();
+                    for seq in stale {
+                        self.resend(&mut pending, seq, &make)?;
+                    }
+                    continue;
+                },
+            };
+
+            // A late reply to an earlier copy of a request does as well as
+            // one to the latest
+            let found = pending.iter().find(|(&seq, p)| {
+                seq == frame.seq || p.earlier.contains(&frame.seq)
+            });
+            let (seq, index, op) = match found {
+                Some((&seq, p)) => (seq, p.index, p.op),
+                None => continue,
+            };
+
+            if frame.op == OP_ERROR {
+                let err = self.remote_error(&frame.payload)?;
+                if !resendable(&err) {
+                    return Err(err);
+                }
+                // Unless it is about an earlier copy, already resent
+                if frame.seq == seq {
+                    self.resend(&mut pending, seq, &make)?;
+                }
+                continue;
+            }
+
+            let payload = self.check_reply(op, frame)?;
+            pending.remove(&seq);
+            done(index, payload)?;
+            finished += 1;
+        }
+
+        Ok(())
+    }
+
+    fn resend<M>(
+        &mut self,
+        pending: &mut BTreeMap<u32, Pending>,
+        seq: u32,
+        make: &M,
+    ) -> Result<()>
+    where
+        M: Fn(usize) -> (u8, Vec<u8>),
+    {
+        let mut old = pending.remove(&seq).unwrap();
+        if old.tries >= TRIES {
+            return Err(Error::Timeout);
+        }
+
+        let (op, payload) = make(old.index);
+        old.earlier.push(seq);
+        let seq = self.send(op, &payload);
+        pending.insert(seq, Pending {
+            index: old.index,
+            op,
+            sent: Instant::now(),
+            tries: old.tries + 1,
+            earlier: old.earlier,
+        });
+        Ok(())
+    }
+
+    /// Check that a frame is a successful reply to `op`.
+    fn check_reply(&mut self, op: u8, frame: Frame) -> Result<Vec<u8>>
+    {
+        if frame.op == OP_ERROR {
+            return Err(self.remote_error(&frame.payload)?);
+        }
+        if frame.op != op | OP_REPLY {
+            return Err(Error::Protocol(format!(
+                "reply opcode {:#04x} to request {:#04x}",
+                frame.op, op
+            )));
+        }
+        Ok(frame.payload)
+    }
+
+    /// Turn an OP_ERROR payload into an error. Since version 7 it has the
+    /// code and the message; before, it only had an index, and the firmware
+    /// is asked for the message the first time one is seen. This goes by
+    /// the payload, as errors can come before OP_HELLO has been answered.
+    fn remote_error(&mut self, payload: &[u8]) -> Result<Error>
+    {
+        if payload.len() < 2 {
+            return protocol("bad error reply");
+        }
+        let code = get_le(&payload[.. 2]) as u16;
+
+        if payload.len() > 2 {
+            let message = String::from_utf8_lossy(&payload[2 ..]);
+            return Ok(Error::Remote {
+                code,
+                message: message.into_owned(),
+            });
+        }
+
+        if !self.messages.contains_key(&code) {
+            for _ in 0 .. TRIES {
+                let seq = self.send(OP_ERRMSG, &code.to_le_bytes());
+                match self.recv_for(seq, Instant::now())? {
+                    Some(ref frame) if frame.op == OP_ERRMSG | OP_REPLY => {
+                        let message = String::from_utf8_lossy(&frame.payload);
+                        self.messages.insert(code, message.into_owned());
+                        break;
+                    },
+                    _ => continue,
+                }
+            }
+        }
+
+        let message = match self.messages.get(&code) {
+            Some(message) => message.clone(),
+            None => format!("error {}", code),
+        };
+        let code = ERROR_MESSAGES
+            .iter()
+            .find(|&&(_, m)| m == message)
+            .map_or(ERRC_OTHER, |&(c, _)| c);
+        Ok(Error::Remote { code, message })
+    }
+
+    fn send(&mut self, op: u8, payload: &[u8]) -> u32
+    {
+        let seq = self.next_seq;
+        // Sequence 0 is what the firmware uses when it can't read ours
+        self.next_seq = self.next_seq.wrapping_add(1).max(1);
+
+        let frame = Frame::new(op, seq, payload.to_vec()).encode();
+        // Write errors show up as timeouts
+        let _ = self.port.write_all(&frame).and_then(|_| self.port.flush());
+        seq
+    }
+
+    /// Wait for the reply to `seq`, ignoring others, for one timeout from
+    /// `start`.
+    fn recv_for(&mut self, seq: u32, start: Instant) -> Result<Option<Frame>>
+    {
+        let deadline = start + self.timeout;
+        loop {
+            match self.recv(deadline)? {
+                Some(frame) if frame.seq == seq => return Ok(Some(frame)),
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Receive the next intact frame, or None at the deadline. Damaged
+    /// frames are dropped; the request they answered will time out.
+    fn recv(&mut self, deadline: Instant) -> Result<Option<Frame>>
+    {
+        let mut buf = [0u8; 4096];
+        loop {
+            while let Some(raw) = self.deframer.next_frame() {
+                if let Ok(frame) = Frame::decode(&raw) {
+                    return Ok(Some(frame));
+                }
+            }
+
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+
+            let n = self.read_some(&mut buf)?;
+            self.deframer.push(&buf[.. n]);
+        }
+    }
+
+    /// Read whatever is available; 0 if the port's read timed out.
+    fn read_some(&mut self, buf: &mut [u8]) -> Result<usize>
+    {
+        match self.port.read(buf) {
+            Ok(n) => Ok(n),
+            Err(ref e)
+                if e.kind() == io::ErrorKind::Interrupted ||
+                    e.kind() == io::ErrorKind::WouldBlock ||
+                    e.kind() == io::ErrorKind::TimedOut =>
+            {
+                Ok(0)
+            },
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Wait for an "ack" line in line mode, returning the rest of it.
+    fn expect_ack(&mut self) -> Result<String>
+    {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let line = self.read_line(deadline)?;
+            if let Some(rest) = line.strip_prefix("ack") {
+                return Ok(rest.trim_start().to_string());
+            }
+            if let Some(rest) = line.strip_prefix("error ") {
+                return Err(Error::Protocol(rest.to_string()));
+            }
+        }
+    }
+
+    fn read_line(&mut self, deadline: Instant) -> Result<String>
+    {
+        let mut c = [0u8; 1];
+        loop {
+            if let Some(end) = self.line.iter().position(|&c| c == b'\n') {
+                let line: Vec<u8> = self.line.drain(..= end).collect();
+                let line = String::from_utf8_lossy(&line);
+                return Ok(line.trim_end_matches(['\r', '\n']).to_string());
+            }
+
+            if Instant::now() >= deadline {
+                return Err(Error::Timeout);
+            }
+
+            // One byte at a time, so nothing after the line is taken away
+            // from binary mode
+            if self.read_some(&mut c)? == 1 {
+                self.line.push(c[0]);
+            }
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! Consistent Overhead Byte Stuffing, matching `data::cobs` in the firmware
+//! (checked by `tests/cobs.rs`). Frame delimiters are not added or expected
+//! here.
+
+/// Largest possible encoded size of `len` bytes.
+pub fn max_encoded_len(len: usize) -> usize
+{
+    len + len / 254 + 1
+}
+
+/// Encode a block of data. The result contains no zero bytes.
+pub fn encode(src: &[u8]) -> Vec<u8>
+{
+    let mut out = Vec::with_capacity(max_encoded_len(src.len()));
+    let mut code_idx = 0;
+    let mut code = 1u8;
+    out.push(0);
+
+    for &c in src {
+        if c != 0 {
+            out.push(c);
+            code += 1;
+        }
+
+        if c == 0 || code == 0xff {
+            out[code_idx] = code;
+            code_idx = out.len();
+            code = 1;
+            out.push(0);
+        }
+    }
+
+    out[code_idx] = code;
+    out
+}
+
+/// Decode a block of data, or None if it is not valid COBS.
+pub fn decode(src: &[u8]) -> Option<Vec<u8>>
+{
+    let mut out = Vec::with_capacity(src.len());
+    let mut i = 0;
+
+    while i < src.len() {
+        let code = src[i] as usize;
+        if code == 0 || i + code > src.len() {
+            return None;
+        }
+
+        let block = &src[i + 1 .. i + code];
+        if block.contains(&0) {
+            return None;
+        }
+        out.extend_from_slice(block);
+        i += code;
+
+        // Every block but the last and the full-length ones ends in a zero
+        if code != 0xff && i < src.len() {
+            out.push(0);
+        }
+    }
+
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: &[u8])
+    {
+        let encoded = encode(data);
+        assert!(!encoded.contains(&0));
+        assert!(encoded.len() <= max_encoded_len(data.len()));
+        assert_eq!(decode(&encoded).as_deref(), Some(data));
+    }
+
+    #[test]
+    fn known_vectors()
+    {
+        assert_eq!(encode(&[]), [1]);
+        assert_eq!(encode(&[0]), [1, 1]);
+        assert_eq!(encode(&[0x11, 0x22, 0, 0x33]), [3, 0x11, 0x22, 2, 0x33]);
+    }
+
+    #[test]
+    fn round_trips()
+    {
+        round_trip(&[0, 0]);
+        round_trip(&[1; 254]);
+        round_trip(&[1; 255]);
+        round_trip(&(0 ..= 255).collect::<Vec<u8>>());
+
+        let mut long = vec![7u8; 1000];
+        long[500] = 0;
+        round_trip(&long);
+    }
+
+    #[test]
+    fn rejects_bad_input()
+    {
+        assert_eq!(decode(&[3, 1]), None);
+        assert_eq!(decode(&[0]), None);
+        assert_eq!(decode(&[3, 1, 0]), None);
+    }
+}
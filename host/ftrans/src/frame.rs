@@ -0,0 +1,179 @@
+//! Binary mode frames. The layout is documented with the firmware side, in
+//! `ecfw_rust/drivers/ftrans.rs`:
+//!
+//! ```text
+//! offset  size  field
+//! 0       1     opcode
+//! 1       4     sequence number
+//! 5       4     payload length n
+//! 9       n     payload
+//! 9 + n   4     CRC32 of everything before it
+//! ```
+//!
+//! all little endian, COBS-encoded and terminated by a zero byte.
+
+use crate::cobs;
+use crate::crc32::crc32;
+
+pub use crate::proto::*;
+
+/// Messages of the errors with codes, as older firmware sends them. It
+/// only sends ERROR_TABLE indexes, so the code is found from the text.
+pub const ERROR_MESSAGES: &[(u16, &str)] = &[
+    (ERRC_CHECKSUM, "invalid checksum"),
+    (ERRC_FRAME, "malformed frame"),
+    (ERRC_NOT_SUPPORTED, "not supported"),
+    (ERRC_ARG_RANGE, "argument out of range"),
+    (ERRC_UTF8, "invalid UTF-8"),
+    (ERRC_EXPECTED_ARGS, "expected argument(s)"),
+    (ERRC_FILE_NOT_OPEN, "file not open"),
+    (ERRC_NOT_FOUND, "no such file or directory"),
+    (ERRC_EXISTS, "file exists"),
+    (ERRC_NOT_DIR, "not a directory"),
+    (ERRC_IS_DIR, "is a directory"),
+    (ERRC_NOT_EMPTY, "directory not empty"),
+    (ERRC_READ_ONLY, "read-only file system"),
+    (ERRC_NO_SPACE, "no space left on device"),
+    (ERRC_IO, "I/O error"),
+    (ERRC_BUSY, "busy"),
+    (ERRC_NO_UPLOAD, "no upload in progress"),
+    (ERRC_UPLOAD_MISMATCH, "upload does not match"),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub op: u8,
+    pub seq: u32,
+    pub payload: Vec<u8>,
+}
+
+/// Why a received frame could not be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// Invalid COBS data or too short to hold a header
+    Malformed,
+    /// Length field doesn't match; the sequence number may still be useful
+    Length(u32),
+    /// CRC mismatch
+    Checksum(u32),
+}
+
+impl Frame {
+    pub fn new(op: u8, seq: u32, payload: Vec<u8>) -> Frame
+    {
+        Frame { op, seq, payload }
+    }
+
+    /// Encode the frame, including the trailing delimiter.
+    pub fn encode(&self) -> Vec<u8>
+    {
+        let body = HEADER_LEN + self.payload.len();
+        let mut raw = Vec::with_capacity(body + CRC_LEN);
+
+        raw.push(self.op);
+        raw.extend_from_slice(&self.seq.to_le_bytes());
+        raw.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
+        raw.extend_from_slice(&self.payload);
+        let crc = crc32(&raw);
+        raw.extend_from_slice(&crc.to_le_bytes());
+
+        let mut encoded = cobs::encode(&raw);
+        encoded.push(0);
+        encoded
+    }
+
+    /// Decode a frame, still COBS-encoded and without its delimiter.
+    pub fn decode(encoded: &[u8]) -> Result<Frame, FrameError>
+    {
+        let raw = cobs::decode(encoded).ok_or(FrameError::Malformed)?;
+        if raw.len() < HEADER_LEN + CRC_LEN {
+            return Err(FrameError::Malformed);
+        }
+
+        let op = raw[0];
+        let seq = get_le(&raw[1 .. 5]) as u32;
+        let body = HEADER_LEN + get_le(&raw[5 .. 9]) as usize;
+
+        if body + CRC_LEN != raw.len() {
+            return Err(FrameError::Length(seq));
+        }
+
+        if crc32(&raw[.. body]) != get_le(&raw[body ..]) as u32 {
+            return Err(FrameError::Checksum(seq));
+        }
+
+        Ok(Frame::new(op, seq, raw[HEADER_LEN .. body].to_vec()))
+    }
+}
+
+/// Splits a received byte stream into encoded frames.
+#[derive(Debug, Default)]
+pub struct Deframer {
+    buf: Vec<u8>,
+}
+
+impl Deframer {
+    pub fn new() -> Deframer
+    {
+        Deframer::default()
+    }
+
+    pub fn push(&mut self, data: &[u8])
+    {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Take the next complete encoded frame, without its delimiter. Empty
+    /// frames (back-to-back delimiters) are skipped.
+    pub fn next_frame(&mut self) -> Option<Vec<u8>>
+    {
+        loop {
+            let end = self.buf.iter().position(|&c| c == 0)?;
+            let frame: Vec<u8> = self.buf.drain(..= end).take(end).collect();
+            if !frame.is_empty() {
+                return Some(frame);
+            }
+        }
+    }
+
+    /// Discard everything buffered.
+    pub fn clear(&mut self)
+    {
+        self.buf.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip()
+    {
+        let frame = Frame::new(OP_WRITE, 0x1234_5678, vec![0, 1, 2, 0, 0]);
+        let encoded = frame.encode();
+        assert_eq!(encoded.last(), Some(&0));
+        assert_eq!(encoded.iter().filter(|&&c| c == 0).count(), 1);
+
+        let mut deframer = Deframer::new();
+        deframer.push(&[0, 0]);
+        deframer.push(&encoded);
+        let raw = deframer.next_frame().unwrap();
+        assert_eq!(deframer.next_frame(), None);
+        assert_eq!(Frame::decode(&raw), Ok(frame));
+    }
+
+    #[test]
+    fn damaged()
+    {
+        let frame = Frame::new(OP_READ, 7, vec![1; 12]);
+        let mut encoded = frame.encode();
+        encoded.pop();
+
+        // Last payload byte, clear of the COBS code bytes
+        let n = encoded.len();
+        encoded[n - 5] ^= 0x40;
+        assert_eq!(Frame::decode(&encoded), Err(FrameError::Checksum(7)));
+        assert_eq!(Frame::decode(&[1, 1]), Err(FrameError::Malformed));
+    }
+}
@@ -0,0 +1,26 @@
+//! Host-side client for the EC firmware's `ftrans` file transfer protocol
+//! (`ecfw_rust/drivers/ftrans.rs`). Only binary mode is used for transfers;
+//! the line protocol just gets the session into it.
+
+pub mod client;
+pub mod cobs;
+pub mod crc32;
+pub mod frame;
+
+// The protocol's constants, from the firmware's source so that the two
+// can't drift apart. Re-exported by `frame`.
+#[path = "../../../ecfw_rust/drivers/ftrans_proto.rs"]
+pub mod proto;
+pub mod serial;
+
+// The firmware's own source, so that the tests here cover the hash that
+// uploads are committed by. It is written for the firmware's older compiler.
+#[path = "../../../ecfw_rust/data/sha256.rs"]
+#[allow(
+    clippy::len_zero,
+    clippy::needless_range_loop,
+    clippy::new_without_default
+)]
+pub mod sha256;
+
+pub use client::{Client, Entry, Error, FileType, HashAlg};
@@ -0,0 +1,152 @@
+//! Command line front end for the ftrans client.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::process;
+
+use ftrans::{serial, Client, Error, HashAlg};
+
+const USAGE: &str = "\
+usage: ftrans [-d DEVICE] [-b BAUD] [-s] [-c] COMMAND [ARGS]
+
+  -d DEVICE   serial device (default $FTRANS_DEVICE, else /dev/ttyACM0)
+  -b BAUD     baud rate, for the USART console (default 115200)
+  -s          an ftrans session is already running; don't start one
+  -c          continue an interrupted put or get
+
+commands:
+  put LOCAL REMOTE      upload a file
+  get REMOTE LOCAL      download a file
+  ls [PATH]             list a directory (default /)
+  rm PATH               delete a file or empty directory
+  sync                  flush the card's filesystem
+  truncate PATH SIZE    set the size of a file
+  hash PATH [ALG]       hash a file on the device (sha256 or crc32)";
+
+struct Options {
+    device: String,
+    baud: u32,
+    start_session: bool,
+    resume: bool,
+}
+
+fn usage() -> !
+{
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn main()
+{
+    let mut opts = Options {
+        device: env::var("FTRANS_DEVICE")
+            .unwrap_or_else(|_| "/dev/ttyACM0".into()),
+        baud: serial::DEFAULT_BAUD,
+        start_session: true,
+        resume: false,
+    };
+    let mut args = env::args().skip(1);
+    let mut rest = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-d" => opts.device = args.next().unwrap_or_else(|| usage()),
+            "-b" => {
+                opts.baud = match args.next().map(|b| b.parse()) {
+                    Some(Ok(b)) => b,
+                    _ => usage(),
+                }
+            },
+            "-s" => opts.start_session = false,
+            "-c" => opts.resume = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            },
+            _ => rest.push(arg),
+        }
+    }
+
+    if rest.is_empty() {
+        usage();
+    }
+
+    if let Err(e) = run(&opts, &rest) {
+        eprintln!("ftrans: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(opts: &Options, args: &[String]) -> Result<(), Error>
+{
+    let cmd = args[0].as_str();
+    let args: Vec<&str> = args[1 ..].iter().map(|s| s.as_str()).collect();
+
+    let nargs = match cmd {
+        "put" | "get" | "truncate" => 2,
+        "rm" => 1,
+        "ls" => args.len().min(1),
+        "hash" => args.len().clamp(1, 2),
+        "sync" => 0,
+        _ => usage(),
+    };
+    if args.len() != nargs {
+        usage();
+    }
+
+    // Check local arguments before touching the device
+    let upload = match cmd {
+        "put" => Some(fs::read(args[0])?),
+        _ => None,
+    };
+    let partial = match cmd {
+        "get" if opts.resume => match fs::read(args[1]) {
+            Ok(data) => data,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        },
+        _ => Vec::new(),
+    };
+    let size = match cmd {
+        "truncate" => Some(args[1].parse::<u64>().unwrap_or_else(|_| usage())),
+        _ => None,
+    };
+    let alg = match args.get(1).copied() {
+        _ if cmd != "hash" => HashAlg::Sha256,
+        Some("crc32") => HashAlg::Crc32,
+        Some("sha256") | None => HashAlg::Sha256,
+        Some(_) => usage(),
+    };
+
+    let port = serial::open(&opts.device, opts.baud)?;
+    let mut client = Client::connect(port, opts.start_session)?;
+
+    match cmd {
+        "put" if opts.resume => {
+            let data = upload.as_deref().unwrap();
+            let skipped = client.put_resume(args[1], data)?;
+            eprintln!("{} of {} bytes were already there", skipped, data.len());
+        },
+        "put" => client.put(args[1], upload.as_deref().unwrap())?,
+        "get" => fs::write(args[1], client.get_resume(args[0], partial)?)?,
+        "ls" => {
+            for entry in client.list(args.first().copied().unwrap_or("/"))? {
+                println!("{}", entry);
+            }
+        },
+        "rm" => client.remove(args[0])?,
+        "sync" => client.sync()?,
+        "truncate" => client.truncate(args[0], size.unwrap())?,
+        "hash" => {
+            let (_, digest) = client.hash(args[0], alg, 0, u64::MAX)?;
+            let hex: String =
+                digest.iter().map(|b| format!("{:02x}", b)).collect();
+            println!("{}  {}", hex, args[0]);
+        },
+        _ => unreachable!(),
+    }
+
+    client.close()?;
+    Ok(())
+}
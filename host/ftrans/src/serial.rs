@@ -0,0 +1,29 @@
+//! Serial device setup. The EC's USB CDC port ignores line settings, but the
+//! RS-232 USART needs the right baud rate, the tty layer needs to be raw for
+//! both, and reads must time out so that lost frames can be resent.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::process::Command;
+
+/// Baud rate of the EC's USART console
+pub const DEFAULT_BAUD: u32 = 115200;
+
+/// Open a serial device for the client: raw, no echo, and reads that return
+/// empty after 0.2 s without data.
+pub fn open(path: &str, baud: u32) -> io::Result<File>
+{
+    let port = OpenOptions::new().read(true).write(true).open(path)?;
+
+    let status = Command::new("stty")
+        .arg("-F")
+        .arg(path)
+        .arg(baud.to_string())
+        .args(["raw", "-echo", "-hupcl", "min", "0", "time", "2"])
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("stty failed on {}", path)));
+    }
+
+    Ok(port)
+}
@@ -0,0 +1,492 @@
+//! Client tests against the simulated firmware in `sim`.
+
+mod sim;
+
+use std::time::Duration;
+
+use ftrans::frame::{
+    ERRC_EXISTS, ERRC_NOT_FOUND, OP_CLOSE, OP_COMMIT, OP_MKDIR, OP_REMOVE,
+    OP_RENAME, OP_WRITE, VERSION,
+};
+use ftrans::{Client, Error, FileType, HashAlg};
+use sim::{temp_path, Sim, SimPort};
+
+/// Short, since the simulation answers at once or never
+const TIMEOUT: Duration = Duration::from_millis(20);
+
+fn connect(sim: Sim) -> (Client<SimPort>, SimPort)
+{
+    let port = SimPort::new(sim);
+    let client =
+        Client::connect_with_timeout(port.clone(), true, TIMEOUT).unwrap();
+    (client, port)
+}
+
+/// Deterministic test data with no long runs
+fn data(len: usize) -> Vec<u8>
+{
+    let mut x = 0x1234_5678u32;
+    (0 .. len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x as u8
+        })
+        .collect()
+}
+
+fn remote_message(e: Error) -> String
+{
+    remote_error(e).1
+}
+
+fn remote_error(e: Error) -> (u16, String)
+{
+    match e {
+        Error::Remote { code, message } => (code, message),
+        e => panic!("expected a remote error, got {:?}", e),
+    }
+}
+
+#[test]
+fn session()
+{
+    let (client, port) = connect(Sim::new());
+    assert_eq!(client.version(), VERSION);
+    assert!(!port.0.borrow().at_shell());
+
+    client.close().unwrap();
+    assert!(port.0.borrow().at_shell());
+}
+
+#[test]
+fn already_in_session()
+{
+    let port = SimPort::new(Sim::new());
+    port.0.borrow_mut().input_str("ftrans\r");
+
+    let client =
+        Client::connect_with_timeout(port.clone(), false, TIMEOUT).unwrap();
+    client.close().unwrap();
+    assert!(port.0.borrow().at_shell());
+}
+
+#[test]
+fn too_old()
+{
+    let mut sim = Sim::new();
+    sim.version = 3;
+
+    let port = SimPort::new(sim);
+    match Client::connect_with_timeout(port, true, TIMEOUT) {
+        Err(Error::TooOld { version: 3, need: 4 }) => {},
+        other => panic!("expected TooOld, got {:?}", other.err()),
+    }
+}
+
+#[test]
+fn put_get()
+{
+    let (mut client, port) = connect(Sim::new());
+
+    // Empty, less than a chunk, and many windows' worth with a partial
+    // chunk at the end
+    for &len in &[0, 100, 200_001] {
+        let expected = data(len);
+        client.put("/f", &expected).unwrap();
+        assert_eq!(port.0.borrow().files["/f"], expected);
+        assert_eq!(client.get("/f").unwrap(), expected);
+    }
+
+    // put replaces a longer file
+    client.put("/f", b"short").unwrap();
+    assert_eq!(port.0.borrow().files["/f"], b"short");
+    assert!(port.0.borrow().syncs >= 4);
+}
+
+#[test]
+fn put_with_damaged_requests()
+{
+    let mut sim = Sim::new();
+    // Frames 0-3 are the hello, open and truncate; the rest are writes
+    sim.faults.corrupt_rx = vec![4, 9, 10, 30];
+    sim.faults.drop_rx = vec![5, 20];
+    let (mut client, port) = connect(sim);
+
+    let expected = data(100_000);
+    client.put("/f", &expected).unwrap();
+    assert_eq!(port.0.borrow().files["/f"], expected);
+}
+
+#[test]
+fn put_with_lost_replies()
+{
+    let mut sim = Sim::new();
+    sim.faults.corrupt_tx = vec![4, 12];
+    sim.faults.drop_tx = vec![5, 6, 7];
+    let (mut client, port) = connect(sim);
+
+    let expected = data(100_000);
+    client.put("/f", &expected).unwrap();
+    assert_eq!(port.0.borrow().files["/f"], expected);
+}
+
+#[test]
+fn get_with_faults()
+{
+    let mut sim = Sim::new();
+    let expected = data(50_000);
+    sim.files.insert("/f".into(), expected.clone());
+    sim.faults.corrupt_rx = vec![3, 8];
+    sim.faults.corrupt_tx = vec![4, 10];
+    sim.faults.drop_tx = vec![6];
+    let (mut client, _port) = connect(sim);
+
+    assert_eq!(client.get("/f").unwrap(), expected);
+}
+
+#[test]
+fn commit_with_lost_reply()
+{
+    let mut sim = Sim::new();
+    sim.faults.drop_reply_to = vec![OP_COMMIT];
+    let (mut client, port) = connect(sim);
+
+    // The repeated commit finds no upload, but the file is in place
+    let expected = data(10_000);
+    client.put("/f", &expected).unwrap();
+    assert_eq!(port.0.borrow().files["/f"], expected);
+    assert!(!port.0.borrow().files.contains_key(&temp_path("/f")));
+}
+
+#[test]
+fn commit_with_late_reply()
+{
+    let mut sim = Sim::new();
+    sim.faults.delay_reply_to = vec![OP_COMMIT, OP_WRITE];
+    let (mut client, port) = connect(sim);
+
+    let expected = data(10_000);
+    client.put("/f", &expected).unwrap();
+    assert_eq!(port.0.borrow().files["/f"], expected);
+}
+
+#[test]
+fn get_with_lost_close_reply()
+{
+    let mut sim = Sim::new();
+    let expected = data(10_000);
+    sim.files.insert("/f".into(), expected.clone());
+    sim.faults.drop_reply_to = vec![OP_CLOSE];
+    let (mut client, _port) = connect(sim);
+
+    assert_eq!(client.get("/f").unwrap(), expected);
+}
+
+#[test]
+fn debug_output_mixed_in()
+{
+    let mut sim = Sim::new();
+    sim.noise = Some("sysman: event boot\n");
+    let (mut client, port) = connect(sim);
+
+    let expected = data(20_000);
+    client.put("/f", &expected).unwrap();
+    assert_eq!(client.get("/f").unwrap(), expected);
+    assert_eq!(port.0.borrow().files["/f"], expected);
+}
+
+#[test]
+fn gives_up()
+{
+    let mut sim = Sim::new();
+    sim.faults.drop_tx = (1 .. 100).collect();
+    let (mut client, _port) = connect(sim);
+
+    match client.sync() {
+        Err(Error::Timeout) => {},
+        other => panic!("expected a timeout, got {:?}", other),
+    }
+}
+
+#[test]
+fn directories()
+{
+    let (mut client, _port) = connect(Sim::new());
+
+    client.mkdir("/d").unwrap();
+    client.put("/d/a", b"hello").unwrap();
+    client.put("/b", b"").unwrap();
+
+    let entries = client.list("/").unwrap();
+    let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+    assert_eq!(names, ["b", "d"]);
+    assert_eq!(entries[1].file_type, FileType::Dir);
+
+    let entries = client.list("/d").unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].file_type, FileType::File);
+    assert_eq!(entries[0].size, 5);
+    assert_eq!(entries[0].mode, 0o644);
+
+    let st = client.stat("/d/a").unwrap();
+    assert_eq!((st.size, st.name.as_str()), (5, ""));
+
+    let e = client.remove("/d").unwrap_err();
+    assert_eq!(remote_message(e), "directory not empty");
+
+    client.rename("/d/a", "/c").unwrap();
+    client.remove("/d").unwrap();
+    assert_eq!(client.list("/").unwrap().len(), 2);
+    assert_eq!(client.get("/c").unwrap(), b"hello");
+
+    let e = client.list("/c").unwrap_err();
+    assert_eq!(remote_message(e), "not a directory");
+}
+
+#[test]
+fn directories_with_lost_replies()
+{
+    let mut sim = Sim::new();
+    sim.files.insert("/f".into(), Vec::new());
+    sim.faults.drop_reply_to = vec![OP_MKDIR, OP_RENAME, OP_REMOVE];
+    let (mut client, port) = connect(sim);
+
+    // Each repeat fails, but the first copy did the job
+    client.mkdir("/d").unwrap();
+    client.rename("/d", "/e").unwrap();
+    client.remove("/e").unwrap();
+    assert!(port.0.borrow().dirs.iter().eq(["/"]));
+
+    // Unless it didn't
+    port.0.borrow_mut().faults.drop_reply_to = vec![OP_MKDIR];
+    let e = client.mkdir("/f").unwrap_err();
+    assert_eq!(remote_error(e).0, ERRC_EXISTS);
+}
+
+#[test]
+fn list_with_damaged_request()
+{
+    let mut sim = Sim::new();
+    sim.files.insert("/x".into(), Vec::new());
+    sim.faults.corrupt_rx = vec![1];
+    let (mut client, _port) = connect(sim);
+
+    assert_eq!(client.list("/").unwrap().len(), 1);
+}
+
+#[test]
+fn remote_errors()
+{
+    let (mut client, _port) = connect(Sim::new());
+
+    let e = client.remove("/nope").unwrap_err();
+    let expected = (ERRC_NOT_FOUND, "no such file or directory".into());
+    assert_eq!(remote_error(e), expected);
+    let e = client.get("/nope/f").unwrap_err();
+    assert_eq!(remote_error(e), expected);
+    client.mkdir("/d").unwrap();
+    let e = client.mkdir("/d").unwrap_err();
+    assert_eq!(remote_error(e).0, ERRC_EXISTS);
+
+    // The session carries on after an error
+    client.sync().unwrap();
+    client.close().unwrap();
+}
+
+#[test]
+fn errors_from_old_firmware()
+{
+    // Error replies only have an index into the firmware's table, which
+    // the client turns into a code by way of the message
+    let mut sim = Sim::new();
+    sim.version = 6;
+    sim.faults.corrupt_rx = vec![4, 9];
+    let (mut client, port) = connect(sim);
+
+    let e = client.remove("/nope").unwrap_err();
+    let expected = (ERRC_NOT_FOUND, "no such file or directory".into());
+    assert_eq!(remote_error(e), expected);
+
+    // Damaged requests are still recognised and sent again
+    client.put("/f", &data(20_000)).unwrap();
+    assert_eq!(port.0.borrow().files["/f"], data(20_000));
+}
+
+#[test]
+fn read_only()
+{
+    let mut sim = Sim::new();
+    sim.files.insert("/f".into(), data(10));
+    sim.read_only = true;
+    let (mut client, _port) = connect(sim);
+
+    assert_eq!(client.get("/f").unwrap(), data(10));
+    let e = client.put("/f", b"x").unwrap_err();
+    assert_eq!(remote_message(e), "read-only file system");
+}
+
+#[test]
+fn truncate()
+{
+    let (mut client, port) = connect(Sim::new());
+
+    client.put("/f", &data(1000)).unwrap();
+    client.truncate("/f", 10).unwrap();
+    assert_eq!(port.0.borrow().files["/f"], data(10));
+
+    client.truncate("/g", 3).unwrap();
+    assert_eq!(port.0.borrow().files["/g"], [0, 0, 0]);
+}
+
+#[test]
+fn hash()
+{
+    let (mut client, _port) = connect(Sim::new());
+    let expected = data(10_000);
+    client.put("/f", &expected).unwrap();
+
+    for &alg in &[HashAlg::Crc32, HashAlg::Sha256] {
+        let (n, digest) = client.hash("/f", alg, 0, u64::MAX).unwrap();
+        assert_eq!((n, digest), (10_000, alg.digest(&expected)));
+
+        let (n, digest) = client.hash("/f", alg, 100, 1000).unwrap();
+        assert_eq!((n, digest), (1000, alg.digest(&expected[100 .. 1100])));
+
+        let (n, digest) = client.hash("/f", alg, 9_000, 5_000).unwrap();
+        assert_eq!((n, digest), (1000, alg.digest(&expected[9_000 ..])));
+    }
+}
+
+#[test]
+fn put_detects_damage()
+{
+    let mut sim = Sim::new();
+    sim.rot_at = Some(12_345);
+    sim.files.insert("/f".into(), data(10));
+    let (mut client, port) = connect(sim);
+
+    match client.put("/f", &data(50_000)) {
+        Err(Error::Mismatch) => {},
+        other => panic!("expected a mismatch, got {:?}", other),
+    }
+    // The damaged upload was never put in place
+    assert_eq!(port.0.borrow().files["/f"], data(10));
+
+    // A second try is fine
+    client.put("/f", &data(50_000)).unwrap();
+    assert_eq!(port.0.borrow().files["/f"], data(50_000));
+    assert!(!port.0.borrow().files.contains_key(&temp_path("/f")));
+}
+
+#[test]
+fn put_interrupted()
+{
+    let mut sim = Sim::new();
+    sim.files.insert("/f".into(), data(10));
+    // Hello, upload, truncate and three writes get through
+    sim.faults.drop_rx = (6 .. 1000).collect();
+    let (mut client, port) = connect(sim);
+    let expected = data(100_000);
+
+    match client.put("/f", &expected) {
+        Err(Error::Timeout) => {},
+        other => panic!("expected a timeout, got {:?}", other),
+    }
+    let temp = port.0.borrow().files[&temp_path("/f")].clone();
+    assert_eq!(temp, expected[.. temp.len()]);
+    assert_eq!(port.0.borrow().files["/f"], data(10));
+
+    port.0.borrow_mut().faults.drop_rx.clear();
+    let have = client.put_resume("/f", &expected).unwrap();
+    assert_eq!(have, temp.len() as u64);
+    assert_eq!(port.0.borrow().files["/f"], expected);
+    assert!(!port.0.borrow().files.contains_key(&temp_path("/f")));
+}
+
+#[test]
+fn put_resume()
+{
+    let mut sim = Sim::new();
+    let expected = data(100_000);
+    sim.files.insert(temp_path("/f"), expected[.. 40_000].to_vec());
+    let (mut client, port) = connect(sim);
+
+    assert_eq!(client.put_resume("/f", &expected).unwrap(), 40_000);
+    assert_eq!(port.0.borrow().files["/f"], expected);
+    assert_eq!(port.0.borrow().bytes_written, 60_000);
+}
+
+#[test]
+fn put_resume_mismatch()
+{
+    let mut sim = Sim::new();
+    let expected = data(100_000);
+    let mut partial = expected[.. 40_000].to_vec();
+    partial[30_000] ^= 1;
+    sim.files.insert(temp_path("/f"), partial);
+    sim.files.insert(temp_path("/g"), data(120_000));
+    let (mut client, port) = connect(sim);
+
+    // Different data starts over
+    assert_eq!(client.put_resume("/f", &expected).unwrap(), 0);
+    assert_eq!(port.0.borrow().files["/f"], expected);
+    assert_eq!(port.0.borrow().bytes_written, 100_000);
+
+    // A longer file is cut short
+    assert_eq!(client.put_resume("/g", &expected).unwrap(), 100_000);
+    assert_eq!(port.0.borrow().files["/g"], expected);
+}
+
+#[test]
+fn get_resume()
+{
+    let mut sim = Sim::new();
+    let expected = data(100_000);
+    sim.files.insert("/f".into(), expected.clone());
+    let (mut client, port) = connect(sim);
+
+    let have = expected[.. 70_000].to_vec();
+    assert_eq!(client.get_resume("/f", have).unwrap(), expected);
+    assert_eq!(port.0.borrow().bytes_read, 30_000);
+
+    let mut have = expected[.. 70_000].to_vec();
+    have[5] ^= 1;
+    assert_eq!(client.get_resume("/f", have).unwrap(), expected);
+    assert_eq!(port.0.borrow().bytes_read, 130_000);
+}
+
+#[test]
+fn without_upload()
+{
+    let mut sim = Sim::new();
+    sim.version = 5;
+    let expected = data(100_000);
+    sim.files.insert("/f".into(), expected[.. 40_000].to_vec());
+    let (mut client, port) = connect(sim);
+
+    // Resuming works on the target itself
+    assert_eq!(client.put_resume("/f", &expected).unwrap(), 40_000);
+    assert_eq!(port.0.borrow().files["/f"], expected);
+    assert_eq!(port.0.borrow().files.len(), 1);
+    assert_eq!(port.0.borrow().syncs, 1);
+}
+
+#[test]
+fn without_hash()
+{
+    let mut sim = Sim::new();
+    sim.version = 4;
+    let (mut client, port) = connect(sim);
+
+    // Plain transfers still work, unverified
+    client.put("/f", &data(5000)).unwrap();
+    assert_eq!(client.get("/f").unwrap(), data(5000));
+    assert_eq!(port.0.borrow().files["/f"], data(5000));
+
+    match client.put_resume("/f", &data(5000)) {
+        Err(Error::TooOld { version: 4, need: 5 }) => {},
+        other => panic!("expected TooOld, got {:?}", other),
+    }
+}
@@ -0,0 +1,94 @@
+//! Checks `ftrans::cobs` against the firmware's COBS, which can't be built
+//! into the crate itself because it reports errors through `messages`.
+//! Stand-ins for the parts of that it uses are defined here.
+
+extern crate self as messages;
+
+#[path = "../../../ecfw_rust/data/cobs.rs"]
+mod firmware;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Error(&'static str);
+
+pub const ERR_COBS: Error = Error("invalid COBS data");
+pub const ERR_STRLEN: Error = Error("string too long");
+
+fn firmware_encode(src: &[u8]) -> Vec<u8>
+{
+    let mut dest = vec![0u8; firmware::max_encoded_len(src.len())];
+    let n = firmware::encode(&mut dest, src).unwrap();
+    dest.truncate(n);
+    dest
+}
+
+fn firmware_decode(src: &[u8]) -> Option<Vec<u8>>
+{
+    let mut dest = vec![0u8; src.len()];
+    match firmware::decode(&mut dest, src) {
+        Ok(n) => {
+            dest.truncate(n);
+            Some(dest)
+        }
+        Err(e) => {
+            assert_eq!(e, ERR_COBS);
+            None
+        }
+    }
+}
+
+fn samples() -> Vec<Vec<u8>>
+{
+    let mut samples = vec![
+        vec![],
+        vec![0],
+        vec![0, 0],
+        vec![0x11, 0x22, 0, 0x33],
+        vec![1; 253],
+        vec![1; 254],
+        vec![1; 255],
+        vec![1; 508],
+        (0 ..= 255).collect(),
+    ];
+
+    let mut long = vec![7u8; 1000];
+    long[253] = 0;
+    long[500] = 0;
+    samples.push(long);
+    samples
+}
+
+#[test]
+fn same_encoding()
+{
+    for data in samples() {
+        let encoded = ftrans::cobs::encode(&data);
+        assert_eq!(encoded, firmware_encode(&data), "{:?}", data);
+        assert_eq!(
+            ftrans::cobs::max_encoded_len(data.len()),
+            firmware::max_encoded_len(data.len())
+        );
+        assert_eq!(firmware_decode(&encoded), Some(data));
+    }
+}
+
+#[test]
+fn same_rejections()
+{
+    let bad: &[&[u8]] = &[&[3, 1], &[0], &[3, 1, 0], &[2, 0, 1], &[1, 5, 1]];
+
+    for &encoded in bad {
+        assert_eq!(
+            ftrans::cobs::decode(encoded),
+            firmware_decode(encoded),
+            "{:?}",
+            encoded
+        );
+    }
+}
+
+#[test]
+fn short_buffer()
+{
+    let mut dest = [0u8; 2];
+    assert_eq!(firmware::encode(&mut dest, &[1, 2]), Err(ERR_STRLEN));
+}
@@ -0,0 +1,43 @@
+//! Known-answer tests for the firmware's SHA-256, which is compiled into
+//! this crate as `ftrans::sha256`.
+
+use ftrans::sha256::{sha256, Sha256};
+
+fn hex(digest: &[u8]) -> String
+{
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[test]
+fn known_vectors()
+{
+    assert_eq!(
+        hex(&sha256(b"")),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+    assert_eq!(
+        hex(&sha256(b"abc")),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    assert_eq!(
+        hex(&sha256(
+            b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+        )),
+        "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+    );
+}
+
+#[test]
+fn incremental()
+{
+    let data = vec![b'a'; 1_000_000];
+    let mut hash = Sha256::new();
+    for piece in data.chunks(777) {
+        hash.update(piece);
+    }
+    assert_eq!(hash.finish(), sha256(&data));
+    assert_eq!(
+        hex(&hash.finish()),
+        "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+    );
+}
@@ -0,0 +1,737 @@
+//! In-process stand-in for the firmware side of ftrans: the esh prompt, the
+//! line protocol commands the client uses, and binary mode over an
+//! in-memory filesystem. It follows `ecfw_rust/drivers/ftrans.rs`; keep the
+//! two in step. The protocol constants are the firmware's own, through
+//! `ftrans::frame`.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+use ftrans::crc32::crc32;
+use ftrans::frame::*;
+use ftrans::sha256::sha256;
+
+const TEMP_SUFFIX: &str = ".ftrans-tmp";
+
+// Errors and their protocol codes. Before version 7, error replies carry
+// the index into this table instead, as into ERROR_TABLE in the firmware.
+// The order differs from the firmware's on purpose: clients of old firmware
+// must look messages up with OP_ERRMSG.
+const ERRORS: &[(&str, u16)] = &[
+    ("unknown error", ERRC_OTHER),
+    ("invalid checksum", ERRC_CHECKSUM),
+    ("malformed frame", ERRC_FRAME),
+    ("no such file or directory", ERRC_NOT_FOUND),
+    ("file exists", ERRC_EXISTS),
+    ("not a directory", ERRC_NOT_DIR),
+    ("is a directory", ERRC_IS_DIR),
+    ("read-only file system", ERRC_READ_ONLY),
+    ("directory not empty", ERRC_NOT_EMPTY),
+    ("not supported", ERRC_NOT_SUPPORTED),
+    ("file not open", ERRC_FILE_NOT_OPEN),
+    ("argument out of range", ERRC_ARG_RANGE),
+    ("invalid UTF-8", ERRC_UTF8),
+    ("expected argument(s)", ERRC_EXPECTED_ARGS),
+    ("upload does not match", ERRC_UPLOAD_MISMATCH),
+    ("no upload in progress", ERRC_NO_UPLOAD),
+];
+
+fn err(message: &str) -> usize {
+    ERRORS.iter().position(|&(m, _)| m == message).unwrap()
+}
+
+#[derive(PartialEq, Eq)]
+enum Mode {
+    Shell,
+    Line,
+    Binary,
+}
+
+/// Faults to inject. Most are lists of frame numbers (counting from 0) to
+/// act on.
+#[derive(Default)]
+pub struct Faults {
+    /// Requests to damage before they are decoded
+    pub corrupt_rx: Vec<usize>,
+    /// Requests to lose entirely
+    pub drop_rx: Vec<usize>,
+    /// Replies to damage
+    pub corrupt_tx: Vec<usize>,
+    /// Replies to lose
+    pub drop_tx: Vec<usize>,
+    /// Opcodes of requests whose first reply is lost
+    pub drop_reply_to: Vec<u8>,
+    /// Opcodes of requests whose first reply is held back until the next
+    /// request comes in, as if the card were slow
+    pub delay_reply_to: Vec<u8>,
+}
+
+pub struct Sim {
+    mode: Mode,
+    line: Vec<u8>,
+    frame: Vec<u8>,
+    out: VecDeque<u8>,
+    pub version: u32,
+    pub files: BTreeMap<String, Vec<u8>>,
+    pub dirs: BTreeSet<String>,
+    pub read_only: bool,
+    open: Option<String>,
+    /// Target of the upload whose temporary is open
+    upload: Option<String>,
+    held: Vec<(u64, Vec<u8>)>,
+    pub faults: Faults,
+    rx_frames: usize,
+    tx_frames: usize,
+    /// Opcode of the request being answered
+    replying_to: Option<u8>,
+    /// Reply held back by `delay_reply_to`
+    delayed: Vec<u8>,
+    pub syncs: usize,
+    /// Debug output to mix in ahead of every reply, as on the USART
+    pub noise: Option<&'static str>,
+    /// Damage the byte at this offset once a write reaches it, as a bad
+    /// card might
+    pub rot_at: Option<usize>,
+    /// File data received and sent, to check what resuming skipped
+    pub bytes_written: usize,
+    pub bytes_read: usize,
+}
+
+impl Sim {
+    pub fn new() -> Sim
+    {
+        let mut dirs = BTreeSet::new();
+        dirs.insert("/".to_string());
+
+        Sim {
+            mode: Mode::Shell,
+            line: Vec::new(),
+            frame: Vec::new(),
+            out: VecDeque::new(),
+            version: VERSION,
+            files: BTreeMap::new(),
+            dirs,
+            read_only: false,
+            open: None,
+            upload: None,
+            held: Vec::new(),
+            faults: Faults::default(),
+            rx_frames: 0,
+            tx_frames: 0,
+            replying_to: None,
+            delayed: Vec::new(),
+            syncs: 0,
+            noise: None,
+            rot_at: None,
+            bytes_written: 0,
+            bytes_read: 0,
+        }
+    }
+
+    /// Whether the session has ended and the shell is back.
+    pub fn at_shell(&self) -> bool
+    {
+        self.mode == Mode::Shell
+    }
+
+    fn print(&mut self, s: &str)
+    {
+        self.print_noise();
+        self.print_raw(s);
+    }
+
+    fn print_noise(&mut self)
+    {
+        if let Some(noise) = self.noise {
+            self.print_raw(noise);
+        }
+    }
+
+    fn print_raw(&mut self, s: &str)
+    {
+        // print_to_async turns \n into \r\n
+        for c in s.bytes() {
+            if c == b'\n' {
+                self.out.push_back(b'\r');
+            }
+            self.out.push_back(c);
+        }
+    }
+
+    /// Type at the simulated device.
+    pub fn input_str(&mut self, s: &str)
+    {
+        for c in s.bytes() {
+            self.input(c);
+        }
+    }
+
+    fn input(&mut self, c: u8)
+    {
+        match self.mode {
+            Mode::Shell => self.shell_input(c),
+            Mode::Line => self.line_input(c),
+            Mode::Binary => self.binary_input(c),
+        }
+    }
+
+    fn shell_input(&mut self, c: u8)
+    {
+        if c != b'\r' {
+            self.line.push(c);
+            self.out.push_back(c);
+            return;
+        }
+
+        self.print("\n");
+        let line = String::from_utf8_lossy(&self.line).into_owned();
+        self.line.clear();
+        if line == "ftrans" {
+            self.mode = Mode::Line;
+        } else {
+            self.print("command not found\n");
+        }
+    }
+
+    fn line_input(&mut self, c: u8)
+    {
+        match c {
+            3 | 4 => {
+                self.line.clear();
+                self.mode = Mode::Shell;
+            },
+            b'\r' => {
+                let line = String::from_utf8_lossy(&self.line).into_owned();
+                self.line.clear();
+                match line.as_str() {
+                    "hello" => {
+                        let v = self.version;
+                        let more = match v {
+                            ..= 4 => "",
+                            5 => " hash",
+                            _ => " hash upload commit",
+                        };
+                        let ack = format!("ack {} hello binary{}\n", v, more);
+                        self.print(&ack);
+                    },
+                    "binary" => {
+                        self.print("ack\n");
+                        self.mode = Mode::Binary;
+                    },
+                    _ => self.print("error invalid_byte_or_command\n"),
+                }
+            },
+            _ => self.line.push(c),
+        }
+    }
+
+    fn binary_input(&mut self, c: u8)
+    {
+        if c != 0 {
+            self.frame.push(c);
+            return;
+        }
+        if self.frame.is_empty() {
+            return;
+        }
+
+        let mut raw = std::mem::take(&mut self.frame);
+        let n = self.rx_frames;
+        self.rx_frames += 1;
+
+        let delayed = std::mem::take(&mut self.delayed);
+        self.out.extend(delayed);
+        self.replying_to = None;
+
+        if self.faults.drop_rx.contains(&n) {
+            return;
+        }
+        if self.faults.corrupt_rx.contains(&n) {
+            let last = raw.len() - 1;
+            raw[last] = raw[last].wrapping_add(1).max(1);
+        }
+
+        let frame = match Frame::decode(&raw) {
+            Ok(frame) => frame,
+            Err(FrameError::Malformed) => {
+                return self.send_error(0, "malformed frame");
+            },
+            Err(FrameError::Length(seq)) => {
+                return self.send_error(seq, "malformed frame");
+            },
+            Err(FrameError::Checksum(seq)) => {
+                return self.send_error(seq, "invalid checksum");
+            },
+        };
+        self.replying_to = Some(frame.op);
+
+        if frame.op == OP_EXIT {
+            self.send_frame(OP_EXIT | OP_REPLY, frame.seq, &[]);
+            self.mode = Mode::Line;
+            return;
+        }
+
+        if let Err(e) = self.binary_op(frame.op, frame.seq, &frame.payload) {
+            self.send_error(frame.seq, e);
+        }
+    }
+
+    fn binary_op(
+        &mut self,
+        op: u8,
+        seq: u32,
+        payload: &[u8],
+    ) -> Result<(), &'static str>
+    {
+        let reply = op | OP_REPLY;
+
+        match op {
+            OP_HELLO => {
+                let mut info = self.version.to_le_bytes().to_vec();
+                info.extend_from_slice(&(MAX_CHUNK as u32).to_le_bytes());
+                self.send_frame(reply, seq, &info);
+            },
+
+            OP_OPEN => {
+                let path = to_str(payload)?;
+                if self.dirs.contains(path) {
+                    return Err("is a directory");
+                }
+                if !self.dirs.contains(parent(path)) {
+                    return Err("no such file or directory");
+                }
+                if !self.files.contains_key(path) {
+                    if self.read_only {
+                        return Err("read-only file system");
+                    }
+                    self.files.insert(path.to_string(), Vec::new());
+                }
+                self.open = Some(path.to_string());
+                self.upload = None;
+                self.held.clear();
+                self.send_frame(reply, seq, &[]);
+            },
+
+            OP_CLOSE => {
+                let Some(open) = self.open.take() else {
+                    return Err("file not open");
+                };
+                if self.upload.take().is_some() {
+                    self.files.remove(&open);
+                }
+                self.held.clear();
+                self.send_frame(reply, seq, &[]);
+            },
+
+            OP_SYNC => {
+                self.syncs += 1;
+                self.send_frame(reply, seq, &[]);
+            },
+
+            OP_READ => {
+                if payload.len() != 12 {
+                    return Err("malformed frame");
+                }
+                let pos = get_le(&payload[0 .. 8]) as usize;
+                let len = (get_le(&payload[8 .. 12]) as usize).min(MAX_CHUNK);
+                let data = self.open_data()?;
+                let start = pos.min(data.len());
+                let end = (pos + len).min(data.len());
+                let chunk = data[start .. end].to_vec();
+                self.bytes_read += chunk.len();
+                self.send_frame(reply, seq, &chunk);
+            },
+
+            OP_WRITE => {
+                if payload.len() < 8 {
+                    return Err("malformed frame");
+                }
+                self.write_at(get_le(&payload[0 .. 8]), &payload[8 ..])?;
+                self.send_frame(reply, seq, &[]);
+            },
+
+            OP_TRUNCATE => {
+                if payload.len() != 8 {
+                    return Err("malformed frame");
+                }
+                let read_only = self.read_only;
+                let data = self.open_data()?;
+                if read_only {
+                    return Err("read-only file system");
+                }
+                data.resize(get_le(payload) as usize, 0);
+                self.send_frame(reply, seq, &[]);
+            },
+
+            OP_SIZE => {
+                let size = self.open_data()?.len() as u64;
+                self.send_frame(reply, seq, &size.to_le_bytes());
+            },
+
+            OP_STAT => {
+                let record = self.stat_record(to_str(payload)?, "")?;
+                self.send_frame(reply, seq, &record);
+            },
+
+            OP_LIST => {
+                let path = to_str(payload)?;
+                if self.files.contains_key(path) {
+                    return Err("not a directory");
+                }
+                if !self.dirs.contains(path) {
+                    return Err("no such file or directory");
+                }
+
+                let mut names: Vec<String> = self
+                    .dirs
+                    .iter()
+                    .chain(self.files.keys())
+                    .filter(|p| *p != "/" && parent(p) == path)
+                    .cloned()
+                    .collect();
+                names.sort();
+                for full in names {
+                    let name = &full[full.rfind('/').unwrap() + 1 ..];
+                    let record = self.stat_record(&full, name)?;
+                    self.send_frame(reply, seq, &record);
+                }
+                self.send_frame(reply, seq, &[]);
+            },
+
+            OP_MKDIR => {
+                let path = to_str(payload)?;
+                self.check_writable()?;
+                if self.exists(path) {
+                    return Err("file exists");
+                }
+                if !self.dirs.contains(parent(path)) {
+                    return Err("no such file or directory");
+                }
+                self.dirs.insert(path.to_string());
+                self.send_frame(reply, seq, &[]);
+            },
+
+            OP_REMOVE => {
+                let path = to_str(payload)?;
+                self.check_writable()?;
+                if self.dirs.contains(path) {
+                    if self.children(path) {
+                        return Err("directory not empty");
+                    }
+                    self.dirs.remove(path);
+                } else if self.files.remove(path).is_none() {
+                    return Err("no such file or directory");
+                }
+                self.send_frame(reply, seq, &[]);
+            },
+
+            OP_RENAME => {
+                let sep = payload
+                    .iter()
+                    .position(|&c| c == 0)
+                    .ok_or("expected argument(s)")?;
+                let old_path = to_str(&payload[.. sep])?.to_string();
+                let new_path = to_str(&payload[sep + 1 ..])?.to_string();
+                self.check_writable()?;
+                if self.exists(&new_path) {
+                    return Err("file exists");
+                }
+                match self.files.remove(&old_path) {
+                    Some(data) => {
+                        self.files.insert(new_path, data);
+                    },
+                    // Directories with contents aren't needed here
+                    None if self.dirs.remove(&old_path) => {
+                        self.dirs.insert(new_path);
+                    },
+                    None => return Err("no such file or directory"),
+                }
+                self.send_frame(reply, seq, &[]);
+            },
+
+            OP_ERRMSG => {
+                if payload.len() != 2 {
+                    return Err("malformed frame");
+                }
+                let code = get_le(payload) as u16;
+                let found = if self.version >= 7 {
+                    ERRORS.iter().find(|&&(_, c)| c == code)
+                } else {
+                    ERRORS.get(code as usize)
+                };
+                let &(message, _) = found.ok_or("argument out of range")?;
+                self.send_frame(reply, seq, message.as_bytes());
+            },
+
+            OP_HASH if self.version >= 5 => {
+                if payload.len() != 17 {
+                    return Err("malformed frame");
+                }
+                let data = self.open_data()?;
+                let pos = (get_le(&payload[1 .. 9]) as usize).min(data.len());
+                let len = get_le(&payload[9 .. 17]).min(usize::MAX as u64);
+                let end = pos.saturating_add(len as usize).min(data.len());
+                let range = &data[pos .. end];
+
+                let mut result = (range.len() as u64).to_le_bytes().to_vec();
+                match payload[0] {
+                    HASH_CRC32 => {
+                        result.extend_from_slice(&crc32(range).to_be_bytes())
+                    },
+                    HASH_SHA256 => result.extend_from_slice(&sha256(range)),
+                    _ => return Err("not supported"),
+                }
+                self.send_frame(reply, seq, &result);
+            },
+
+            OP_UPLOAD if self.version >= 6 => {
+                let path = to_str(payload)?;
+                self.check_writable()?;
+                if self.dirs.contains(path) {
+                    return Err("is a directory");
+                }
+                if !self.dirs.contains(parent(path)) {
+                    return Err("no such file or directory");
+                }
+                // Left as it is, so an interrupted upload can be resumed
+                let temp = temp_path(path);
+                self.files.entry(temp.clone()).or_default();
+                self.open = Some(temp);
+                self.upload = Some(path.to_string());
+                self.held.clear();
+                self.send_frame(reply, seq, &[]);
+            },
+
+            OP_COMMIT if self.version >= 6 => {
+                let Some(target) = self.upload.clone() else {
+                    return Err("no upload in progress");
+                };
+                if payload.len() != 32 {
+                    return Err("argument out of range");
+                }
+                if sha256(self.open_data()?) != payload {
+                    return Err("upload does not match");
+                }
+                let temp = self.open.take().unwrap();
+                let data = self.files.remove(&temp).unwrap();
+                self.files.insert(target, data);
+                self.upload = None;
+                self.held.clear();
+                self.syncs += 1;
+                self.send_frame(reply, seq, &[]);
+            },
+
+            _ => return Err("not supported"),
+        }
+
+        Ok(())
+    }
+
+    /// Writes past the end of the file are held, as in the firmware.
+    fn write_at(&mut self, pos: u64, data: &[u8]) -> Result<(), &'static str>
+    {
+        if self.read_only {
+            self.open_data()?;
+            return Err("read-only file system");
+        }
+
+        if pos > self.open_data()?.len() as u64 {
+            let held: usize = self.held.iter().map(|(_, d)| d.len()).sum();
+            if held + data.len() > WINDOW_BYTES {
+                return Err("argument out of range");
+            }
+            self.held.push((pos, data.to_vec()));
+            return Ok(());
+        }
+
+        self.bytes_written += data.len();
+        let mut rot_at = self.rot_at.take();
+        let mut pending = vec![(pos, data.to_vec())];
+        while let Some((pos, data)) = pending.pop() {
+            let file = self.open_data()?;
+            let (pos, end) = (pos as usize, pos as usize + data.len());
+            if file.len() < end {
+                file.resize(end, 0);
+            }
+            file[pos .. end].copy_from_slice(&data);
+            match rot_at {
+                Some(at) if at < end => {
+                    file[at] ^= 0x10;
+                    rot_at = None;
+                },
+                _ => {},
+            }
+
+            let size = file.len() as u64;
+            if let Some(i) = self.held.iter().position(|&(p, _)| p <= size) {
+                pending.push(self.held.swap_remove(i));
+            }
+        }
+        self.rot_at = rot_at;
+        Ok(())
+    }
+
+    fn open_data(&mut self) -> Result<&mut Vec<u8>, &'static str>
+    {
+        let path = self.open.as_ref().ok_or("file not open")?;
+        Ok(self.files.get_mut(path).unwrap())
+    }
+
+    fn check_writable(&self) -> Result<(), &'static str>
+    {
+        if self.read_only {
+            Err("read-only file system")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn exists(&self, path: &str) -> bool
+    {
+        self.dirs.contains(path) || self.files.contains_key(path)
+    }
+
+    fn children(&self, path: &str) -> bool
+    {
+        self.dirs
+            .iter()
+            .chain(self.files.keys())
+            .any(|p| p != "/" && parent(p) == path)
+    }
+
+    fn stat_record(
+        &self,
+        path: &str,
+        name: &str,
+    ) -> Result<Vec<u8>, &'static str>
+    {
+        let (kind, mode, size) = if self.dirs.contains(path) {
+            (b'd', 0o755u16, 4096u64)
+        } else if let Some(data) = self.files.get(path) {
+            (b'f', 0o644u16, data.len() as u64)
+        } else {
+            return Err("no such file or directory");
+        };
+
+        let mut record = vec![kind];
+        record.extend_from_slice(&mode.to_le_bytes());
+        record.extend_from_slice(&size.to_le_bytes());
+        record.extend_from_slice(&1_500_000_000i64.to_le_bytes());
+        record.extend_from_slice(name.as_bytes());
+        Ok(record)
+    }
+
+    fn send_frame(&mut self, op: u8, seq: u32, payload: &[u8])
+    {
+        let n = self.tx_frames;
+        self.tx_frames += 1;
+        if self.faults.drop_tx.contains(&n) ||
+            take_fault(&mut self.faults.drop_reply_to, self.replying_to)
+        {
+            return;
+        }
+
+        let mut encoded = Frame::new(op, seq, payload.to_vec()).encode();
+        if self.faults.corrupt_tx.contains(&n) {
+            let i = encoded.len() - 2;
+            encoded[i] = encoded[i].wrapping_add(1).max(1);
+        }
+        if take_fault(&mut self.faults.delay_reply_to, self.replying_to) {
+            self.delayed.push(0);
+            self.delayed.extend(encoded);
+            return;
+        }
+        self.print_noise();
+        self.out.push_back(0);
+        self.out.extend(encoded);
+    }
+
+    fn send_error(&mut self, seq: u32, message: &str)
+    {
+        let (_, code) = ERRORS[err(message)];
+        if self.version >= 7 {
+            let mut payload = code.to_le_bytes().to_vec();
+            payload.extend_from_slice(message.as_bytes());
+            self.send_frame(OP_ERROR, seq, &payload);
+        } else {
+            let index = err(message) as u16;
+            self.send_frame(OP_ERROR, seq, &index.to_le_bytes());
+        }
+    }
+}
+
+/// Remove `op` from an opcode fault list, returning whether it was there.
+fn take_fault(list: &mut Vec<u8>, op: Option<u8>) -> bool
+{
+    match list.iter().position(|&o| Some(o) == op) {
+        Some(i) => {
+            list.remove(i);
+            true
+        },
+        None => false,
+    }
+}
+
+fn to_str(data: &[u8]) -> Result<&str, &'static str>
+{
+    std::str::from_utf8(data).or(Err("invalid UTF-8"))
+}
+
+/// Where an upload to `path` is written until it is committed.
+pub fn temp_path(path: &str) -> String
+{
+    let split = path.rfind('/').map_or(0, |i| i + 1);
+    format!("{}.{}{}", &path[.. split], &path[split ..], TEMP_SUFFIX)
+}
+
+fn parent(path: &str) -> &str
+{
+    match path.rfind('/') {
+        Some(0) => "/",
+        Some(i) => &path[.. i],
+        None => "",
+    }
+}
+
+/// The client's end of the simulated serial link. Reads never block: with
+/// nothing to read they return 0, like a serial device whose read timed
+/// out.
+#[derive(Clone)]
+pub struct SimPort(pub Rc<RefCell<Sim>>);
+
+impl SimPort {
+    pub fn new(sim: Sim) -> SimPort
+    {
+        SimPort(Rc::new(RefCell::new(sim)))
+    }
+}
+
+impl Read for SimPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        let mut sim = self.0.borrow_mut();
+        let n = buf.len().min(sim.out.len());
+        for (b, c) in buf.iter_mut().zip(sim.out.drain(.. n)) {
+            *b = c;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for SimPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+    {
+        let mut sim = self.0.borrow_mut();
+        for &c in buf {
+            sim.input(c);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()>
+    {
+        Ok(())
+    }
+}
@@ -29,12 +29,164 @@ import subprocess
 HEADER = """\
 /*****************************************************************************
  * This ASF source file has been edited by unfuck-asf to have a sane include *
- * path. The edits are restricted to only #include lines, and are highly     *
- * automated, so no copyright is claimed on the modifications.               *
+ * path. The edits are restricted to only #include lines (plus card register *
+ * functions appended to sd_mmc), and are highly automated, so no copyright  *
+ * is claimed on the modifications.                                          *
  *****************************************************************************/
 
 """
 
+# Functions appended to sd_mmc.c. ASF keeps the card's RCA and the slot
+# selection private, and throws the CID away after init, so reading the card
+# registers (and cleaning up after a failed transfer) has to be done from
+# inside. ASF also waits for transfers to end without a time limit; the EC
+# firmware polls sd_mmc_transfer_ended() itself first, and abandons the
+# transfer if it takes too long.
+SD_MMC_PATCH_C = """
+/* Added by unfuck-asf: raw card register access for the EC firmware. */
+
+static sd_mmc_err_t sd_mmc_app_read(uint8_t slot, sdmmc_cmd_def_t cmd,
+		uint16_t len, uint8_t *dest)
+{
+	uint32_t buf[16];
+	sd_mmc_err_t err = sd_mmc_select_slot(slot);
+	if (err != SD_MMC_OK) {
+		return err;
+	}
+	if (!(sd_mmc_card->type & CARD_TYPE_SD) || len > sizeof(buf)) {
+		sd_mmc_deselect_slot();
+		return SD_MMC_ERR_PARAM;
+	}
+	if (!driver_send_cmd(SDMMC_CMD55_APP_CMD,
+				(uint32_t)sd_mmc_card->rca << 16)
+			|| !driver_adtc_start(cmd, 0, len, 1, true)
+			|| !driver_start_read_blocks(buf, 1)
+			|| !driver_wait_end_of_read_blocks()) {
+		sd_mmc_deselect_slot();
+		return SD_MMC_ERR_COMM;
+	}
+	memcpy(dest, buf, len);
+	sd_mmc_deselect_slot();
+	return SD_MMC_OK;
+}
+
+sd_mmc_err_t sd_mmc_get_cid(uint8_t slot, uint8_t *cid)
+{
+	uint32_t arg;
+	bool ok;
+	sd_mmc_err_t err = sd_mmc_select_slot(slot);
+	if (err != SD_MMC_OK) {
+		return err;
+	}
+	/* SEND_CID is only accepted in stand-by state */
+	arg = (uint32_t)sd_mmc_card->rca << 16;
+	ok = driver_send_cmd(SDMMC_CMD7_DESELECT_CARD_CMD, 0)
+		&& driver_send_cmd(SDMMC_MCI_CMD10_SEND_CID, arg);
+	if (ok) {
+		driver_get_response_128(cid);
+	}
+	ok = driver_send_cmd(SDMMC_CMD7_SELECT_CARD_CMD, arg) && ok;
+	sd_mmc_deselect_slot();
+	return ok ? SD_MMC_OK : SD_MMC_ERR_COMM;
+}
+
+sd_mmc_err_t sd_mmc_get_csd(uint8_t slot, uint8_t *csd)
+{
+	sd_mmc_err_t err = sd_mmc_select_slot(slot);
+	if (err != SD_MMC_OK) {
+		return err;
+	}
+	memcpy(csd, sd_mmc_card->csd, CSD_REG_BSIZE);
+	sd_mmc_deselect_slot();
+	return SD_MMC_OK;
+}
+
+sd_mmc_err_t sd_mmc_get_scr(uint8_t slot, uint8_t *scr)
+{
+	return sd_mmc_app_read(slot, SD_ACMD51_SEND_SCR, SD_SCR_REG_BSIZE, scr);
+}
+
+sd_mmc_err_t sd_mmc_get_ssr(uint8_t slot, uint8_t *ssr)
+{
+	return sd_mmc_app_read(slot, SD_ACMD13_SD_STATUS, 64, ssr);
+}
+
+sd_mmc_err_t sd_mmc_stop_transfer(uint8_t slot)
+{
+	sd_mmc_err_t err = sd_mmc_select_slot(slot);
+	if (err != SD_MMC_OK) {
+		return err;
+	}
+	/* ASF bails out of failed transfers without stopping the card. If no
+	 * transfer was in progress, the card just flags an illegal command. */
+	driver_send_cmd(SDMMC_CMD12_STOP_TRANSMISSION, 0);
+	sd_mmc_nb_block_remaining = 0;
+	sd_mmc_deselect_slot();
+	return SD_MMC_OK;
+}
+
+#define SD_MMC_HSMCI_SR_END (HSMCI_SR_XFRDONE | HSMCI_SR_UNRE \
+		| HSMCI_SR_OVRE | HSMCI_SR_DTOE | HSMCI_SR_DCRCE)
+
+bool sd_mmc_transfer_ended(void)
+{
+	/* XFRDONE clears when the transfer command is sent. The error flags
+	 * end the transfer too; the wait that follows reports them. */
+	return (HSMCI->HSMCI_SR & SD_MMC_HSMCI_SR_END) != 0;
+}
+
+void sd_mmc_abort_transfer(void)
+{
+	/* As hsmci_reset(), which is private, and releasing the slot as the
+	 * end of a transfer would */
+	uint32_t mr = HSMCI->HSMCI_MR & ~HSMCI_MR_PDCMODE;
+	uint32_t dtor = HSMCI->HSMCI_DTOR;
+	uint32_t sdcr = HSMCI->HSMCI_SDCR;
+	uint32_t cstor = HSMCI->HSMCI_CSTOR;
+	uint32_t cfg = HSMCI->HSMCI_CFG;
+
+	HSMCI->HSMCI_PTCR = HSMCI_PTCR_RXTDIS | HSMCI_PTCR_TXTDIS;
+	HSMCI->HSMCI_CR = HSMCI_CR_SWRST;
+	HSMCI->HSMCI_MR = mr;
+	HSMCI->HSMCI_DTOR = dtor;
+	HSMCI->HSMCI_SDCR = sdcr;
+	HSMCI->HSMCI_CSTOR = cstor;
+	HSMCI->HSMCI_CFG = cfg;
+	HSMCI->HSMCI_CR = HSMCI_CR_PWSEN | HSMCI_CR_MCIEN;
+
+	sd_mmc_nb_block_remaining = 0;
+	sd_mmc_deselect_slot();
+}
+"""
+
+SD_MMC_PATCH_H = """
+/* Added by unfuck-asf: raw card register access for the EC firmware.
+ * Registers are copied out most significant byte first. */
+sd_mmc_err_t sd_mmc_get_cid(uint8_t slot, uint8_t *cid);   /* 16 bytes */
+sd_mmc_err_t sd_mmc_get_csd(uint8_t slot, uint8_t *csd);   /* 16 bytes */
+sd_mmc_err_t sd_mmc_get_scr(uint8_t slot, uint8_t *scr);   /* 8 bytes */
+sd_mmc_err_t sd_mmc_get_ssr(uint8_t slot, uint8_t *ssr);   /* 64 bytes */
+sd_mmc_err_t sd_mmc_stop_transfer(uint8_t slot);
+
+/* Transfers with a time limit (HSMCI only): after sd_mmc_start_*_blocks(),
+ * poll sd_mmc_transfer_ended(), then call sd_mmc_wait_end_of_*_blocks(),
+ * which returns at once. If it never ends, call sd_mmc_abort_transfer() and
+ * then sd_mmc_stop_transfer(). */
+bool sd_mmc_transfer_ended(void);
+void sd_mmc_abort_transfer(void);
+/* End of unfuck-asf additions */
+"""
+
+# Start of the first line of both patches, to find them in a patched tree
+SD_MMC_PATCH_MARK = "/* Added by unfuck-asf: raw card register access"
+
+# Last line of SD_MMC_PATCH_H. Older versions of it lack this, and end with
+# the last prototype.
+SD_MMC_PATCH_H_END = "/* End of unfuck-asf additions */"
+
+# Line in sd_mmc.h after which SD_MMC_PATCH_H is inserted
+SD_MMC_H_ANCHOR = re.compile(r'^bool sd_mmc_is_write_protected\(uint8_t slot\);')
+
 HEADER_LIST = [i + "\n" for i in HEADER.split("\n")]
 
 INC_RE = re.compile(r'^\s*#\s*include\s+[<"]([^>"]+)[>"]')
@@ -118,6 +270,75 @@ def fix_one_file(fn, incpath, root):
         for i in lines:
             f.write(i)
 
+def strip_patch_c(text):
+    """Remove an earlier version of SD_MMC_PATCH_C from sd_mmc.c. It was
+    appended, so it runs from the mark to the end of the file."""
+    i = text.find(SD_MMC_PATCH_MARK)
+    if i < 0:
+        return text
+    head = text[:i]
+    if head.endswith("\n\n"):
+        head = head[:-1]
+    return head
+
+def strip_patch_h(lines):
+    """Remove an earlier version of SD_MMC_PATCH_H from the lines of
+    sd_mmc.h: from the mark through the end line, or for versions without
+    one, the comment starting with the mark and the prototypes after it."""
+    for n, line in enumerate(lines):
+        if not line.startswith(SD_MMC_PATCH_MARK):
+            continue
+
+        end = n
+        while end < len(lines) and \
+                not lines[end].startswith(SD_MMC_PATCH_H_END):
+            end += 1
+        if end < len(lines):
+            end += 1
+        else:
+            end = n
+            while "*/" not in lines[end]:
+                end += 1
+            end += 1
+            while end < len(lines) and \
+                    lines[end].startswith("sd_mmc_err_t "):
+                end += 1
+
+        start = n - 1 if n > 0 and lines[n - 1] == "\n" else n
+        return lines[:start] + lines[end:]
+    return lines
+
+def patch_sd_mmc(newpath):
+    """Add register access functions to the SD/MMC stack. Runs on every
+    build; an older version of the patch is replaced, and files already up
+    to date are left alone."""
+    base = os.path.join(newpath, "components", "memory", "sd_mmc")
+
+    cpath = os.path.join(base, "sd_mmc.c")
+    with open(cpath) as f:
+        old = f.read()
+    new = strip_patch_c(old) + SD_MMC_PATCH_C
+    if new != old:
+        with open(cpath, "w") as f:
+            f.write(new)
+
+    hpath = os.path.join(base, "sd_mmc.h")
+    with open(hpath) as f:
+        old = f.read()
+    lines = strip_patch_h(old.splitlines(True))
+
+    for n, line in enumerate(lines):
+        if SD_MMC_H_ANCHOR.match(line):
+            lines.insert(n + 1, SD_MMC_PATCH_H)
+            break
+    else:
+        raise Exception("cannot find where to patch %s" % hpath)
+
+    new = "".join(lines)
+    if new != old:
+        with open(hpath, "w") as f:
+            f.write(new)
+
 def main(argv):
     if len(argv) != 4:
         usage()
@@ -128,6 +349,8 @@ def main(argv):
     newpath = argv[3]
 
     if os.path.exists(os.path.join(newpath, "drivers")):
+        # Already unfucked, but the patch may be newer than the tree
+        patch_sd_mmc(newpath)
         return 0
 
     if platform not in ("avr32", "mega", "xmega", "sam", "sam0"):
@@ -164,6 +387,9 @@ def main(argv):
             fp = os.path.join(root, name)
             fix_one_file(fp, incpath, newpath)
 
+    print("Patch SD/MMC stack")
+    patch_sd_mmc(newpath)
+
     # Copy other things directly
     direct_copy = ["thirdparty/CMSIS", "thirdparty/freertos"]
     for path in direct_copy: